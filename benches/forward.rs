@@ -0,0 +1,161 @@
+//! Relays a stream through the proxy, once from a plain `TcpStream` client, which Linux splices
+//! within the kernel, and once from a wrapped one, which is copied through buffers as any other
+//! stream is. Prints the throughput and the CPU time of the process, which includes the sending
+//! and receiving ends of the benchmark in both cases.
+//!
+//! Run with `cargo bench --bench forward`, optionally followed by the MiB to relay.
+
+use std::{
+    env, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use socks5::{constant::*, Config, Forwarding, Socks5};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+const CHUNK: usize = 64 * 1024;
+
+/// Hides the type of the stream, so that the proxy can't take the spliced path.
+struct Opaque(TcpStream);
+
+impl AsyncRead for Opaque {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Opaque {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Discards everything it receives.
+async fn sink() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![0; CHUNK];
+                while stream.read(&mut buf).await.unwrap() > 0 {}
+            });
+        }
+    });
+    addr
+}
+
+/// Serves every client through the proxy with buffers as large as a pipe, hiding the client
+/// stream if asked to.
+async fn proxy(opaque: bool) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Arc::new(Config::default().forwarding(Forwarding {
+        send_buffer: CHUNK,
+        receive_buffer: CHUNK,
+        ..Forwarding::default()
+    }));
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let socks5 = Socks5::<TcpStream>::with_config(config.clone());
+            tokio::spawn(async move {
+                match opaque {
+                    true => socks5.start(Opaque(stream)).await,
+                    false => socks5.start(stream).await,
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Sends the bytes to the sink through the proxy, returning once the sink has them all.
+async fn relay(proxy: SocketAddr, sink: SocketAddr, bytes: usize) {
+    let SocketAddr::V4(sink) = sink else {
+        unreachable!()
+    };
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+    client.write_all(&[VER, CONNECT, RSV, IPV4]).await.unwrap();
+    client.write_all(&sink.ip().octets()).await.unwrap();
+    client.write_all(&sink.port().to_be_bytes()).await.unwrap();
+    let mut reply = [0; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [VER, NO_AUTH, VER, OK]);
+
+    let chunk = vec![0x5a; CHUNK];
+    for _ in 0..bytes / CHUNK {
+        client.write_all(&chunk).await.unwrap();
+    }
+    client.shutdown().await.unwrap();
+    // the proxy closes the client once the sink has read everything and closed too.
+    assert_eq!(client.read(&mut [0]).await.unwrap(), 0);
+}
+
+/// The user and system CPU time of the process so far.
+#[cfg(unix)]
+fn cpu_time() -> Duration {
+    // SAFETY: getrusage only fills the zeroed struct.
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage
+    };
+    let time = |it: libc::timeval| {
+        Duration::from_secs(it.tv_sec as u64) + Duration::from_micros(it.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+#[tokio::main]
+async fn main() {
+    let mib = env::args()
+        .skip(1)
+        .find_map(|it| it.parse::<usize>().ok())
+        .unwrap_or(1024);
+    let sink = sink().await;
+    for (name, opaque) in [("splice", false), ("copy", true)] {
+        let proxy = proxy(opaque).await;
+        relay(proxy, sink, CHUNK).await;
+
+        let (started, cpu) = (Instant::now(), cpu_time());
+        relay(proxy, sink, mib << 20).await;
+        let (elapsed, cpu) = (started.elapsed(), cpu_time() - cpu);
+
+        let throughput = mib as f64 / elapsed.as_secs_f64();
+        println!(
+            "{name:>6}: {mib} MiB in {elapsed:.2?}, {throughput:.0} MiB/s, {cpu:.2?} CPU ({:.0}%)",
+            cpu.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+        );
+    }
+}
@@ -0,0 +1,16 @@
+//! Arbitrary bytes after a greeting that offers password authentication.
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use socks5::{constant::*, Config, Credential};
+
+#[global_allocator]
+static ALLOCATOR: common::Bounded = common::Bounded;
+
+fuzz_target!(|data: &[u8]| {
+    let config = Config::new(Some(Credential::new("user", "pass")));
+    let input = [&[VER, 1, CREDENTIAL_AUTH][..], data].concat();
+    let _ = common::session(config, &input);
+});
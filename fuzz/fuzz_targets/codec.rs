@@ -0,0 +1,30 @@
+//! Decodes arbitrary bytes as every message, which must fit the handshake buffers and decode
+//! again from its encoding.
+#![no_main]
+
+use std::fmt::Debug;
+
+use libfuzzer_sys::fuzz_target;
+use socks5::codec::*;
+
+fn roundtrip<M: Decode + Encode + PartialEq + Debug>(data: &[u8]) {
+    if let Ok((message, n)) = M::decode(data) {
+        assert!(n <= data.len());
+        let encoded = message.to_vec().expect("decoded messages encode");
+        assert!(encoded.len() <= MAX_MESSAGE_LEN);
+        assert_eq!(M::decode(&encoded), Ok((message, encoded.len())));
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    roundtrip::<Addr>(data);
+    roundtrip::<Greeting>(data);
+    roundtrip::<MethodSelection>(data);
+    roundtrip::<AuthRequest>(data);
+    roundtrip::<AuthResponse>(data);
+    roundtrip::<Request>(data);
+    roundtrip::<Reply>(data);
+    roundtrip::<UdpHeader>(data);
+    roundtrip::<Socks4Request>(data);
+    roundtrip::<Socks4Reply>(data);
+});
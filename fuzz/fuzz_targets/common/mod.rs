@@ -0,0 +1,66 @@
+//! Runs fuzzed sessions, failing on a panic, a session that never ends or one that allocates
+//! more than it's bounded to. Run a target with `cargo fuzz run <target>` from the repository.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    time::Duration,
+};
+
+use socks5::{Config, Error};
+use tokio::{runtime::Runtime, time::timeout};
+
+/// What a session may allocate on top of a copy of its input: buffers for the handshake, the
+/// forwarding buffers of both directions and the tasks and streams of the session.
+const MAX_SESSION_ALLOCATION: usize = 256 * 1024;
+
+/// Longer than any session may take, which the paused clock reaches as soon as it's idle.
+const HANG: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Counts the bytes allocated, keeping the peak.
+pub struct Bounded;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Bounded {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Relaxed) + layout.size();
+        PEAK.fetch_max(current, Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+thread_local! {
+    static RUNTIME: RefCell<Runtime> = RefCell::new(
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap(),
+    );
+}
+
+/// Runs a session over the input to its end, which is either success or a typed error.
+pub fn session(config: Config, input: &[u8]) -> Result<(), Error> {
+    RUNTIME.with(|runtime| {
+        let runtime = runtime.borrow();
+        let base = CURRENT.load(Relaxed);
+        PEAK.store(base, Relaxed);
+        let result =
+            runtime.block_on(async { timeout(HANG, socks5::fuzz::session(config, input)).await });
+        let allocated = PEAK.load(Relaxed) - base;
+        assert!(
+            allocated <= MAX_SESSION_ALLOCATION + input.len(),
+            "the session allocated {allocated} bytes for {} bytes of input",
+            input.len()
+        );
+        result.expect("the session never ends")
+    })
+}
@@ -0,0 +1,19 @@
+//! Arbitrary bytes from the first one, detected as SOCKS5, SOCKS4 or HTTP, with password
+//! authentication required or not by the first byte.
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use socks5::{Config, Credential};
+
+#[global_allocator]
+static ALLOCATOR: common::Bounded = common::Bounded;
+
+fuzz_target!(|data: &[u8]| {
+    let config = match data.first() {
+        Some(it) if it & 0x80 != 0 => Config::new(Some(Credential::new("user", "pass"))),
+        _ => Config::default(),
+    };
+    let _ = common::session(config, data);
+});
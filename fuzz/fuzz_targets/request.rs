@@ -0,0 +1,16 @@
+//! Arbitrary bytes after a greeting that needs no authentication, as the request and then the
+//! bytes forwarded upstream.
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use socks5::{constant::*, Config};
+
+#[global_allocator]
+static ALLOCATOR: common::Bounded = common::Bounded;
+
+fuzz_target!(|data: &[u8]| {
+    let input = [&[VER, 1, NO_AUTH][..], data].concat();
+    let _ = common::session(Config::default(), &input);
+});
@@ -0,0 +1,164 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// An IP network in CIDR notation such as `10.8.0.0/16`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (prefix <= max).then_some(Network { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix as u32);
+        let ip = match (self.addr, ip) {
+            (IpAddr::V6(_), IpAddr::V4(ip)) => IpAddr::V6(ip.to_ipv6_mapped()),
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => return false,
+            },
+            (_, ip) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = mask(32).unwrap_or(0) as u32;
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = mask(128).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn loopback() -> [Network; 2] {
+        [
+            Network::new([127, 0, 0, 0].into(), 8).unwrap(),
+            Network::new(Ipv6Addr::LOCALHOST.into(), 128).unwrap(),
+        ]
+    }
+}
+
+impl FromStr for Network {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| InvalidNetwork)?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| InvalidNetwork)?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Network::new(addr, prefix).ok_or(InvalidNetwork)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidNetwork;
+
+impl fmt::Display for InvalidNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid network, expected an address or CIDR notation")
+    }
+}
+
+impl std::error::Error for InvalidNetwork {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Allow(Network),
+    Deny(Network),
+}
+
+/// Destinations a client may connect to. Rules are checked in order and the first matching one
+/// decides; a destination no rule matches is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct AccessRules(Vec<Rule>);
+
+impl AccessRules {
+    pub fn allow(mut self, network: Network) -> Self {
+        self.0.push(Rule::Allow(network));
+        self
+    }
+
+    pub fn deny(mut self, network: Network) -> Self {
+        self.0.push(Rule::Deny(network));
+        self
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.0
+            .iter()
+            .find_map(|rule| match rule {
+                Rule::Allow(network) => network.contains(ip).then_some(true),
+                Rule::Deny(network) => network.contains(ip).then_some(false),
+            })
+            .unwrap_or(true)
+    }
+
+    /// Whether every destination is permitted, as with no deny rules.
+    pub(crate) fn permits_all(&self) -> bool {
+        !self.0.iter().any(|rule| matches!(rule, Rule::Deny(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> Network {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_networks() {
+        assert_eq!(network("10.8.0.0/16").to_string(), "10.8.0.0/16");
+        assert_eq!(network("::1").to_string(), "::1/128");
+        assert_eq!(network("127.0.0.1").to_string(), "127.0.0.1/32");
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("localhost/8".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn network_contains() {
+        assert!(network("10.8.0.0/16").contains("10.8.255.1".parse().unwrap()));
+        assert!(!network("10.8.0.0/16").contains("10.9.0.1".parse().unwrap()));
+        assert!(network("0.0.0.0/0").contains("1.2.3.4".parse().unwrap()));
+        assert!(network("fd00::/8").contains("fd12::1".parse().unwrap()));
+        assert!(network("127.0.0.0/8").contains("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!network("127.0.0.0/8").contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = AccessRules::default()
+            .allow(network("10.0.0.1"))
+            .deny(network("10.0.0.0/8"));
+
+        assert!(rules.permits("10.0.0.1".parse().unwrap()));
+        assert!(!rules.permits("10.0.0.2".parse().unwrap()));
+        assert!(rules.permits("192.168.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,517 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Mutex, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{quota::civil_from_days, session::Session, IOResult};
+
+/// The rotated files kept unless told otherwise.
+const KEEP: usize = 5;
+/// The lines waiting to be written, beyond which new ones are dropped.
+const QUEUE: usize = 1024;
+/// Syslog facility `daemon` with severity `info`.
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// How each line of an [`AccessLog`] is laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// After the common log format, with `-` for what isn't known:
+    /// `client - user [time] "command requested" target reply duration sent received`.
+    #[default]
+    Text,
+    /// One JSON object per line, with `null` for what isn't known.
+    Json,
+}
+
+/// One line per finished session: when it started, the client, the user, the command and the
+/// target the client asked for, the address connected to, the SOCKS5 reply code sent, how many
+/// milliseconds it lasted and the bytes relayed each way.
+///
+/// Lines are written by a thread of their own. Failing writes are ignored and lines that would
+/// wait behind too many others dropped, so that logging never holds up or ends a session.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: LogFormat,
+    rotation: Rotation,
+    /// Taken by the writer once the first line is logged.
+    sink: Mutex<Option<Sink>>,
+    lines: OnceLock<SyncSender<String>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rotation {
+    max_size: Option<u64>,
+    interval: Option<u64>,
+    keep: usize,
+}
+
+#[derive(Debug)]
+enum Sink {
+    File(LogFile),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+}
+
+impl AccessLog {
+    /// Appends to the file, which is only rotated once told when.
+    pub fn file<P: AsRef<Path>>(path: P) -> IOResult<Self> {
+        Ok(Self::with_sink(Sink::File(LogFile::open(path.as_ref())?)))
+    }
+
+    /// Sends to the syslog daemon of the host at `/dev/log`.
+    #[cfg(unix)]
+    pub fn syslog() -> IOResult<Self> {
+        Self::syslog_at("/dev/log")
+    }
+
+    /// Sends to the syslog daemon listening on the Unix datagram socket, dropping the lines it
+    /// has no room for.
+    #[cfg(unix)]
+    pub fn syslog_at<P: AsRef<Path>>(path: P) -> IOResult<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::with_sink(Sink::Syslog(socket)))
+    }
+
+    fn with_sink(sink: Sink) -> Self {
+        AccessLog {
+            format: LogFormat::default(),
+            rotation: Rotation {
+                max_size: None,
+                interval: None,
+                keep: KEEP,
+            },
+            sink: Mutex::new(Some(sink)),
+            lines: OnceLock::new(),
+            writer: Mutex::new(None),
+        }
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Rotates the file before it grows past the size in bytes.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.rotation.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotates the file once the interval it was written in has passed, counting intervals from
+    /// the Unix epoch so that daily files start at midnight UTC.
+    pub fn rotate_every(mut self, interval: Duration) -> Self {
+        self.rotation.interval = Some(interval.as_secs().max(1));
+        self
+    }
+
+    /// How many rotated files are kept, from `path.1`, the newest, to `path.{files}`. Five by
+    /// default.
+    pub fn keep(mut self, files: usize) -> Self {
+        self.rotation.keep = files;
+        self
+    }
+
+    /// Logs the finished session, which sent the client the reply code if any.
+    pub(crate) fn record(&self, session: &Session, reply: Option<u8>) {
+        let line = match self.format {
+            LogFormat::Text => text(session, reply),
+            LogFormat::Json => json(session, reply),
+        };
+        let _ = self.lines.get_or_init(|| self.start()).try_send(line);
+    }
+
+    /// Starts the thread writing the lines to the sink.
+    fn start(&self) -> SyncSender<String> {
+        let (lines, queued) = sync_channel::<String>(QUEUE);
+        if let Some(mut sink) = self.sink.lock().unwrap().take() {
+            let rotation = self.rotation;
+            let writer = thread::spawn(move || {
+                for line in queued {
+                    let _ = sink.write(&line, rotation);
+                }
+            });
+            *self.writer.lock().unwrap() = Some(writer);
+        }
+        lines
+    }
+}
+
+impl Drop for AccessLog {
+    /// Waits for the queued lines to be written.
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.get_mut().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Sink {
+    fn write(&mut self, line: &str, rotation: Rotation) -> IOResult<()> {
+        match self {
+            Sink::File(file) => file.write(line, rotation),
+            // a daemon that can't keep up loses the line rather than blocking the others.
+            #[cfg(unix)]
+            Sink::Syslog(socket) => {
+                let message = format!("<{SYSLOG_PRIORITY}>socks5: {}", line.trim_end());
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Seconds since the Unix epoch of the last write.
+    written: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> IOResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let written = metadata.modified().map(secs).unwrap_or_else(|_| now());
+        Ok(LogFile {
+            path: path.into(),
+            file,
+            size: metadata.len(),
+            written,
+        })
+    }
+
+    fn write(&mut self, line: &str, rotation: Rotation) -> IOResult<()> {
+        let now = now();
+        let too_large = rotation
+            .max_size
+            .is_some_and(|max| self.size + line.len() as u64 > max);
+        let too_old = rotation
+            .interval
+            .is_some_and(|it| now / it != self.written / it);
+        if self.size > 0 && (too_large || too_old) {
+            self.rotate(rotation.keep)?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        self.written = now;
+        Ok(())
+    }
+
+    /// Shifts the rotated files along, dropping the oldest, and starts a new file.
+    fn rotate(&mut self, keep: usize) -> IOResult<()> {
+        match keep {
+            0 => fs::remove_file(&self.path)?,
+            keep => {
+                for n in (1..keep).rev() {
+                    let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+                }
+                fs::rename(&self.path, self.rotated(1))?;
+            }
+        }
+        *self = LogFile::open(&self.path)?;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
+
+fn text(session: &Session, reply: Option<u8>) -> String {
+    let field = |it: Option<String>| it.map_or_else(|| "-".into(), |it| escape_text(&it));
+    let (year, month, day, time) = timestamp(session.tracker.started);
+    format!(
+        "{} - {} [{day:02}/{}/{year}:{time} +0000] \"{} {}\" {} {} {} {} {}\n",
+        field(session.peer.map(|it| it.to_string())),
+        field(session.user.as_ref().map(|it| it.name.clone())),
+        MONTHS[month as usize - 1],
+        field(session.command.clone()),
+        field(session.requested.as_ref().map(|it| it.to_string())),
+        field(session.target.map(|it| it.to_string())),
+        field(reply.map(|it| it.to_string())),
+        duration(session),
+        session.sent,
+        session.received,
+    )
+}
+
+fn json(session: &Session, reply: Option<u8>) -> String {
+    let field = |it: Option<String>| it.map_or_else(|| "null".into(), |it| escape_json(&it));
+    let (year, month, day, time) = timestamp(session.tracker.started);
+    format!(
+        "{{\"time\":\"{year}-{month:02}-{day:02}T{time}Z\",\"client\":{},\"user\":{},\
+         \"command\":{},\"requested\":{},\"target\":{},\"reply\":{},\"duration\":{},\
+         \"sent\":{},\"received\":{}}}\n",
+        field(session.peer.map(|it| it.to_string())),
+        field(session.user.as_ref().map(|it| it.name.clone())),
+        field(session.command.clone()),
+        field(session.requested.as_ref().map(|it| it.to_string())),
+        field(session.target.map(|it| it.to_string())),
+        reply.map_or_else(|| "null".into(), |it| it.to_string()),
+        duration(session),
+        session.sent,
+        session.received,
+    )
+}
+
+fn duration(session: &Session) -> u128 {
+    let started = session.tracker.started;
+    started.elapsed().unwrap_or_default().as_millis()
+}
+
+/// The UTC year, month, day and `hh:mm:ss` of the time.
+fn timestamp(time: SystemTime) -> (u64, u64, u64, String) {
+    let secs = secs(time);
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let secs = secs % 86400;
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    (year, month, day, time)
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn now() -> u64 {
+    secs(SystemTime::now())
+}
+
+/// Keeps a field of a text line a single word of printable ASCII.
+fn escape_text(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            ' ' => escaped.push_str("\\x20"),
+            c => {
+                let _ = write!(escaped, "{}", c.escape_default());
+            }
+        }
+    }
+    escaped
+}
+
+fn escape_json(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len() + 2);
+    escaped.push('"');
+    for c in field.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        codec::Addr,
+        config::Config,
+        constant::{CONNECT, IPV4, NO_AUTH, OK, RSV, VER},
+        registry::Tracker,
+        test::AsyncExactRead,
+        Socks5,
+    };
+
+    /// A session started at 2024-02-29 13:05:09 UTC.
+    fn session() -> Session {
+        let mut session = Session::new(Arc::new(Config::default()));
+        let mut tracker = Tracker::new(1);
+        tracker.started = UNIX_EPOCH + Duration::from_secs(1709211909);
+        session.tracker = Arc::new(tracker);
+        session.peer = Some("127.0.0.1:5000".parse().unwrap());
+        session.user = Some("al ice\"".into());
+        session.command = Some("CONNECT".into());
+        session.requested = Some(Addr::Domain("example.com".into(), 80));
+        session.target = Some("93.184.216.34:80".parse().unwrap());
+        session.sent = 120;
+        session.received = 4096;
+        session
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("socks5-{}-{name}.log", std::process::id()))
+    }
+
+    #[test]
+    fn format_text_lines() {
+        let line = text(&session(), Some(0));
+
+        assert!(line.starts_with(
+            "127.0.0.1:5000 - al\\x20ice\\\" [29/Feb/2024:13:05:09 +0000] \
+             \"CONNECT example.com:80\" 93.184.216.34:80 0 "
+        ));
+        assert!(line.ends_with(" 120 4096\n"));
+        assert!(text(&Session::new(Default::default()), None).starts_with("- - - ["));
+    }
+
+    #[test]
+    fn format_json_lines() {
+        let line = json(&session(), None);
+
+        assert!(line.starts_with(
+            "{\"time\":\"2024-02-29T13:05:09Z\",\"client\":\"127.0.0.1:5000\",\
+             \"user\":\"al ice\\\"\",\"command\":\"CONNECT\",\"requested\":\"example.com:80\",\
+             \"target\":\"93.184.216.34:80\",\"reply\":null,\"duration\":"
+        ));
+        assert!(line.ends_with(",\"sent\":120,\"received\":4096}\n"));
+        assert_eq!(escape_json("a\nb"), "\"a\\u000ab\"");
+    }
+
+    fn rotation(max_size: Option<u64>, interval: Option<u64>, keep: usize) -> Rotation {
+        Rotation {
+            max_size,
+            interval,
+            keep,
+        }
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let path = temp_path("size");
+        let line = text(&session(), Some(0));
+        let mut file = LogFile::open(&path).unwrap();
+
+        for _ in 0..5 {
+            let rotation = rotation(Some(2 * line.len() as u64), None, 1);
+            file.write(&line, rotation).unwrap();
+        }
+        let rotated = fs::read_to_string(format!("{}.1", path.display())).unwrap();
+        let current = fs::read_to_string(&path).unwrap();
+        let oldest = Path::new(&format!("{}.2", path.display())).exists();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(format!("{}.1", path.display())).unwrap();
+
+        assert_eq!((rotated.lines().count(), current.lines().count()), (2, 1));
+        assert!(!oldest);
+    }
+
+    #[test]
+    fn rotate_by_time() {
+        let path = temp_path("time");
+        let line = text(&session(), Some(0));
+        let daily = rotation(None, Some(86400), KEEP);
+        let mut file = LogFile::open(&path).unwrap();
+        file.write(&line, daily).unwrap();
+        file.written -= 86400;
+
+        file.write(&line, daily).unwrap();
+        let rotated = fs::read_to_string(format!("{}.1", path.display())).unwrap();
+        let current = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(format!("{}.1", path.display())).unwrap();
+
+        assert_eq!((rotated.lines().count(), current.lines().count()), (1, 1));
+    }
+
+    #[test]
+    fn write_lines_in_the_background() {
+        let path = temp_path("background");
+        let log = AccessLog::file(&path).unwrap();
+
+        for _ in 0..3 {
+            log.record(&session(), Some(0));
+        }
+        drop(log);
+        let lines = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.lines().count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_to_syslog() {
+        let path = temp_path("syslog");
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let log = AccessLog::syslog_at(&path).unwrap().format(LogFormat::Json);
+
+        log.record(&session(), Some(0));
+        let mut message = [0; 1024];
+        let n = daemon.recv(&mut message).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let message = std::str::from_utf8(&message[..n]).unwrap();
+        assert!(message.starts_with("<30>socks5: {\"time\""), "{message}");
+        assert!(message.ends_with('}'));
+    }
+
+    #[tokio::test]
+    async fn log_finished_sessions() {
+        let path = temp_path("session");
+        let log = AccessLog::file(&path).unwrap().format(LogFormat::Json);
+        let config = Config::default().access_log(log);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let (mut client, server) = duplex(1024);
+        let session = tokio::spawn(Socks5::<TcpStream>::with_config(config.into()).start(server));
+
+        client
+            .write_all(&[VER, 1, NO_AUTH, VER, CONNECT, RSV, IPV4])
+            .await
+            .unwrap();
+        client.write_all(&[127, 0, 0, 1]).await.unwrap();
+        client
+            .write_all(&target.port().to_be_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            client.read_exact_bytes::<4>().await.unwrap(),
+            [VER, NO_AUTH, VER, OK]
+        );
+        client.read_exact_bytes::<8>().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        upstream.read_exact_bytes::<4>().await.unwrap();
+        upstream.write_all(b"pong!").await.unwrap();
+        drop(upstream);
+        client.read_to_end(&mut vec![]).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+        let line = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let target = format!("\"{target}\"");
+        assert!(line.contains(&format!("\"requested\":{target},\"target\":{target}")));
+        assert!(line.contains("\"command\":\"CONNECT\""));
+        assert!(line.contains("\"reply\":0,"));
+        assert!(line.ends_with(",\"sent\":4,\"received\":5}\n"), "{line}");
+    }
+}
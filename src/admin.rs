@@ -0,0 +1,572 @@
+//! An HTTP/JSON API to inspect and steer a running server.
+use std::{
+    io,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use crate::{
+    config::Config,
+    credential::Users,
+    marker::Stream,
+    registry::SessionInfo,
+    server::{accept_failed, Accept, Control},
+    IOResult,
+};
+
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// How long a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Reload = Box<dyn Fn() -> IOResult<Config> + Send + Sync>;
+type SetLogLevel = Box<dyn Fn(&str) -> IOResult<()> + Send + Sync>;
+
+/// Serves the admin API, one request per connection:
+///
+/// - `GET /sessions` lists the running sessions.
+/// - `DELETE /sessions/{id}` terminates a session.
+/// - `DELETE /users/{name}/sessions` terminates every session of the user.
+/// - `PUT /users/{name}` with `{"password": ..}` adds a user, `DELETE /users/{name}` disables it.
+/// - `POST /reload` replaces the settings of new sessions.
+/// - `PUT /log-level` with `{"level": ..}` changes the log level.
+///
+/// Requests with an `Origin`, which browsers send on behalf of other sites, are refused. Unless
+/// a [`token`](Admin::token) is required, so are requests naming a host other than loopback, as
+/// a rebound DNS name would, and the API should only listen on loopback or a Unix socket.
+/// Endpoints of a feature that isn't set up answer `501`.
+pub struct Admin {
+    control: Control,
+    token: Option<String>,
+    users: Option<Arc<Users>>,
+    reload: Option<Reload>,
+    log_level: Option<SetLogLevel>,
+}
+
+impl Admin {
+    pub fn new(control: Control) -> Self {
+        Admin {
+            control,
+            token: None,
+            users: None,
+            reload: None,
+            log_level: None,
+        }
+    }
+
+    /// Requires every request to carry the token as `Authorization: Bearer {token}`, answering
+    /// `401` otherwise.
+    pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// The users the user endpoints add and disable, which the server should verify passwords
+    /// against.
+    pub fn users(mut self, users: Arc<Users>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    /// Loads the settings `POST /reload` applies, such as from a configuration file.
+    pub fn on_reload<F>(mut self, reload: F) -> Self
+    where
+        F: Fn() -> IOResult<Config> + Send + Sync + 'static,
+    {
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    /// Applies the level `PUT /log-level` asks for, failing with `InvalidInput` for an unknown one.
+    pub fn on_log_level<F>(mut self, set: F) -> Self
+    where
+        F: Fn(&str) -> IOResult<()> + Send + Sync + 'static,
+    {
+        self.log_level = Some(Box::new(set));
+        self
+    }
+
+    pub async fn serve<A: Accept>(self, listener: A) -> IOResult<()> {
+        let admin = Arc::new(self);
+        loop {
+            let incoming = match listener.accept().await {
+                Ok(incoming) => incoming,
+                Err(_) => {
+                    accept_failed(&admin.control.config().stats).await;
+                    continue;
+                }
+            };
+            let admin = admin.clone();
+            tokio::spawn(async move { admin.handle(incoming.stream).await });
+        }
+    }
+
+    async fn handle<S: Stream>(&self, mut stream: S) -> IOResult<()> {
+        let request = timeout(READ_TIMEOUT, read_request(&mut stream))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        let response = match request {
+            Ok(request) => self
+                .authorize(&request)
+                .unwrap_or_else(|| self.respond(&request)),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => error(400, err.to_string()),
+            Err(err) => return Err(err),
+        };
+        stream.write_all(&response.to_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    /// The response refusing the request, if it isn't allowed.
+    fn authorize(&self, request: &Request) -> Option<Response> {
+        if request.header("origin").is_some() {
+            return Some(error(403, "cross-origin requests are refused"));
+        }
+        match &self.token {
+            Some(token) => {
+                let bearer = request
+                    .header("authorization")
+                    .and_then(|it| it.strip_prefix("Bearer "));
+                let authorized =
+                    bearer.is_some_and(|it| same(it.trim().as_bytes(), token.as_bytes()));
+                (!authorized).then(|| error(401, "expect the bearer token"))
+            }
+            None => {
+                let foreign = request.header("host").is_some_and(|it| !is_loopback(it));
+                foreign.then(|| error(403, "only loopback hosts are served"))
+            }
+        }
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        let path = request.path.trim_matches('/').split('/');
+        let Some(path) = path.map(decode).collect::<Option<Vec<_>>>() else {
+            return error(400, "malformed path");
+        };
+        let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+        match (request.method.as_str(), path.as_slice()) {
+            ("GET", ["sessions"]) => {
+                let sessions = self.control.sessions().iter().map(to_json).collect();
+                Response::json(200, Value::Array(sessions))
+            }
+            ("DELETE", ["sessions", id]) => match id.parse().map(|id| self.control.terminate(id)) {
+                Ok(true) => Response::empty(204),
+                _ => error(404, "no such session"),
+            },
+            ("DELETE", ["users", name, "sessions"]) => {
+                let terminated = self.control.terminate_user(name);
+                Response::json(200, json!({ "terminated": terminated }))
+            }
+            ("PUT", ["users", name]) => {
+                let Some(users) = &self.users else {
+                    return error(501, "users are not managed at runtime");
+                };
+                let body = match request.json() {
+                    Ok(body) => body,
+                    Err(response) => return response,
+                };
+                match body["password"].as_str() {
+                    Some(password) => {
+                        users.add(*name, password);
+                        Response::empty(204)
+                    }
+                    None => error(400, "expect a password"),
+                }
+            }
+            ("DELETE", ["users", name]) => match &self.users {
+                Some(users) if users.disable(name) => Response::empty(204),
+                Some(_) => error(404, "no such user"),
+                None => error(501, "users are not managed at runtime"),
+            },
+            ("POST", ["reload"]) => {
+                let Some(reload) = &self.reload else {
+                    return error(501, "reload is not set up");
+                };
+                match reload() {
+                    Ok(config) => {
+                        self.control.reload(config);
+                        Response::empty(204)
+                    }
+                    Err(err) => error(500, err.to_string()),
+                }
+            }
+            ("PUT", ["log-level"]) => {
+                let Some(set) = &self.log_level else {
+                    return error(501, "log level is not set up");
+                };
+                let body = match request.json() {
+                    Ok(body) => body,
+                    Err(response) => return response,
+                };
+                let Some(level) = body["level"].as_str() else {
+                    return error(400, "expect a level");
+                };
+                match set(level) {
+                    Ok(()) => Response::empty(204),
+                    Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                        error(400, err.to_string())
+                    }
+                    Err(err) => error(500, err.to_string()),
+                }
+            }
+            _ => error(404, "no such endpoint"),
+        }
+    }
+}
+
+fn to_json(session: &SessionInfo) -> Value {
+    let started = session
+        .started
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    json!({
+        "id": session.id,
+        "client": session.peer.map(|it| it.to_string()),
+        "user": session.user,
+        "target": session.target.map(|it| it.to_string()),
+        "started": started.as_secs(),
+        "sent": session.sent,
+        "received": session.received,
+        "stage": session.stage,
+    })
+}
+
+/// Compares in a time that depends on the lengths alone.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Whether the `Host` header names loopback, with or without a port.
+fn is_loopback(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse().is_ok_and(|it: IpAddr| it.is_loopback())
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(it, _)| it.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as a JSON object, or a `400` response to send instead.
+    fn json(&self) -> Result<Value, Response> {
+        match serde_json::from_slice::<Value>(&self.body) {
+            Ok(body) if body.is_object() => Ok(body),
+            _ => Err(error(400, "expect a JSON object")),
+        }
+    }
+}
+
+async fn read_request<S: Stream>(stream: &mut S) -> IOResult<Request> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let mut buf = vec![];
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|it| it == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() > MAX_REQUEST_LEN {
+            return Err(invalid("request head too large"));
+        }
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("request head isn't UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect::<Vec<_>>();
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.parse::<usize>())
+        .transpose()
+        .map_err(|_| invalid("malformed Content-Length"))?
+        .unwrap_or(0);
+    if end + length > MAX_REQUEST_LEN {
+        return Err(invalid("request body too large"));
+    }
+    let (method, path) = (method.to_owned(), path.to_owned());
+    let mut body = buf.split_off(end);
+    let received = body.len().min(length);
+    body.resize(length, 0);
+    stream.read_exact(&mut body[received..]).await?;
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// Decodes the percent escapes of a path segment.
+fn decode(segment: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            byte => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    body: Option<Value>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            body: Some(body),
+        }
+    }
+
+    fn empty(status: u16) -> Self {
+        Response { status, body: None }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            501 => "Not Implemented",
+            _ => "Internal Server Error",
+        };
+        let body = self.body.as_ref().map(Value::to_string).unwrap_or_default();
+        let mut response = format!("HTTP/1.1 {} {reason}\r\nConnection: close\r\n", self.status);
+        if self.status == 401 {
+            response += "WWW-Authenticate: Bearer\r\n";
+        }
+        if self.body.is_some() {
+            response += "Content-Type: application/json\r\n";
+        }
+        response += &format!("Content-Length: {}\r\n\r\n{body}", body.len());
+        response.into_bytes()
+    }
+}
+
+fn error<S: Into<String>>(status: u16, reason: S) -> Response {
+    Response::json(status, json!({ "error": reason.into() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::duplex,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        constant::{GENERAL_FAILURE, NO_AUTH, VER},
+        credential::PasswordVerifier,
+        server::{Server, ServerHandle},
+        test::AsyncExactRead,
+    };
+
+    async fn server() -> (ServerHandle, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (Server::builder().listener(listener).start().unwrap(), addr)
+    }
+
+    async fn request(admin: &Admin, request: &str) -> (u16, Value) {
+        let (mut client, stream) = duplex(1024);
+        client.write_all(request.as_bytes()).await.unwrap();
+        admin.handle(stream).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head["HTTP/1.1 ".len()..][..3].parse().unwrap();
+        (status, serde_json::from_str(body).unwrap_or_default())
+    }
+
+    fn put(path: &str, body: &str) -> String {
+        format!(
+            "PUT {path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn list_and_terminate_sessions() {
+        let (server, addr) = server().await;
+        let admin = Admin::new(server.control());
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+
+        let session = loop {
+            let (status, sessions) = request(&admin, "GET /sessions HTTP/1.1\r\n\r\n").await;
+            assert_eq!(status, 200);
+            if sessions[0]["stage"] == "connect" {
+                break sessions[0].clone();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(session["client"], client.local_addr().unwrap().to_string());
+        assert_eq!(
+            (&session["user"], &session["sent"]),
+            (&Value::Null, &json!(0))
+        );
+
+        let terminate = format!("DELETE /sessions/{} HTTP/1.1\r\n\r\n", session["id"]);
+        assert_eq!(request(&admin, &terminate).await.0, 204);
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [VER, GENERAL_FAILURE]);
+        assert_eq!(request(&admin, &terminate).await.0, 404);
+
+        let (status, body) = request(&admin, "DELETE /users/alice/sessions HTTP/1.1\r\n\r\n").await;
+        assert_eq!((status, body), (200, json!({ "terminated": 0 })));
+    }
+
+    #[tokio::test]
+    async fn add_and_disable_users() {
+        let (server, _) = server().await;
+        let users = Arc::new(Users::new());
+        let admin = Admin::new(server.control()).users(users.clone());
+
+        let add = put("/users/al%20ice", r#"{"password": "secret"}"#);
+        assert_eq!(request(&admin, &add).await.0, 204);
+        assert!(users.verify("al ice", "secret").await.unwrap().is_some());
+        assert_eq!(request(&admin, &put("/users/bob", "{}")).await.0, 400);
+        assert_eq!(request(&admin, &put("/users/bob", "[]")).await.0, 400);
+
+        let disable = "DELETE /users/al%20ice HTTP/1.1\r\n\r\n";
+        assert_eq!(request(&admin, disable).await.0, 204);
+        assert!(users.verify("al ice", "secret").await.unwrap().is_none());
+        assert_eq!(request(&admin, disable).await.0, 404);
+
+        let admin = Admin::new(server.control());
+        assert_eq!(request(&admin, disable).await.0, 501);
+    }
+
+    #[tokio::test]
+    async fn reload_and_change_log_level() {
+        let (server, _) = server().await;
+        let admin = Admin::new(server.control());
+        assert_eq!(
+            request(&admin, "POST /reload HTTP/1.1\r\n\r\n").await.0,
+            501
+        );
+
+        let admin = admin
+            .on_reload(|| Err(io::Error::other("bad configuration")))
+            .on_log_level(|level| match level {
+                "debug" => Ok(()),
+                _ => Err(io::ErrorKind::InvalidInput.into()),
+            });
+        let (status, body) = request(&admin, "POST /reload HTTP/1.1\r\n\r\n").await;
+        assert_eq!(
+            (status, body),
+            (500, json!({ "error": "bad configuration" }))
+        );
+        let admin = admin.on_reload(|| Ok(Config::default()));
+        assert_eq!(
+            request(&admin, "POST /reload HTTP/1.1\r\n\r\n").await.0,
+            204
+        );
+
+        let level = |level| put("/log-level", &format!(r#"{{"level": "{level}"}}"#));
+        assert_eq!(request(&admin, &level("debug")).await.0, 204);
+        assert_eq!(request(&admin, &level("chatty")).await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn reject_unknown_requests() {
+        let (server, _) = server().await;
+        let admin = Admin::new(server.control());
+
+        assert_eq!(request(&admin, "GET /users HTTP/1.1\r\n\r\n").await.0, 404);
+        assert_eq!(request(&admin, "GET /%zz HTTP/1.1\r\n\r\n").await.0, 400);
+        assert_eq!(request(&admin, "GET\r\n\r\n").await.0, 400);
+        let large = "PUT /log-level HTTP/1.1\r\nContent-Length: 100000\r\n\r\n";
+        assert_eq!(request(&admin, large).await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn refuse_browsers_and_foreign_hosts() {
+        let (server, _) = server().await;
+        let admin = Admin::new(server.control());
+        let get = |headers| format!("GET /sessions HTTP/1.1\r\n{headers}\r\n");
+
+        assert_eq!(
+            request(&admin, &get("Host: localhost:9000\r\n")).await.0,
+            200
+        );
+        assert_eq!(request(&admin, &get("Host: [::1]:9000\r\n")).await.0, 200);
+        assert_eq!(request(&admin, &get("Host: 127.0.0.1\r\n")).await.0, 200);
+        assert_eq!(request(&admin, &get("Host: rebound.test\r\n")).await.0, 403);
+        let origin = "Host: localhost\r\nOrigin: http://evil.test\r\n";
+        assert_eq!(request(&admin, &get(origin)).await.0, 403);
+    }
+
+    #[tokio::test]
+    async fn require_the_token() {
+        let (server, _) = server().await;
+        let admin = Admin::new(server.control()).token("secret");
+        let get = |headers| format!("GET /sessions HTTP/1.1\r\n{headers}\r\n");
+
+        assert_eq!(request(&admin, &get("")).await.0, 401);
+        let wrong = "Authorization: Bearer guess\r\n";
+        assert_eq!(request(&admin, &get(wrong)).await.0, 401);
+        let bearer = "Host: admin.test\r\nAuthorization: Bearer secret\r\n";
+        assert_eq!(request(&admin, &get(bearer)).await.0, 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn time_out_idle_clients() {
+        let (server, _) = server().await;
+        let admin = Admin::new(server.control());
+        let (_client, stream) = duplex(1024);
+
+        let err = admin.handle(stream).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use crate::{
+    connect::Connect,
+    constant::{NO_ACCEPTABLE_METHODS, NO_AUTH},
+    credential::{Credential, PasswordAuth},
+    marker::Stream,
+    session::Session,
+    BoxFuture, Result, Stage,
+};
+
+/// An authenticated client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    /// Extra facts the authentication backend knows about the user, such as groups.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl User {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        User {
+            name: name.into(),
+            attributes: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<&str> for User {
+    fn from(name: &str) -> Self {
+        User::new(name)
+    }
+}
+
+/// A SOCKS5 authentication method the server can select during negotiation.
+///
+/// Private methods use codes `0x80` to `0xFE`.
+pub trait AuthMethod: Send + Sync {
+    fn code(&self) -> u8;
+
+    /// When a client offers several registered methods, the one with the highest priority wins.
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Runs the method-specific sub-negotiation once the method has been selected, resolving to
+    /// the user if the method establishes one, or failing with
+    /// [`Error::BadCredential`](crate::Error::BadCredential) to reject the client.
+    fn authenticate<'a>(
+        &'a self,
+        client: &'a mut dyn Stream,
+    ) -> BoxFuture<'a, Result<Option<User>>>;
+}
+
+/// `NO AUTHENTICATION REQUIRED`, which has no sub-negotiation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuth;
+
+impl AuthMethod for NoAuth {
+    fn code(&self) -> u8 {
+        NO_AUTH
+    }
+
+    fn authenticate<'a>(&'a self, _: &'a mut dyn Stream) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// The registered authentication methods, ordered by descending priority.
+#[derive(Clone, Default)]
+pub struct AuthMethods(Vec<Arc<dyn AuthMethod>>);
+
+impl AuthMethods {
+    /// Registers a method, replacing any method registered with the same code.
+    ///
+    /// Panics on `0xFF`, which is reserved for `NO ACCEPTABLE METHODS`.
+    pub fn register<M: AuthMethod + 'static>(mut self, method: M) -> Self {
+        assert_ne!(method.code(), NO_ACCEPTABLE_METHODS, "0xFF is reserved");
+        self.0.retain(|it| it.code() != method.code());
+        let n = self
+            .0
+            .partition_point(|it| it.priority() >= method.priority());
+        self.0.insert(n, Arc::new(method));
+        self
+    }
+
+    pub(crate) fn select(&self, offered: &[u8]) -> Option<&Arc<dyn AuthMethod>> {
+        self.0.iter().find(|it| offered.contains(&it.code()))
+    }
+}
+
+impl From<Option<Credential>> for AuthMethods {
+    fn from(credential: Option<Credential>) -> Self {
+        match credential {
+            Some(credential) => {
+                AuthMethods::default().register(PasswordAuth::new(Arc::new(credential)))
+            }
+            None => AuthMethods::default().register(NoAuth),
+        }
+    }
+}
+
+impl fmt::Debug for AuthMethods {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|it| it.code()))
+            .finish()
+    }
+}
+
+/// Runs the sub-negotiation of the method selected during negotiation.
+pub struct Authentication(pub Arc<dyn AuthMethod>);
+
+impl Authentication {
+    pub async fn run<S: Stream, U>(
+        &mut self,
+        mut client: S,
+        session: &mut Session,
+    ) -> Result<Stage<U>> {
+        if let Some(user) = self.0.authenticate(&mut client).await? {
+            session.user = Some(user);
+        }
+        session.hooks().after_auth(session).await?;
+        Ok(Stage::Connect(Connect))
+    }
+}
+
+impl fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Authentication")
+            .field(&self.0.code())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{constant::CREDENTIAL_AUTH, error::Error, test::AsyncExactRead};
+
+    const CHALLENGE_AUTH: u8 = 0x80;
+
+    /// Echoes a one byte challenge back as the response.
+    struct Challenge(u8);
+
+    impl AuthMethod for Challenge {
+        fn code(&self) -> u8 {
+            CHALLENGE_AUTH
+        }
+
+        fn priority(&self) -> i32 {
+            10
+        }
+
+        fn authenticate<'a>(
+            &'a self,
+            client: &'a mut dyn Stream,
+        ) -> BoxFuture<'a, Result<Option<User>>> {
+            Box::pin(async move {
+                client.write_u8(self.0).await?;
+                match client.read_u8().await? {
+                    answer if answer == self.0 => {
+                        Ok(Some(User::new(format!("challenge-{answer}"))))
+                    }
+                    _ => Err(Error::BadCredential),
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn select_offered_method_with_highest_priority() {
+        let methods = AuthMethods::from(Some(Credential::new("root", "pass")))
+            .register(NoAuth)
+            .register(Challenge(7));
+
+        let code = |offered: &[u8]| methods.select(offered).map(|it| it.code());
+
+        assert_eq!(code(&[NO_AUTH, CREDENTIAL_AUTH]), Some(CREDENTIAL_AUTH));
+        assert_eq!(code(&[NO_AUTH]), Some(NO_AUTH));
+        assert_eq!(code(&[NO_AUTH, CHALLENGE_AUTH]), Some(CHALLENGE_AUTH));
+        assert_eq!(code(&[0x3]), None);
+    }
+
+    #[test]
+    fn register_replaces_same_code() {
+        let methods = AuthMethods::default()
+            .register(Challenge(1))
+            .register(Challenge(2));
+
+        assert_eq!(format!("{methods:?}"), "[128]");
+    }
+
+    #[tokio::test]
+    async fn custom_sub_negotiation() {
+        let (mut client, mut server) = duplex(100);
+        let mut authentication = Authentication(Arc::new(Challenge(7)));
+        client.write_u8(7).await.unwrap();
+
+        let mut session = Session::new(Default::default());
+
+        let result = authentication
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await;
+
+        assert!(matches!(result, Ok(Stage::Connect(_))));
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [7]);
+        assert_eq!(session.user, Some("challenge-7".into()));
+    }
+
+    #[tokio::test]
+    async fn fails_with_custom_sub_negotiation() {
+        let (mut client, mut server) = duplex(100);
+        let mut authentication = Authentication(Arc::new(Challenge(7)));
+        client.write_u8(8).await.unwrap();
+
+        let mut session = Session::new(Default::default());
+
+        let result = authentication
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await;
+
+        assert!(matches!(result, Err(Error::BadCredential)));
+        assert_eq!(session.user, None);
+    }
+}
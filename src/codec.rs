@@ -0,0 +1,941 @@
+//! Sans-IO encoding and decoding of SOCKS5 ([RFC 1928]), username/password ([RFC 1929]) and
+//! SOCKS4/SOCKS4a messages.
+//!
+//! Every message decodes from the front of a byte slice. When the slice is too short,
+//! [`ParseError::Incomplete`] reports how many more bytes are needed before decoding can make
+//! progress, so a caller never has to read past the end of a message. Fields are validated as
+//! soon as they are available, so a bad version byte is reported without waiting for the rest.
+//!
+//! With the `codec` feature, [`MessageCodec`] plugs any message into `tokio_util::codec`.
+//!
+//! [RFC 1928]: https://www.rfc-editor.org/rfc/rfc1928
+//! [RFC 1929]: https://www.rfc-editor.org/rfc/rfc1929
+#![forbid(unsafe_code)]
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    str::Utf8Error,
+};
+
+use crate::constant::{
+    BIND, CONNECT, CREDENTIAL_VERSION, DOMAIN_NAME, IPV4, IPV6, RSV, SOCKS4_REPLY_VER, SOCKS4_VER,
+    UDP_ASSOCIATE, VER,
+};
+
+/// The longest message in the handshake: a [`Socks4Request`] with 255 byte userid and domain.
+pub const MAX_MESSAGE_LEN: usize = 8 + 256 + 256;
+
+pub trait Decode: Sized {
+    /// Decodes a message from the front of `buf`, returning it with the number of bytes consumed.
+    fn decode(buf: &[u8]) -> Result<(Self, usize)>;
+}
+
+pub trait Encode {
+    fn encoded_len(&self) -> usize;
+
+    /// Encodes the message into the front of `dst`, which must hold at least
+    /// [`encoded_len`](Encode::encoded_len) bytes, failing if a field can't be carried.
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError>;
+
+    fn to_vec(&self) -> std::result::Result<Vec<u8>, EncodeError> {
+        let mut buf = vec![0; self.encoded_len()];
+        self.encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// At least this many more bytes are needed to continue decoding.
+    Incomplete(usize),
+    BadVersion(u8),
+    NoMethods,
+    BadCommand(u8),
+    BadRSV(u8),
+    InvalidAtype(u8),
+    InvalidDomainName(Utf8Error),
+    /// A NUL terminated SOCKS4 field is longer than 255 bytes.
+    FieldTooLong,
+    /// The message is longer than [`MAX_MESSAGE_LEN`].
+    MessageTooLong,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete(n) => write!(f, "incomplete message, {n} more bytes needed"),
+            ParseError::BadVersion(ver) => write!(f, "unsupported version {ver:#04x}"),
+            ParseError::NoMethods => f.write_str("no authentication methods offered"),
+            ParseError::BadCommand(cmd) => write!(f, "unknown command {cmd:#04x}"),
+            ParseError::BadRSV(rsv) => write!(f, "reserved byte must be zero, got {rsv:#04x}"),
+            ParseError::InvalidAtype(atype) => write!(f, "unknown address type {atype:#04x}"),
+            ParseError::InvalidDomainName(err) => write!(f, "invalid domain name: {err}"),
+            ParseError::FieldTooLong => f.write_str("field longer than 255 bytes"),
+            ParseError::MessageTooLong => f.write_str("message too long"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A message field the protocol can't carry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EncodeError {
+    /// A length prefixed field, such as a domain name or the methods of a greeting, is longer
+    /// than 255 bytes.
+    FieldTooLong,
+    /// SOCKS4 only carries IPv4 addresses.
+    Ipv6Unsupported,
+    /// The message is longer than [`MAX_MESSAGE_LEN`].
+    MessageTooLong,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::FieldTooLong => f.write_str("field longer than 255 bytes"),
+            EncodeError::Ipv6Unsupported => f.write_str("SOCKS4 only carries IPv4 addresses"),
+            EncodeError::MessageTooLong => f.write_str("message too long"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<EncodeError> for std::io::Error {
+    fn from(err: EncodeError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
+
+impl From<ParseError> for std::io::Error {
+    fn from(err: ParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+struct Input<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Input { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let remaining = self.buf.len() - self.pos;
+        if remaining < n {
+            return Err(ParseError::Incomplete(n - remaining));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn version(&mut self) -> Result<u8> {
+        match self.u8()? {
+            VER => Ok(VER),
+            ver => Err(ParseError::BadVersion(ver)),
+        }
+    }
+
+    fn rsv(&mut self) -> Result<u8> {
+        match self.u8()? {
+            RSV => Ok(RSV),
+            rsv => Err(ParseError::BadRSV(rsv)),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn nul_terminated(&mut self) -> Result<&'a [u8]> {
+        let rest = &self.buf[self.pos..];
+        match rest.iter().take(256).position(|&byte| byte == 0) {
+            Some(n) => {
+                self.pos += n + 1;
+                Ok(&rest[..n])
+            }
+            None if rest.len() >= 256 => Err(ParseError::FieldTooLong),
+            None => Err(ParseError::Incomplete(1)),
+        }
+    }
+}
+
+struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Output<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Output { buf, pos: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+        self
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) -> std::result::Result<&mut Self, EncodeError> {
+        let len = u8::try_from(bytes.len()).map_err(|_| EncodeError::FieldTooLong)?;
+        Ok(self.put(&[len]).put(bytes))
+    }
+}
+
+/// Destination address carried by requests, replies and UDP headers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Addr {
+    Ip(SocketAddr),
+    /// A domain name of at most 255 bytes and a port.
+    Domain(String, u16),
+}
+
+impl Addr {
+    pub fn port(&self) -> u16 {
+        match self {
+            Addr::Ip(addr) => addr.port(),
+            Addr::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl Default for Addr {
+    fn default() -> Self {
+        Addr::Ip(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        Addr::Ip(addr)
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Ip(addr) => addr.fmt(f),
+            Addr::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+impl Decode for Addr {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        let addr = match input.u8()? {
+            IPV4 => {
+                let ip = <[u8; 4]>::try_from(input.take(4)?).unwrap();
+                Addr::Ip(SocketAddr::from((ip, input.u16()?)))
+            }
+            IPV6 => {
+                let ip = <[u8; 16]>::try_from(input.take(16)?).unwrap();
+                Addr::Ip(SocketAddr::from((ip, input.u16()?)))
+            }
+            DOMAIN_NAME => {
+                let domain = input.bytes()?;
+                let domain = std::str::from_utf8(domain).map_err(ParseError::InvalidDomainName)?;
+                Addr::Domain(domain.to_owned(), input.u16()?)
+            }
+            atype => return Err(ParseError::InvalidAtype(atype)),
+        };
+        Ok((addr, input.pos))
+    }
+}
+
+impl Encode for Addr {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Addr::Ip(SocketAddr::V4(_)) => 1 + 4 + 2,
+            Addr::Ip(SocketAddr::V6(_)) => 1 + 16 + 2,
+            Addr::Domain(domain, _) => 1 + 1 + domain.len() + 2,
+        }
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        let mut output = Output::new(dst);
+        match self {
+            Addr::Ip(SocketAddr::V4(addr)) => output.put(&[IPV4]).put(&addr.ip().octets()),
+            Addr::Ip(SocketAddr::V6(addr)) => output.put(&[IPV6]).put(&addr.ip().octets()),
+            Addr::Domain(domain, _) => output.put(&[DOMAIN_NAME]).put_bytes(domain.as_bytes())?,
+        }
+        .put(&self.port().to_be_bytes());
+        Ok(())
+    }
+}
+
+/// `VER NMETHODS METHODS`, the first message sent by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Greeting {
+    pub methods: Vec<u8>,
+}
+
+impl Decode for Greeting {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        input.version()?;
+        let nmethods = input.u8()?;
+        if nmethods == 0 {
+            return Err(ParseError::NoMethods);
+        }
+        let methods = input.take(nmethods as usize)?.to_vec();
+        Ok((Greeting { methods }, input.pos))
+    }
+}
+
+impl Encode for Greeting {
+    fn encoded_len(&self) -> usize {
+        2 + self.methods.len()
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst).put(&[VER]).put_bytes(&self.methods)?;
+        Ok(())
+    }
+}
+
+/// `VER METHOD`, the server's answer to a [`Greeting`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodSelection {
+    pub method: u8,
+}
+
+impl Decode for MethodSelection {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        input.version()?;
+        let method = input.u8()?;
+        Ok((MethodSelection { method }, input.pos))
+    }
+}
+
+impl Encode for MethodSelection {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst).put(&[VER, self.method]);
+        Ok(())
+    }
+}
+
+/// `VER ULEN UNAME PLEN PASSWD`, the username/password sub-negotiation request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub username: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+impl Decode for AuthRequest {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        // clients in the wild send either the RFC 1929 version or the SOCKS5 version.
+        match input.u8()? {
+            version if version > VER => return Err(ParseError::BadVersion(version)),
+            _ => (),
+        }
+        let username = input.bytes()?.to_vec();
+        let password = input.bytes()?.to_vec();
+        Ok((AuthRequest { username, password }, input.pos))
+    }
+}
+
+impl Encode for AuthRequest {
+    fn encoded_len(&self) -> usize {
+        3 + self.username.len() + self.password.len()
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst)
+            .put(&[CREDENTIAL_VERSION])
+            .put_bytes(&self.username)?
+            .put_bytes(&self.password)?;
+        Ok(())
+    }
+}
+
+/// `VER STATUS`, the server's answer to an [`AuthRequest`], with the RFC 1929 version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthResponse {
+    pub status: u8,
+}
+
+impl Decode for AuthResponse {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        match input.u8()? {
+            CREDENTIAL_VERSION => (),
+            ver => return Err(ParseError::BadVersion(ver)),
+        }
+        let status = input.u8()?;
+        Ok((AuthResponse { status }, input.pos))
+    }
+}
+
+impl Encode for AuthResponse {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst).put(&[CREDENTIAL_VERSION, self.status]);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+impl From<Command> for u8 {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Connect => CONNECT,
+            Command::Bind => BIND,
+            Command::UdpAssociate => UDP_ASSOCIATE,
+        }
+    }
+}
+
+/// `VER CMD RSV ATYP DST.ADDR DST.PORT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub command: Command,
+    pub addr: Addr,
+}
+
+impl Decode for Request {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        input.version()?;
+        let command = match input.u8()? {
+            CONNECT => Command::Connect,
+            BIND => Command::Bind,
+            UDP_ASSOCIATE => Command::UdpAssociate,
+            cmd => return Err(ParseError::BadCommand(cmd)),
+        };
+        input.rsv()?;
+        let (addr, n) = Addr::decode(&buf[input.pos..])?;
+        Ok((Request { command, addr }, input.pos + n))
+    }
+}
+
+impl Encode for Request {
+    fn encoded_len(&self) -> usize {
+        3 + self.addr.encoded_len()
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst).put(&[VER, self.command.into(), RSV]);
+        self.addr.encode(&mut dst[3..])
+    }
+}
+
+/// `VER REP RSV ATYP BND.ADDR BND.PORT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub reply: u8,
+    pub addr: Addr,
+}
+
+impl Decode for Reply {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        input.version()?;
+        let reply = input.u8()?;
+        input.rsv()?;
+        let (addr, n) = Addr::decode(&buf[input.pos..])?;
+        Ok((Reply { reply, addr }, input.pos + n))
+    }
+}
+
+impl Encode for Reply {
+    fn encoded_len(&self) -> usize {
+        3 + self.addr.encoded_len()
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst).put(&[VER, self.reply, RSV]);
+        self.addr.encode(&mut dst[3..])
+    }
+}
+
+/// `RSV FRAG ATYP DST.ADDR DST.PORT` that prefixes every UDP datagram; the payload follows the
+/// decoded length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub addr: Addr,
+}
+
+impl Decode for UdpHeader {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        input.rsv()?;
+        input.rsv()?;
+        let frag = input.u8()?;
+        let (addr, n) = Addr::decode(&buf[input.pos..])?;
+        Ok((UdpHeader { frag, addr }, input.pos + n))
+    }
+}
+
+impl Encode for UdpHeader {
+    fn encoded_len(&self) -> usize {
+        3 + self.addr.encoded_len()
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst).put(&[RSV, RSV, self.frag]);
+        self.addr.encode(&mut dst[3..])
+    }
+}
+
+/// `VN CD DSTPORT DSTIP USERID NUL`, followed by `DOMAIN NUL` when SOCKS4a sets `DSTIP` to
+/// `0.0.0.x` with a non-zero `x`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks4Request {
+    pub command: Command,
+    /// An IPv4 address, or a domain name for SOCKS4a.
+    pub addr: Addr,
+    pub userid: Vec<u8>,
+}
+
+impl Decode for Socks4Request {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        match input.u8()? {
+            SOCKS4_VER => (),
+            ver => return Err(ParseError::BadVersion(ver)),
+        }
+        let command = match input.u8()? {
+            CONNECT => Command::Connect,
+            BIND => Command::Bind,
+            cmd => return Err(ParseError::BadCommand(cmd)),
+        };
+        let port = input.u16()?;
+        let ip = <[u8; 4]>::try_from(input.take(4)?).unwrap();
+        let userid = input.nul_terminated()?.to_vec();
+        let addr = match ip {
+            [0, 0, 0, x] if x != 0 => {
+                let domain = input.nul_terminated()?;
+                let domain = std::str::from_utf8(domain).map_err(ParseError::InvalidDomainName)?;
+                Addr::Domain(domain.to_owned(), port)
+            }
+            ip => Addr::Ip(SocketAddr::from((ip, port))),
+        };
+        Ok((
+            Socks4Request {
+                command,
+                addr,
+                userid,
+            },
+            input.pos,
+        ))
+    }
+}
+
+impl Encode for Socks4Request {
+    fn encoded_len(&self) -> usize {
+        let domain = match &self.addr {
+            Addr::Domain(domain, _) => domain.len() + 1,
+            Addr::Ip(_) => 0,
+        };
+        8 + self.userid.len() + 1 + domain
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        // decoders stop looking for the NUL of a field after 255 bytes.
+        let domain = match &self.addr {
+            Addr::Domain(domain, _) => domain.len(),
+            Addr::Ip(_) => 0,
+        };
+        if self.userid.len() > 255 || domain > 255 {
+            return Err(EncodeError::FieldTooLong);
+        }
+        let mut output = Output::new(dst);
+        output
+            .put(&[SOCKS4_VER, self.command.into()])
+            .put(&self.addr.port().to_be_bytes());
+        match &self.addr {
+            Addr::Ip(SocketAddr::V4(addr)) => output.put(&addr.ip().octets()),
+            Addr::Ip(SocketAddr::V6(_)) => return Err(EncodeError::Ipv6Unsupported),
+            Addr::Domain(..) => output.put(&[0, 0, 0, 1]),
+        }
+        .put(&self.userid)
+        .put(&[0]);
+        if let Addr::Domain(domain, _) = &self.addr {
+            output.put(domain.as_bytes()).put(&[0]);
+        }
+        Ok(())
+    }
+}
+
+/// `VN CD DSTPORT DSTIP`, the server's answer to a [`Socks4Request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Socks4Reply {
+    pub reply: u8,
+    pub addr: SocketAddrV4,
+}
+
+impl Decode for Socks4Reply {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut input = Input::new(buf);
+        match input.u8()? {
+            SOCKS4_REPLY_VER => (),
+            ver => return Err(ParseError::BadVersion(ver)),
+        }
+        let reply = input.u8()?;
+        let port = input.u16()?;
+        let ip = <[u8; 4]>::try_from(input.take(4)?).unwrap();
+        let addr = SocketAddrV4::new(ip.into(), port);
+        Ok((Socks4Reply { reply, addr }, input.pos))
+    }
+}
+
+impl Encode for Socks4Reply {
+    fn encoded_len(&self) -> usize {
+        8
+    }
+
+    fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+        Output::new(dst)
+            .put(&[SOCKS4_REPLY_VER, self.reply])
+            .put(&self.addr.port().to_be_bytes())
+            .put(&self.addr.ip().octets());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "codec")]
+pub use framed::MessageCodec;
+
+#[cfg(feature = "codec")]
+mod framed {
+    use std::{io, marker::PhantomData};
+
+    use tokio_util::{
+        bytes::{Buf, BytesMut},
+        codec::{Decoder, Encoder},
+    };
+
+    use super::{Decode, Encode, ParseError};
+
+    /// Adapts a message type to `tokio_util::codec`, decoding `D` and encoding any message.
+    ///
+    /// Parse errors surface as [`io::ErrorKind::InvalidData`] wrapping a [`ParseError`].
+    pub struct MessageCodec<D>(PhantomData<fn() -> D>);
+
+    impl<D> MessageCodec<D> {
+        pub fn new() -> Self {
+            MessageCodec(PhantomData)
+        }
+    }
+
+    impl<D> Default for MessageCodec<D> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<D: Decode> Decoder for MessageCodec<D> {
+        type Item = D;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<D>> {
+            match D::decode(src) {
+                Ok((message, n)) => {
+                    src.advance(n);
+                    Ok(Some(message))
+                }
+                Err(ParseError::Incomplete(n)) => {
+                    src.reserve(n);
+                    Ok(None)
+                }
+                Err(err) => Err(err.into()),
+            }
+        }
+    }
+
+    impl<D, E: Encode> Encoder<E> for MessageCodec<D> {
+        type Error = io::Error;
+
+        fn encode(&mut self, item: E, dst: &mut BytesMut) -> io::Result<()> {
+            let start = dst.len();
+            dst.resize(start + item.encoded_len(), 0);
+            item.encode(&mut dst[start..])
+                .inspect_err(|_| dst.truncate(start))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::constant::{CREDENTIAL_AUTH, NO_AUTH, OK};
+
+    fn roundtrip<M: Decode + Encode + PartialEq + fmt::Debug>(message: M) {
+        let bytes = message.to_vec().unwrap();
+        let (decoded, n) = M::decode(&bytes).unwrap();
+        assert_eq!(n, bytes.len());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn decode_greeting() {
+        let (greeting, n) = Greeting::decode(&[VER, 2, NO_AUTH, CREDENTIAL_AUTH, 0xFF]).unwrap();
+
+        assert_eq!(greeting.methods, [NO_AUTH, CREDENTIAL_AUTH]);
+        assert_eq!(n, 4);
+    }
+
+    #[test]
+    fn incomplete_reports_bytes_needed_for_next_field() {
+        assert_eq!(Greeting::decode(&[]), Err(ParseError::Incomplete(1)));
+        assert_eq!(Greeting::decode(&[VER]), Err(ParseError::Incomplete(1)));
+        assert_eq!(Greeting::decode(&[VER, 3]), Err(ParseError::Incomplete(3)));
+        assert_eq!(
+            Request::decode(&[VER, CONNECT, RSV, IPV6, 0]),
+            Err(ParseError::Incomplete(15))
+        );
+    }
+
+    #[test]
+    fn fields_are_validated_before_message_completes() {
+        assert_eq!(Greeting::decode(&[0x4]), Err(ParseError::BadVersion(0x4)));
+        assert_eq!(Greeting::decode(&[VER, 0]), Err(ParseError::NoMethods));
+        assert_eq!(
+            Request::decode(&[VER, 0x9]),
+            Err(ParseError::BadCommand(0x9))
+        );
+        assert_eq!(
+            Request::decode(&[VER, CONNECT, 0x1]),
+            Err(ParseError::BadRSV(0x1))
+        );
+        assert_eq!(
+            Request::decode(&[VER, CONNECT, RSV, 0x2]),
+            Err(ParseError::InvalidAtype(0x2))
+        );
+        assert_eq!(
+            AuthRequest::decode(&[0x6]),
+            Err(ParseError::BadVersion(0x6))
+        );
+    }
+
+    #[test]
+    fn invalid_domain_name() {
+        let err = Addr::decode(&[DOMAIN_NAME, 2, 0, 159, 0, 80]).unwrap_err();
+
+        assert!(matches!(err, ParseError::InvalidDomainName(_)));
+    }
+
+    #[test]
+    fn auth_request_accepts_lower_versions() {
+        let (request, _) = AuthRequest::decode(&[0x1, 1, b'a', 1, b'b']).unwrap();
+
+        assert_eq!(request.username, b"a");
+        assert_eq!(request.password, b"b");
+    }
+
+    #[test]
+    fn encode_reply() {
+        let reply = Reply {
+            reply: OK,
+            addr: Addr::default(),
+        };
+
+        assert_eq!(
+            reply.to_vec().unwrap(),
+            [VER, OK, RSV, IPV4, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encode_auth_request_with_rfc1929_version() {
+        let request = AuthRequest {
+            username: b"root".to_vec(),
+            password: b"pass".to_vec(),
+        };
+
+        assert_eq!(request.to_vec().unwrap(), b"\x01\x04root\x04pass");
+    }
+
+    #[test]
+    fn auth_response_has_rfc1929_version() {
+        assert_eq!(
+            AuthResponse { status: OK }.to_vec().unwrap(),
+            [CREDENTIAL_VERSION, OK]
+        );
+        assert_eq!(
+            AuthResponse::decode(&[VER, OK]),
+            Err(ParseError::BadVersion(VER))
+        );
+    }
+
+    #[test]
+    fn fields_too_long_fail_to_encode() {
+        let long = "a".repeat(256);
+        let request = Request {
+            command: Command::Connect,
+            addr: Addr::Domain(long.clone(), 80),
+        };
+        assert_eq!(request.to_vec(), Err(EncodeError::FieldTooLong));
+        let greeting = Greeting {
+            methods: vec![NO_AUTH; 256],
+        };
+        assert_eq!(greeting.to_vec(), Err(EncodeError::FieldTooLong));
+        let request = Socks4Request {
+            command: Command::Connect,
+            addr: Addr::Domain(long, 80),
+            userid: vec![],
+        };
+        assert_eq!(request.to_vec(), Err(EncodeError::FieldTooLong));
+        let request = Socks4Request {
+            command: Command::Connect,
+            addr: "[::1]:80".parse::<SocketAddr>().unwrap().into(),
+            userid: vec![],
+        };
+        assert_eq!(request.to_vec(), Err(EncodeError::Ipv6Unsupported));
+    }
+
+    #[test]
+    fn roundtrip_messages() {
+        roundtrip(Greeting {
+            methods: vec![NO_AUTH],
+        });
+        roundtrip(MethodSelection { method: NO_AUTH });
+        roundtrip(AuthRequest {
+            username: b"user".to_vec(),
+            password: vec![],
+        });
+        roundtrip(AuthResponse { status: OK });
+        roundtrip(Request {
+            command: Command::Connect,
+            addr: Addr::Domain("example.com".into(), 443),
+        });
+        roundtrip(Request {
+            command: Command::UdpAssociate,
+            addr: "[::1]:53".parse::<SocketAddr>().unwrap().into(),
+        });
+        roundtrip(Reply {
+            reply: OK,
+            addr: "10.0.0.1:1080".parse::<SocketAddr>().unwrap().into(),
+        });
+        roundtrip(UdpHeader {
+            frag: 0,
+            addr: Addr::Domain("localhost".into(), 53),
+        });
+    }
+
+    #[test]
+    fn decode_never_panics_on_truncated_input() {
+        let bytes = Request {
+            command: Command::Bind,
+            addr: Addr::Domain("example.com".into(), 80),
+        }
+        .to_vec()
+        .unwrap();
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                Request::decode(&bytes[..len]),
+                Err(ParseError::Incomplete(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_socks4a_request() {
+        let (request, n) =
+            Socks4Request::decode(b"\x04\x01\x00\x50\x00\x00\x00\x01root\0localhost\0").unwrap();
+
+        assert_eq!(request.command, Command::Connect);
+        assert_eq!(request.addr, Addr::Domain("localhost".into(), 80));
+        assert_eq!(request.userid, b"root");
+        assert_eq!(n, 23);
+    }
+
+    #[test]
+    fn socks4_fields_are_bounded() {
+        let mut buf = vec![SOCKS4_VER, CONNECT, 0, 80, 127, 0, 0, 1];
+        buf.extend([b'a'; 255]);
+        assert_eq!(Socks4Request::decode(&buf), Err(ParseError::Incomplete(1)));
+
+        buf.push(b'a');
+        assert_eq!(Socks4Request::decode(&buf), Err(ParseError::FieldTooLong));
+    }
+
+    #[test]
+    fn socks4_rejects_udp_associate() {
+        assert_eq!(
+            Socks4Request::decode(&[SOCKS4_VER, UDP_ASSOCIATE]),
+            Err(ParseError::BadCommand(UDP_ASSOCIATE))
+        );
+    }
+
+    #[test]
+    fn roundtrip_socks4_messages() {
+        roundtrip(Socks4Request {
+            command: Command::Bind,
+            addr: "10.0.0.1:21".parse::<SocketAddr>().unwrap().into(),
+            userid: vec![],
+        });
+        roundtrip(Socks4Request {
+            command: Command::Connect,
+            addr: Addr::Domain("example.com".into(), 443),
+            userid: b"user".to_vec(),
+        });
+        roundtrip(Socks4Reply {
+            reply: crate::constant::REQUEST_GRANTED,
+            addr: "0.0.0.0:0".parse().unwrap(),
+        });
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn message_codec() {
+        use tokio_util::{
+            bytes::BytesMut,
+            codec::{Decoder, Encoder},
+        };
+
+        let mut codec = MessageCodec::<Greeting>::new();
+        let mut buf = BytesMut::from(&[VER, 2, NO_AUTH][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&[CREDENTIAL_AUTH]);
+        let greeting = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(greeting.methods, [NO_AUTH, CREDENTIAL_AUTH]);
+        assert!(buf.is_empty());
+
+        codec
+            .encode(MethodSelection { method: NO_AUTH }, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], [VER, NO_AUTH]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    access::{AccessRules, Network},
+    access_log::AccessLog,
+    auth::{AuthMethod, AuthMethods, NoAuth},
+    credential::{Credential, PasswordAuth, PasswordVerifier},
+    forward::Forwarding,
+    hooks::Hooks,
+    quota::Accounting,
+    registry::Registry,
+    resolver::{Resolve, SystemResolver},
+    stats::Stats,
+    tunnel::Dial,
+};
+
+/// Settings shared by every session accepted on one listener.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub(crate) verifier: Option<Arc<dyn PasswordVerifier>>,
+    pub(crate) auth_methods: AuthMethods,
+    pub(crate) socks4: bool,
+    pub(crate) http: bool,
+    pub(crate) trusted: Vec<Network>,
+    pub(crate) access: AccessRules,
+    pub(crate) authenticated_access: Option<AccessRules>,
+    pub(crate) user_access: HashMap<String, AccessRules>,
+    pub(crate) hooks: Arc<dyn Hooks>,
+    pub(crate) resolver: Arc<dyn Resolve>,
+    pub(crate) dialer: Option<Arc<dyn Dial>>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) forwarding: Forwarding,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) accounting: Option<Arc<Accounting>>,
+    pub(crate) access_log: Option<Arc<AccessLog>>,
+    pub(crate) registry: Arc<Registry>,
+}
+
+impl Config {
+    pub fn new(credential: Option<Credential>) -> Self {
+        match credential {
+            Some(credential) => Config::with_verifier(credential),
+            None => Config::with_auth_methods(None, AuthMethods::default().register(NoAuth)),
+        }
+    }
+
+    /// Requires clients to authenticate with a username and password checked by the verifier,
+    /// such as an LDAP directory or a webhook, usually wrapped in [`Cached`](crate::Cached).
+    pub fn with_verifier<V: PasswordVerifier + 'static>(verifier: V) -> Self {
+        let verifier: Arc<dyn PasswordVerifier> = Arc::new(verifier);
+        let auth_methods = AuthMethods::default().register(PasswordAuth::new(verifier.clone()));
+        Config::with_auth_methods(Some(verifier), auth_methods)
+    }
+
+    fn with_auth_methods(
+        verifier: Option<Arc<dyn PasswordVerifier>>,
+        auth_methods: AuthMethods,
+    ) -> Self {
+        Config {
+            verifier,
+            auth_methods,
+            socks4: true,
+            http: true,
+            trusted: vec![],
+            access: AccessRules::default(),
+            authenticated_access: None,
+            user_access: HashMap::new(),
+            hooks: Arc::new(()),
+            resolver: Arc::new(SystemResolver),
+            dialer: None,
+            handshake_timeout: None,
+            forwarding: Forwarding::default(),
+            stats: Arc::default(),
+            accounting: None,
+            access_log: None,
+            registry: Arc::default(),
+        }
+    }
+
+    /// Registers a SOCKS5 authentication method next to the one implied by the verifier,
+    /// replacing any method with the same code.
+    pub fn auth_method<M: AuthMethod + 'static>(mut self, method: M) -> Self {
+        self.auth_methods = self.auth_methods.register(method);
+        self
+    }
+
+    /// Accepts SOCKS4 and SOCKS4a clients next to SOCKS5 ones, enabled by default.
+    ///
+    /// SOCKS4 can't carry a password, so its requests are rejected when a verifier is set. Only
+    /// `CONNECT` is served, `BIND` being rejected.
+    pub fn socks4(mut self, enabled: bool) -> Self {
+        self.socks4 = enabled;
+        self
+    }
+
+    /// Accepts HTTP proxy clients, both `CONNECT` tunnels and plain requests with an absolute
+    /// URI, enabled by default. `Proxy-Authorization: Basic` is checked by the verifier.
+    pub fn http(mut self, enabled: bool) -> Self {
+        self.http = enabled;
+        self
+    }
+
+    /// Lets clients connecting from the network skip authentication, so SOCKS5 clients may
+    /// select `NO_AUTH` while other clients still have to authenticate with a password.
+    pub fn trust(mut self, network: Network) -> Self {
+        self.trusted.push(network);
+        self
+    }
+
+    /// Destinations clients may connect to, allowing everything by default.
+    pub fn access(mut self, rules: AccessRules) -> Self {
+        self.access = rules;
+        self
+    }
+
+    /// Destinations authenticated clients may connect to instead of the [`access`](Self::access)
+    /// rules, which still apply to anonymous and trusted clients.
+    pub fn authenticated_access(mut self, rules: AccessRules) -> Self {
+        self.authenticated_access = Some(rules);
+        self
+    }
+
+    /// Destinations the user with this name may connect to, instead of any other rules.
+    pub fn user_access<S: Into<String>>(mut self, name: S, rules: AccessRules) -> Self {
+        self.user_access.insert(name.into(), rules);
+        self
+    }
+
+    /// Totals the traffic of authenticated users, whose sessions are refused or closed once they
+    /// are over their quota.
+    pub fn accounting(mut self, accounting: Arc<Accounting>) -> Self {
+        self.accounting = Some(accounting);
+        self
+    }
+
+    /// Writes a line for every finished session.
+    pub fn access_log(mut self, log: AccessLog) -> Self {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Callbacks for the events of every session.
+    pub fn hooks<H: Hooks + 'static>(mut self, hooks: H) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Resolves the domain names clients connect to, the system DNS configuration by default.
+    pub fn resolver<R: Resolve + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Opens the upstream of every session with the dialer, such as a [`Tunnel`](crate::Tunnel)
+    /// to a remote instance, instead of connecting to the target.
+    pub fn dial<D: Dial + 'static>(mut self, dialer: D) -> Self {
+        self.dialer = Some(Arc::new(dialer));
+        self
+    }
+
+    /// Closes sessions that haven't finished their handshake and connected upstream in time.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// How connected sessions relay, such as their buffer sizes and how they close.
+    pub fn forwarding(mut self, forwarding: Forwarding) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new(None)
+    }
+}
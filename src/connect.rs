@@ -1,108 +1,157 @@
 use std::{future::Future, net::SocketAddr};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use trust_dns_resolver::{
-    error::ResolveError,
-    name_server::{GenericConnector, TokioRuntimeProvider},
-    AsyncResolver, TokioAsyncResolver,
-};
-
 use crate::{
-    constant::{CONNECT, DOMAIN_NAME, IPV4, IPV6, OK, RSV, UNSPECIFIED_SOCKET_ADDR, VER},
-    error::Error::{self, *},
-    extract::{try_extract_rsv, try_extract_version},
-    forward::Forward,
+    codec::{Addr, Command, Reply, Request},
+    constant::OK,
+    error::Error::*,
+    forward::{Forward, Remote},
     marker::{Stream, UnpinAsyncRead},
-    read_vec_u8, IOResult, Result, Stage, Upstream,
+    read_message,
+    session::Session,
+    write_message, IOResult, Result, Stage, Upstream,
 };
 
-lazy_static::lazy_static! {
-    static ref DNS_RESOLVER: AsyncResolver<GenericConnector<TokioRuntimeProvider>> = TokioAsyncResolver::tokio_from_system_conf().unwrap();
-}
-
 #[derive(Debug)]
 pub struct Connect;
 
 impl Connect {
-    pub async fn run<'a, S: Stream, U>(&mut self, mut client: S) -> Result<Stage<U>>
+    pub async fn run<'a, S: Stream, U>(
+        &mut self,
+        mut client: S,
+        session: &mut Session,
+    ) -> Result<Stage<U>>
     where
         U: Upstream<'a>,
         U::Output: Future<Output = IOResult<U>>,
     {
-        let addr = try_extract_addr(&mut client).await?;
-        client.write_all(&[VER, OK, RSV, IPV4]).await?;
-        client.write_all(&UNSPECIFIED_SOCKET_ADDR).await?;
-        let upstream = U::connect(addr).await?;
+        let target = try_extract_addr(&mut client).await?;
+        session.command = Some("CONNECT".into());
+        let upstream = connect_upstream(session, target).await?;
+        let reply = Reply {
+            reply: OK,
+            addr: Addr::default(),
+        };
+        write_message(&mut client, &reply).await?;
         Ok(Stage::Forward(Forward(upstream)))
     }
 }
 
-extract!(connect_cmd != CONNECT => BadCommand(connect_cmd));
-
-async fn try_extract_addr<T: UnpinAsyncRead>(mut client: T) -> Result<SocketAddr> {
-    _ = try_extract_version(&mut client).await?;
-    _ = try_extract_connect_cmd(&mut client).await?;
-    _ = try_extract_rsv(&mut client).await?;
-    let atype = client.read_u8().await?;
-    match atype {
-        IPV4 => {
-            let ip = client.read_u32().await?;
-            let port = client.read_u16().await?;
-            Ok(SocketAddr::from((ip.to_be_bytes(), port)))
-        }
-        IPV6 => {
-            let ip = client.read_u128().await?;
-            let port = client.read_u16().await?;
-            Ok(SocketAddr::from((ip.to_be_bytes(), port)))
-        }
-        DOMAIN_NAME => {
-            let len = client.read_u8().await? as usize;
-            let domain = read_vec_u8(&mut client, len).await?;
-            let domain = std::str::from_utf8(&domain).map_err(Error::InvalidDomainName)?;
-            let port = client.read_u16().await?;
-            let record = DNS_RESOLVER
-                .ipv4_lookup(domain)
-                .await?
-                .into_iter()
-                .next()
-                .ok_or_else(|| ResolveError::from("No record found"))?;
-            Ok(SocketAddr::from((record.0, port)))
+async fn try_extract_addr<T: UnpinAsyncRead>(client: T) -> Result<Addr> {
+    let Request { command, addr } = read_message(client).await?;
+    if command != Command::Connect {
+        return Err(BadCommand(command.into()));
+    }
+    Ok(addr)
+}
+
+/// Resolves the target, checks it against the access rules and connects to it, running the
+/// connect hooks around. With a [`Dial`](crate::Dial) configured, the target is dialed instead.
+/// Domains are then only resolved locally when the access rules of the client deny anything,
+/// and are otherwise left to whatever the dialer connects through, which leaves the session
+/// without a resolved target.
+pub(crate) async fn connect_upstream<'a, U>(
+    session: &mut Session,
+    mut target: Addr,
+) -> Result<Remote<U>>
+where
+    U: Upstream<'a>,
+    U::Output: Future<Output = IOResult<U>>,
+{
+    session.requested = Some(target.clone());
+    session.hooks().before_connect(session, &mut target).await?;
+    if let Some(dialer) = session.config.dialer.clone() {
+        session.check_quota()?;
+        let target = match target {
+            Addr::Ip(addr) => Addr::Ip(session.permit(addr)?),
+            domain if session.restricted() => {
+                Addr::Ip(session.permit(resolve(session, domain).await?)?)
+            }
+            domain => domain,
+        };
+        let upstream = dialer.dial(&target).await?;
+        if let Addr::Ip(addr) = target {
+            session.target = Some(addr);
+            session.hooks().after_connect(session, addr).await?;
         }
-        _ => Err(Error::InvalidAtype(atype)),
+        return Ok(Remote::Dialed(upstream));
+    }
+    let addr = session.permit(resolve(session, target).await?)?;
+    let upstream = U::connect(addr).await?;
+    session.target = Some(addr);
+    session.hooks().after_connect(session, addr).await?;
+    Ok(Remote::Direct(upstream))
+}
+
+async fn resolve(session: &Session, addr: Addr) -> Result<SocketAddr> {
+    match addr {
+        Addr::Ip(addr) => Ok(addr),
+        Addr::Domain(domain, port) => session.config.resolver.resolve(&domain, port).await,
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use std::{io::Cursor, net::SocketAddr};
+    use std::{
+        io::Cursor,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
 
     use tokio::{
         io::{duplex, AsyncWriteExt},
-        net::TcpStream,
+        net::{TcpListener, TcpStream},
     };
-    use trust_dns_resolver::TokioAsyncResolver;
 
     use crate::{
-        connect::Connect,
+        access::AccessRules,
+        codec::Addr,
+        config::Config,
+        connect::{connect_upstream, Connect},
         constant::{CONNECT, DOMAIN_NAME, IPV4, IPV6, OK, RSV, UNSPECIFIED_SOCKET_ADDR, VER},
         error::Error::*,
+        forward::{Forward, Remote},
+        quota::{Accounting, Quota},
+        resolver::Resolve,
+        session::Session,
         test::AsyncExactRead,
-        Stage,
+        BoxFuture, Dial, Result, Stage, Stream,
     };
 
+    /// Resolves every domain to loopback.
+    struct Loopback;
+
+    impl Resolve for Loopback {
+        fn resolve<'a>(&'a self, _: &'a str, port: u16) -> BoxFuture<'a, Result<SocketAddr>> {
+            Box::pin(async move { Ok(SocketAddr::from(([127, 0, 0, 1], port))) })
+        }
+    }
+
+    fn session() -> Session {
+        Session::new(Config::default().resolver(Loopback).into())
+    }
+
     #[tokio::test]
     async fn connect() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap();
         let (mut client, mut server) = duplex(usize::MAX);
         let mut connect = Connect;
         client
-            .write_all(&[VER, CONNECT, RSV, IPV4, 14, 119, 104, 254, 0, 80])
+            .write_all(&[VER, CONNECT, RSV, IPV4, 127, 0, 0, 1])
+            .await
+            .unwrap();
+        client
+            .write_all(&target.port().to_be_bytes())
             .await
             .unwrap();
 
-        let forward = connect.run::<_, TcpStream>(&mut server).await.unwrap();
+        let forward = connect
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap();
         assert!(matches!(forward,
-                Stage::Forward(forward) if forward.0.peer_addr().unwrap() == "14.119.104.254:80".parse().unwrap()));
+                Stage::Forward(Forward(Remote::Direct(upstream))) if upstream.peer_addr().unwrap() == target));
 
         let response = client.read_exact_bytes::<10>().await.unwrap();
         assert_eq!(response[..4], [VER, OK, RSV, IPV4]);
@@ -118,7 +167,10 @@ mod tests {
             .await
             .unwrap();
 
-        let err = connect.run::<_, TcpStream>(&mut server).await.unwrap_err();
+        let err = connect
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
         assert!(matches!(err, BadVersion(0x6)));
     }
 
@@ -131,7 +183,10 @@ mod tests {
             .await
             .unwrap();
 
-        let err = connect.run::<_, TcpStream>(&mut server).await.unwrap_err();
+        let err = connect
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
         assert!(matches!(err, BadCommand(0x6)));
     }
 
@@ -144,10 +199,88 @@ mod tests {
             .await
             .unwrap();
 
-        let err = connect.run::<_, TcpStream>(&mut server).await.unwrap_err();
+        let err = connect
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
         assert!(matches!(err, BadRSV(0x1)));
     }
 
+    #[tokio::test]
+    async fn fails_with_denied_destination() {
+        let (mut client, mut server) = duplex(usize::MAX);
+        let mut connect = Connect;
+        let config =
+            Config::default().access(AccessRules::default().deny("1.2.3.0/24".parse().unwrap()));
+        client
+            .write_all(&[VER, CONNECT, RSV, IPV4, 1, 2, 3, 4, 0, 80])
+            .await
+            .unwrap();
+
+        let err = connect
+            .run::<_, TcpStream>(&mut server, &mut Session::new(config.into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NotAllowed(addr) if addr == "1.2.3.4:80".parse().unwrap()));
+    }
+
+    /// Dials nothing, recording the targets it's asked for.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Addr>>>);
+
+    impl Dial for Recorder {
+        fn dial<'a>(&'a self, target: &'a Addr) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+            self.0.lock().unwrap().push(target.clone());
+            Box::pin(async { Ok(Box::new(duplex(1).0) as Box<dyn Stream>) })
+        }
+    }
+
+    fn example() -> Addr {
+        Addr::Domain("example.test".into(), 80)
+    }
+
+    #[tokio::test]
+    async fn dial_domains_within_quota() {
+        let dialer = Recorder::default();
+        let accounting = Accounting::new().quota(Quota {
+            daily: Some(0),
+            monthly: None,
+        });
+        let config = Config::default()
+            .resolver(Loopback)
+            .dial(dialer.clone())
+            .accounting(Arc::new(accounting));
+        let mut session = Session::new(config.into());
+        session.user = Some("alice".into());
+
+        let result = connect_upstream::<TcpStream>(&mut session, example()).await;
+        assert!(matches!(result, Err(QuotaExceeded)));
+        assert!(dialer.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dial_domains_checked_against_restricting_rules() {
+        let dialer = Recorder::default();
+        let config = Config::default()
+            .resolver(Loopback)
+            .dial(dialer.clone())
+            .user_access(
+                "bob",
+                AccessRules::default().deny("127.0.0.0/8".parse().unwrap()),
+            );
+        let mut session = Session::new(config.into());
+
+        connect_upstream::<TcpStream>(&mut session, example())
+            .await
+            .unwrap();
+        assert_eq!(session.target, None);
+
+        session.user = Some("bob".into());
+        let result = connect_upstream::<TcpStream>(&mut session, example()).await;
+        assert!(matches!(result, Err(NotAllowed(_))));
+        assert_eq!(*dialer.0.lock().unwrap(), [example()]);
+    }
+
     #[tokio::test]
     async fn extract_ipv6_addr() {
         let buf = Cursor::new([
@@ -156,13 +289,13 @@ mod tests {
         ]);
         let addr = super::try_extract_addr(buf).await.unwrap();
 
-        assert_eq!(addr, "[::1]:10".parse().unwrap());
+        assert_eq!(addr, Addr::Ip("[::1]:10".parse().unwrap()));
     }
 
     #[tokio::test]
     async fn extract_domain_name() {
         let buf = Cursor::new({
-            let host = "www.baidu.com";
+            let host = "www.example.test";
             let mut buf = vec![VER, CONNECT, RSV, DOMAIN_NAME];
             buf.push(host.len() as u8);
             buf.extend(host.as_bytes());
@@ -170,14 +303,10 @@ mod tests {
             buf
         });
         let addr = super::try_extract_addr(buf).await.unwrap();
+        assert_eq!(addr, Addr::Domain("www.example.test".into(), 80));
 
-        let ip = TokioAsyncResolver::tokio_from_system_conf().unwrap();
-        let lookup = ip.ipv4_lookup("www.baidu.com").await.unwrap();
-        let available = lookup
-            .iter()
-            .map(|it| SocketAddr::from((it.0, 80)))
-            .collect::<Vec<_>>();
-        assert!(available.contains(&addr));
+        let addr = super::resolve(&session(), addr).await.unwrap();
+        assert_eq!(addr, "127.0.0.1:80".parse().unwrap());
     }
 
     #[tokio::test]
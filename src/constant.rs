@@ -1,9 +1,13 @@
 pub const VER: u8 = 0x5;
 pub const NO_AUTH: u8 = 0x0;
 pub const CREDENTIAL_AUTH: u8 = 0x02;
+pub const CREDENTIAL_VERSION: u8 = 0x1;
 pub const OK: u8 = 0x0;
+pub const GENERAL_FAILURE: u8 = 0x1;
 pub const AUTH_ERROR: u8 = 0x1;
 pub const CONNECT: u8 = 0x1;
+pub const BIND: u8 = 0x2;
+pub const UDP_ASSOCIATE: u8 = 0x3;
 pub const RSV: u8 = 0x0;
 pub const IPV4: u8 = 0x1;
 pub const DOMAIN_NAME: u8 = 0x3;
@@ -12,4 +16,9 @@ pub const UNSPECIFIED_SOCKET_ADDR: [u8; 6] = [0x0, 0x0, 0x0, 0x0, 0x0, 0x0];
 pub const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
 pub const UNSUPPORTED_COMMAND: u8 = 0x7;
 pub const CONNECTION_REFUSED: u8 = 0x02;
+pub const CONNECTION_NOT_ALLOWED: u8 = 0x02;
 pub const TARGET_SERVER_UNREACHABLE: u8 = 0x04;
+pub const SOCKS4_VER: u8 = 0x4;
+pub const SOCKS4_REPLY_VER: u8 = 0x0;
+pub const REQUEST_GRANTED: u8 = 0x5A;
+pub const REQUEST_REJECTED: u8 = 0x5B;
@@ -1,17 +1,42 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    connect::Connect,
-    constant::{OK, VER},
+    auth::{AuthMethod, User},
+    codec::{AuthRequest, AuthResponse},
+    constant::{CREDENTIAL_AUTH, OK},
     error::Error,
     marker::{Stream, UnpinAsyncRead},
-    read_vec_u8, Result, Stage,
+    read_message, write_message, BoxFuture, Result,
 };
 
+/// Checks a username and password, resolving to the user on success or `None` to reject them.
+///
+/// Errors are reserved for a backend that couldn't give an answer.
+pub trait PasswordVerifier: Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>>;
+}
+
+impl fmt::Debug for dyn PasswordVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordVerifier")
+    }
+}
+
+/// A single static username and password.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Credential {
-    username: String,
-    password: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
 }
 
 impl Credential {
@@ -25,51 +50,202 @@ impl Credential {
             password: password.into(),
         }
     }
+}
+
+impl PasswordVerifier for Credential {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        let matched = self.username == username && self.password == password;
+        Box::pin(async move { Ok(matched.then(|| User::new(username))) })
+    }
+}
+
+/// Users added and disabled while the server runs, such as through the admin API.
+#[derive(Debug, Default)]
+pub struct Users(RwLock<HashMap<String, String>>);
 
-    pub(crate) async fn run<S: Stream, U>(&mut self, mut client: S) -> Result<Stage<U>> {
-        let (username, password) = try_extract_credential(&mut client).await?;
-        if self.username.as_bytes() != username || self.password.as_bytes() != password {
-            return Err(Error::BadCredential);
+impl Users {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the user, or changes their password.
+    pub fn add<U, P>(&self, username: U, password: P)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let mut users = self.0.write().unwrap();
+        users.insert(username.into(), password.into());
+    }
+
+    /// Rejects the user from now on, returning whether they existed. Running sessions go on.
+    pub fn disable(&self, username: &str) -> bool {
+        self.0.write().unwrap().remove(username).is_some()
+    }
+}
+
+impl PasswordVerifier for Users {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        let matched = self.0.read().unwrap().get(username).map(String::as_str) == Some(password);
+        Box::pin(async move { Ok(matched.then(|| User::new(username))) })
+    }
+}
+
+impl<V: PasswordVerifier + ?Sized> PasswordVerifier for Arc<V> {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        (**self).verify(username, password)
+    }
+}
+
+/// The most users remembered at once.
+const MAX_CACHED: usize = 10_000;
+
+/// Remembers the users another verifier accepted for a while, so a busy client doesn't hit the
+/// backend on every connection. Passwords are only kept as keyed hashes, and rejections and
+/// backend errors are never remembered.
+pub struct Cached<V> {
+    inner: V,
+    ttl: Duration,
+    hasher: RandomState,
+    answers: Mutex<Answers>,
+}
+
+#[derive(Default)]
+struct Answers {
+    /// The hash of the accepted password and the user, with the instant they expire.
+    users: HashMap<String, (u64, Instant, User)>,
+    /// When the expired answers are next dropped.
+    sweep_at: Option<Instant>,
+}
+
+impl<V> Cached<V> {
+    pub fn new(inner: V, ttl: Duration) -> Self {
+        Cached {
+            inner,
+            ttl,
+            hasher: RandomState::new(),
+            answers: Mutex::default(),
         }
-        client.write_all(&[VER, OK]).await?;
-        Ok(Stage::Connect(Connect))
     }
 }
 
-extract!(version > VER => Error::BadVersion(version));
+impl<V: PasswordVerifier> PasswordVerifier for Cached<V> {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move {
+            let hash = self.hasher.hash_one(password);
+            let now = Instant::now();
+            if let Some((accepted, expires, user)) =
+                self.answers.lock().unwrap().users.get(username)
+            {
+                if *accepted == hash && *expires > now {
+                    return Ok(Some(user.clone()));
+                }
+            }
+            let Some(user) = self.inner.verify(username, password).await? else {
+                return Ok(None);
+            };
+            let mut answers = self.answers.lock().unwrap();
+            // expired answers are dropped at most once a ttl, rather than looked for on every miss.
+            if answers.sweep_at.is_none_or(|it| it <= now) {
+                answers.users.retain(|_, (_, expires, _)| *expires > now);
+                answers.sweep_at = Some(now + self.ttl);
+            }
+            if answers.users.len() < MAX_CACHED || answers.users.contains_key(username) {
+                let answer = (hash, now + self.ttl, user.clone());
+                answers.users.insert(username.to_owned(), answer);
+            }
+            Ok(Some(user))
+        })
+    }
+}
 
-async fn try_extract_credential<R: UnpinAsyncRead>(mut client: R) -> Result<(Vec<u8>, Vec<u8>)> {
-    try_extract_version(&mut client).await?;
-    let username = {
-        let ulen = client.read_u8().await? as usize;
-        read_vec_u8(&mut client, ulen).await?
-    };
-    let password = {
-        let plen = client.read_u8().await? as usize;
-        read_vec_u8(&mut client, plen).await?
-    };
+/// RFC 1929 username/password authentication against a [`PasswordVerifier`].
+#[derive(Debug, Clone)]
+pub struct PasswordAuth(Arc<dyn PasswordVerifier>);
+
+impl PasswordAuth {
+    pub fn new(verifier: Arc<dyn PasswordVerifier>) -> Self {
+        PasswordAuth(verifier)
+    }
+}
+
+impl AuthMethod for PasswordAuth {
+    fn code(&self) -> u8 {
+        CREDENTIAL_AUTH
+    }
+
+    /// Preferred over `NO_AUTH` when a client offers both.
+    fn priority(&self) -> i32 {
+        1
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        mut client: &'a mut dyn Stream,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move {
+            let (username, password) = try_extract_credential(&mut client).await?;
+            let (Ok(username), Ok(password)) =
+                (String::from_utf8(username), String::from_utf8(password))
+            else {
+                return Err(Error::BadCredential);
+            };
+            let Some(user) = self.0.verify(&username, &password).await? else {
+                return Err(Error::BadCredential);
+            };
+            write_message(&mut client, &AuthResponse { status: OK }).await?;
+            Ok(Some(user))
+        })
+    }
+}
+
+async fn try_extract_credential<R: UnpinAsyncRead>(client: R) -> Result<(Vec<u8>, Vec<u8>)> {
+    let AuthRequest { username, password } = read_message(client).await?;
     Ok((username, password))
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::{
-        io::{duplex, AsyncWriteExt},
-        net::TcpStream,
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
     };
 
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use super::*;
     use crate::{
-        constant::{OK, VER},
-        credential::Credential,
-        error::Error,
+        constant::{CREDENTIAL_VERSION, OK, VER},
         test::AsyncExactRead,
-        Stage,
     };
 
+    fn password_auth() -> PasswordAuth {
+        PasswordAuth::new(Arc::new(Credential::new("root", "pass")))
+    }
+
     #[tokio::test]
     async fn authenticate_with_valid_credential() {
-        let mut it = Credential::new("root", "pass");
-        let (mut a, b) = duplex(usize::MAX);
+        let it = password_auth();
+        let (mut a, mut b) = duplex(usize::MAX);
 
         a.write_all(&[VER]).await.unwrap();
         a.write_all(&[4]).await.unwrap();
@@ -77,15 +253,18 @@ mod tests {
         a.write_all(&[4]).await.unwrap();
         a.write_all(b"pass").await.unwrap();
 
-        let result = it.run::<_, TcpStream>(b).await;
-        assert!(matches!(result, Ok(Stage::Connect(_))));
-        assert_eq!(a.read_exact_bytes().await.unwrap(), [VER, OK]);
+        let result = it.authenticate(&mut b).await;
+        assert!(matches!(result, Ok(Some(user)) if user.name == "root"));
+        assert_eq!(
+            a.read_exact_bytes().await.unwrap(),
+            [CREDENTIAL_VERSION, OK]
+        );
     }
 
     #[tokio::test]
     async fn authenticate_with_lower_version_is_ok() {
-        let mut it = Credential::new("root", "pass");
-        let (mut a, b) = duplex(usize::MAX);
+        let it = password_auth();
+        let (mut a, mut b) = duplex(usize::MAX);
 
         a.write_all(&[0x1]).await.unwrap();
         a.write_all(&[4]).await.unwrap();
@@ -93,26 +272,29 @@ mod tests {
         a.write_all(&[4]).await.unwrap();
         a.write_all(b"pass").await.unwrap();
 
-        let result = it.run::<_, TcpStream>(b).await;
-        assert!(matches!(result, Ok(Stage::Connect(_))));
-        assert_eq!(a.read_exact_bytes().await.unwrap(), [VER, OK]);
+        let result = it.authenticate(&mut b).await;
+        assert!(matches!(result, Ok(Some(user)) if user.name == "root"));
+        assert_eq!(
+            a.read_exact_bytes().await.unwrap(),
+            [CREDENTIAL_VERSION, OK]
+        );
     }
 
     #[tokio::test]
     async fn fails_with_bad_version() {
-        let mut it = Credential::new("root", "pass");
-        let (mut a, b) = duplex(usize::MAX);
+        let it = password_auth();
+        let (mut a, mut b) = duplex(usize::MAX);
 
         a.write_all(&[0x06]).await.unwrap();
 
-        let result = it.run::<_, TcpStream>(b).await;
+        let result = it.authenticate(&mut b).await;
         assert!(matches!(result, Err(Error::BadVersion(0x6))));
     }
 
     #[tokio::test]
     async fn fails_with_bad_credential() {
-        let mut it = Credential::new("root", "pass");
-        let (mut a, b) = duplex(usize::MAX);
+        let it = password_auth();
+        let (mut a, mut b) = duplex(usize::MAX);
 
         a.write_all(&[VER]).await.unwrap();
         a.write_all(&[4]).await.unwrap();
@@ -120,7 +302,59 @@ mod tests {
         a.write_all(&[3]).await.unwrap();
         a.write_all(b"bad").await.unwrap();
 
-        let result = it.run::<_, TcpStream>(b).await;
+        let result = it.authenticate(&mut b).await;
         assert!(matches!(result, Err(Error::BadCredential)));
     }
+
+    /// Accepts every password equal to the username, counting the calls.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl PasswordVerifier for Counting {
+        fn verify<'a>(
+            &'a self,
+            username: &'a str,
+            password: &'a str,
+        ) -> BoxFuture<'a, Result<Option<User>>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok((username == password).then(|| User::new(username))) })
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_answers_expire() {
+        let it = Cached::new(Counting::default(), Duration::from_millis(50));
+
+        assert!(it.verify("root", "root").await.unwrap().is_some());
+        assert!(it.verify("root", "root").await.unwrap().is_some());
+        assert_eq!(it.inner.0.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(it.verify("root", "root").await.unwrap().is_some());
+        assert_eq!(it.inner.0.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejections_are_not_cached() {
+        let it = Cached::new(Counting::default(), Duration::from_secs(60));
+
+        assert!(it.verify("root", "root").await.unwrap().is_some());
+        assert!(it.verify("root", "bad").await.unwrap().is_none());
+        assert!(it.verify("root", "bad").await.unwrap().is_none());
+        assert_eq!(it.inner.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn add_and_disable_users() {
+        let users = Arc::new(Users::new());
+        let verifier: Arc<dyn PasswordVerifier> = users.clone();
+
+        users.add("alice", "secret");
+        assert!(verifier.verify("alice", "secret").await.unwrap().is_some());
+        assert!(verifier.verify("alice", "bad").await.unwrap().is_none());
+
+        assert!(users.disable("alice"));
+        assert!(!users.disable("alice"));
+        assert!(verifier.verify("alice", "secret").await.unwrap().is_none());
+    }
 }
@@ -0,0 +1,162 @@
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    auth::NoAuth,
+    constant::{SOCKS4_VER, VER},
+    error::Error,
+    http::Http,
+    marker::Stream,
+    negotiation::Negotiation,
+    rewind::Rewind,
+    session::Session,
+    socks4::Socks4,
+    Result, Stage,
+};
+
+/// Routes a session by the protocol version in its first byte, leaving the byte for the routed
+/// stage to decode. Trusted clients are let through without a password.
+#[derive(Debug)]
+pub struct Detect;
+
+impl Detect {
+    pub async fn run<S: Stream, U>(
+        &mut self,
+        client: &mut Rewind<S>,
+        session: &Session,
+    ) -> Result<Stage<U>> {
+        let version = client.read_u8().await?;
+        client.rewind(&[version]);
+        let config = &session.config;
+        let (methods, verifier) = match session.trusted() {
+            true => (config.auth_methods.clone().register(NoAuth), None),
+            false => (config.auth_methods.clone(), config.verifier.clone()),
+        };
+        match version {
+            VER => Ok(Stage::Negotiation(Negotiation(methods))),
+            SOCKS4_VER if config.socks4 => Ok(Stage::Socks4(Socks4(verifier))),
+            // every HTTP method starts with an uppercase letter.
+            b'A'..=b'Z' if config.http => Ok(Stage::Http(Http(verifier))),
+            _ => Err(Error::BadVersion(version)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{duplex, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::Detect;
+    use crate::{
+        config::Config,
+        constant::{NO_AUTH, SOCKS4_VER, VER},
+        credential::Credential,
+        error::Error,
+        rewind::Rewind,
+        session::Session,
+        test::AsyncExactRead,
+        Stage,
+    };
+
+    fn new_session(config: Config) -> Session {
+        Session::new(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn routes_socks5() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let session = new_session(Config::default());
+        client.write_all(&[VER]).await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+
+        assert!(matches!(result, Ok(Stage::Negotiation(_))));
+        assert_eq!(server.read_exact_bytes().await.unwrap(), [VER]);
+    }
+
+    #[tokio::test]
+    async fn routes_socks4() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let session = new_session(Config::default());
+        client.write_all(&[SOCKS4_VER]).await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+
+        assert!(matches!(result, Ok(Stage::Socks4(_))));
+        assert_eq!(server.read_exact_bytes().await.unwrap(), [SOCKS4_VER]);
+    }
+
+    #[tokio::test]
+    async fn routes_http() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let session = new_session(Config::default());
+        client.write_all(b"CONNECT").await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+
+        assert!(matches!(result, Ok(Stage::Http(_))));
+        assert_eq!(server.read_exact_bytes().await.unwrap(), *b"CONNECT");
+    }
+
+    #[tokio::test]
+    async fn fails_with_http_disabled() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let session = new_session(Config::default().http(false));
+        client.write_all(b"GET").await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+
+        assert!(matches!(result, Err(Error::BadVersion(b'G'))));
+    }
+
+    #[tokio::test]
+    async fn fails_with_socks4_disabled() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let session = new_session(Config::default().socks4(false));
+        client.write_all(&[SOCKS4_VER]).await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+
+        assert!(matches!(result, Err(Error::BadVersion(SOCKS4_VER))));
+    }
+
+    #[tokio::test]
+    async fn trusted_clients_may_skip_authentication() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let config = Config::new(Some(Credential::new("root", "pass")))
+            .trust("127.0.0.0/8".parse().unwrap());
+        let mut session = new_session(config);
+        session.peer = Some("127.0.0.1:5000".parse().unwrap());
+        client.write_all(&[VER, VER]).await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+        assert!(matches!(result, Ok(Stage::Negotiation(it)) if it.0.select(&[NO_AUTH]).is_some()));
+
+        session.peer = Some("10.0.0.1:5000".parse().unwrap());
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+        assert!(matches!(result, Ok(Stage::Negotiation(it)) if it.0.select(&[NO_AUTH]).is_none()));
+    }
+
+    #[tokio::test]
+    async fn trusted_clients_skip_proxy_authorization() {
+        let (mut client, server) = duplex(100);
+        let mut server = Rewind::new(server);
+        let mut session = new_session(Config::new(Some(Credential::new("root", "pass"))));
+        session.user = Some("alice".into());
+        client.write_all(&[SOCKS4_VER]).await.unwrap();
+
+        let result = Detect.run::<_, TcpStream>(&mut server, &session).await;
+
+        assert!(matches!(result, Ok(Stage::Socks4(it)) if it.0.is_none()));
+    }
+}
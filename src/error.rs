@@ -1,19 +1,26 @@
-use std::{io, str::Utf8Error};
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    str::Utf8Error,
+};
 
 use tokio::io::AsyncWriteExt;
 use trust_dns_resolver::error::ResolveError;
 
 use crate::{
+    codec::{Addr, AuthResponse, ParseError, Reply, Socks4Reply},
     constant::{
-        AUTH_ERROR, CONNECTION_REFUSED, NO_ACCEPTABLE_METHODS, TARGET_SERVER_UNREACHABLE,
-        UNSUPPORTED_COMMAND, VER,
+        AUTH_ERROR, CONNECTION_NOT_ALLOWED, CONNECTION_REFUSED, GENERAL_FAILURE,
+        NO_ACCEPTABLE_METHODS, REQUEST_REJECTED, TARGET_SERVER_UNREACHABLE, UNSUPPORTED_COMMAND,
+        VER,
     },
     marker::UnpinAsyncWrite,
-    IOResult,
+    write_message, IOResult,
 };
 
 #[derive(Debug)]
 #[non_exhaustive]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     BadVersion(u8),
     NoAuthMethods,
@@ -24,36 +31,169 @@ pub enum Error {
     InvalidAtype(u8),
     InvalidDomainName(Utf8Error),
     ResolveDomainError(ResolveError),
+    BadHttpRequest(&'static str),
+    NotAllowed(SocketAddr),
+    QuotaExceeded,
+    /// Ended through [`Control::terminate`](crate::Control::terminate).
+    Terminated,
+    /// Sent as is in a SOCKS5 reply, ends the session from a [`Hooks`](crate::Hooks) callback.
+    Rejected(u8),
     IO(io::Error),
 }
 
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const PROXY_AUTHENTICATION_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+Proxy-Authenticate: Basic realm=\"socks5\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const BAD_GATEWAY: &[u8] =
+    b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const SERVICE_UNAVAILABLE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Self::IO(err)
     }
 }
 
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::BadVersion(ver) => Self::BadVersion(ver),
+            ParseError::NoMethods => Self::NoAuthMethods,
+            ParseError::BadCommand(cmd) => Self::BadCommand(cmd),
+            ParseError::BadRSV(rsv) => Self::BadRSV(rsv),
+            ParseError::InvalidAtype(atype) => Self::InvalidAtype(atype),
+            ParseError::InvalidDomainName(err) => Self::InvalidDomainName(err),
+            err => Self::IO(err.into()),
+        }
+    }
+}
+
 impl From<ResolveError> for Error {
     fn from(err: ResolveError) -> Self {
         Self::ResolveDomainError(err)
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadVersion(ver) => write!(f, "unsupported version {ver:#04x}"),
+            Error::NoAuthMethods => f.write_str("no authentication methods offered"),
+            Error::UnacceptableMethods(methods) => {
+                write!(f, "no acceptable authentication methods in {methods:02x?}")
+            }
+            Error::BadCredential => f.write_str("bad credential"),
+            Error::BadCommand(cmd) => write!(f, "unsupported command {cmd:#04x}"),
+            Error::BadRSV(rsv) => write!(f, "reserved byte must be zero, got {rsv:#04x}"),
+            Error::InvalidAtype(atype) => write!(f, "unknown address type {atype:#04x}"),
+            Error::InvalidDomainName(err) => write!(f, "invalid domain name: {err}"),
+            Error::ResolveDomainError(err) => write!(f, "failed to resolve domain: {err}"),
+            Error::BadHttpRequest(reason) => write!(f, "bad HTTP request: {reason}"),
+            Error::NotAllowed(addr) => write!(f, "connection to {addr} not allowed"),
+            Error::QuotaExceeded => f.write_str("traffic quota exceeded"),
+            Error::Terminated => f.write_str("terminated by the server"),
+            Error::Rejected(reply) => write!(f, "rejected with reply {reply:#04x}"),
+            Error::IO(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl Error {
     pub async fn write<W: UnpinAsyncWrite>(self, mut client: W) -> IOResult<()> {
         match self {
-            Error::BadVersion(_) | Error::NoAuthMethods | Error::UnacceptableMethods(_) => {
-                client.write_all(&[VER, NO_ACCEPTABLE_METHODS]).await
+            Error::IO(err) => Err(err),
+            err => {
+                let reply = err.reply().unwrap_or(GENERAL_FAILURE);
+                client.write_all(&[VER, reply]).await
             }
-            Error::BadCredential => client.write_all(&[VER, AUTH_ERROR]).await,
-            Error::BadRSV(_) | Error::InvalidAtype(_) | Error::InvalidDomainName(_) => {
-                client.write_all(&[VER, CONNECTION_REFUSED]).await
+        }
+    }
+
+    /// Answers a rejected credential with a failed RFC 1929 response, a hook rejecting the
+    /// authenticated client with a failed [`Reply`], and any other error of the sub-negotiation
+    /// as [`write`](Error::write) does.
+    pub async fn write_auth<W: UnpinAsyncWrite>(self, mut client: W) -> IOResult<()> {
+        match self {
+            Error::BadCredential => {
+                let response = AuthResponse { status: AUTH_ERROR };
+                write_message(&mut client, &response).await
             }
-            Error::BadCommand(_) => client.write_all(&[VER, UNSUPPORTED_COMMAND]).await,
-            Error::ResolveDomainError(_) => {
-                client.write_all(&[VER, TARGET_SERVER_UNREACHABLE]).await
+            err @ Error::Rejected(_) => err.write_reply(client).await,
+            err => err.write(client).await,
+        }
+    }
+
+    /// Answers a request with a failed [`Reply`], bound to the unspecified address.
+    pub async fn write_reply<W: UnpinAsyncWrite>(self, mut client: W) -> IOResult<()> {
+        match self {
+            Error::IO(err) => Err(err),
+            err => {
+                let reply = Reply {
+                    reply: err.reply().unwrap_or(GENERAL_FAILURE),
+                    addr: Addr::default(),
+                };
+                write_message(&mut client, &reply).await
+            }
+        }
+    }
+
+    /// The SOCKS5 reply code the error is sent as, which SOCKS4 and HTTP replies are also
+    /// translated from, or `None` for IO errors, which end the session without a reply.
+    pub fn reply(&self) -> Option<u8> {
+        let reply = match self {
+            Error::BadVersion(_) | Error::NoAuthMethods | Error::UnacceptableMethods(_) => {
+                NO_ACCEPTABLE_METHODS
             }
+            Error::BadCredential => AUTH_ERROR,
+            Error::BadRSV(_)
+            | Error::InvalidAtype(_)
+            | Error::InvalidDomainName(_)
+            | Error::BadHttpRequest(_) => CONNECTION_REFUSED,
+            Error::BadCommand(_) => UNSUPPORTED_COMMAND,
+            Error::NotAllowed(_) | Error::QuotaExceeded => CONNECTION_NOT_ALLOWED,
+            Error::Rejected(reply) => *reply,
+            Error::Terminated => GENERAL_FAILURE,
+            Error::ResolveDomainError(_) => TARGET_SERVER_UNREACHABLE,
+            Error::IO(_) => return None,
+        };
+        Some(reply)
+    }
+
+    /// SOCKS4 has a single rejection code, which is also sent when the upstream is unreachable.
+    pub async fn write_socks4<W: UnpinAsyncWrite>(self, mut client: W) -> IOResult<()> {
+        let reply = Socks4Reply {
+            reply: REQUEST_REJECTED,
+            addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+        write_message(&mut client, &reply).await?;
+        match self {
+            Error::IO(err) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Upstream failures answer `502`, and the connection is always closed after the response.
+    pub async fn write_http<W: UnpinAsyncWrite>(self, mut client: W) -> IOResult<()> {
+        let status = match self {
+            Error::BadCredential => PROXY_AUTHENTICATION_REQUIRED,
+            Error::NotAllowed(_)
+            | Error::QuotaExceeded
+            | Error::Rejected(CONNECTION_NOT_ALLOWED) => FORBIDDEN,
+            Error::Rejected(_) => BAD_GATEWAY,
+            Error::ResolveDomainError(_) | Error::IO(_) => BAD_GATEWAY,
+            Error::Terminated => SERVICE_UNAVAILABLE,
+            _ => BAD_REQUEST,
+        };
+        client.write_all(status).await?;
+        match self {
             Error::IO(err) => Err(err),
+            _ => Ok(()),
         }
     }
 }
@@ -65,8 +205,9 @@ mod tests {
 
     use crate::{
         constant::{
-            AUTH_ERROR, CONNECTION_REFUSED, NO_ACCEPTABLE_METHODS, TARGET_SERVER_UNREACHABLE,
-            UNSUPPORTED_COMMAND, VER,
+            AUTH_ERROR, CONNECTION_NOT_ALLOWED, CONNECTION_REFUSED, CREDENTIAL_VERSION, IPV4,
+            NO_ACCEPTABLE_METHODS, REQUEST_REJECTED, RSV, SOCKS4_REPLY_VER,
+            TARGET_SERVER_UNREACHABLE, UNSUPPORTED_COMMAND, VER,
         },
         error::Error,
     };
@@ -107,6 +248,24 @@ mod tests {
         assert_eq!(out, [VER, AUTH_ERROR]);
     }
 
+    #[tokio::test]
+    async fn bad_credential_response() {
+        let err = Error::BadCredential;
+        let mut out = vec![];
+        err.write_auth(&mut out).await.unwrap();
+
+        assert_eq!(out, [CREDENTIAL_VERSION, AUTH_ERROR]);
+    }
+
+    #[tokio::test]
+    async fn request_errors_reply_in_full() {
+        let err = Error::BadCommand(0x2);
+        let mut out = vec![];
+        err.write_reply(&mut out).await.unwrap();
+
+        assert_eq!(out, [VER, UNSUPPORTED_COMMAND, RSV, IPV4, 0, 0, 0, 0, 0, 0]);
+    }
+
     #[tokio::test]
     async fn bad_command_error() {
         let err = Error::BadCommand(0x2);
@@ -152,4 +311,68 @@ mod tests {
 
         assert_eq!(out, [VER, CONNECTION_REFUSED]);
     }
+
+    #[tokio::test]
+    async fn not_allowed_error() {
+        let err = Error::NotAllowed("10.0.0.1:80".parse().unwrap());
+        let mut out = vec![];
+        err.write(&mut out).await.unwrap();
+
+        assert_eq!(out, [VER, CONNECTION_NOT_ALLOWED]);
+    }
+
+    #[tokio::test]
+    async fn quota_exceeded_error() {
+        let err = Error::QuotaExceeded;
+        let mut out = vec![];
+        err.write(&mut out).await.unwrap();
+
+        assert_eq!(out, [VER, CONNECTION_NOT_ALLOWED]);
+    }
+
+    #[tokio::test]
+    async fn rejected_error() {
+        let err = Error::Rejected(TARGET_SERVER_UNREACHABLE);
+        let mut out = vec![];
+        err.write(&mut out).await.unwrap();
+
+        assert_eq!(out, [VER, TARGET_SERVER_UNREACHABLE]);
+    }
+
+    #[tokio::test]
+    async fn socks4_rejection() {
+        let err = Error::BadCommand(0x2);
+        let mut out = vec![];
+        err.write_socks4(&mut out).await.unwrap();
+
+        assert_eq!(out, [SOCKS4_REPLY_VER, REQUEST_REJECTED, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn http_proxy_authentication_required() {
+        let err = Error::BadCredential;
+        let mut out = vec![];
+        err.write_http(&mut out).await.unwrap();
+
+        assert!(out.starts_with(b"HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(out.ends_with(b"\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn http_forbidden() {
+        let err = Error::NotAllowed("10.0.0.1:80".parse().unwrap());
+        let mut out = vec![];
+        err.write_http(&mut out).await.unwrap();
+
+        assert!(out.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[tokio::test]
+    async fn http_bad_gateway() {
+        let err = Error::IO(std::io::ErrorKind::ConnectionRefused.into());
+        let mut out = vec![];
+        err.write_http(&mut out).await.unwrap_err();
+
+        assert!(out.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+    }
 }
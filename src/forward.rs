@@ -1,33 +1,362 @@
-use tokio::io::copy_bidirectional;
+use std::{
+    any::Any,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use crate::marker::Stream;
-use crate::Result;
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::{interval, timeout},
+};
+
+use crate::error::Error;
+use crate::marker::{Stream, UnpinAsyncRead, UnpinAsyncWrite};
+use crate::quota::Accounting;
+use crate::rewind::Rewind;
+use crate::session::Session;
+#[cfg(target_os = "linux")]
+use crate::splice;
+use crate::{IOResult, Result};
+
+/// How often the traffic of a running session is accounted and checked against the quota.
+const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(1);
+/// The buffer size of each direction unless configured, as `copy_bidirectional` has.
+const DEFAULT_BUFFER: usize = 8 * 1024;
+
+/// What a session does once one side has closed its stream, such as a client that sends its
+/// request and shuts down writing while it waits for the response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HalfClose {
+    /// Shuts down writing to the other side too, while the other direction goes on.
+    #[default]
+    Propagate,
+    /// Closes both sides.
+    Close,
+}
+
+/// How sessions relay once connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forwarding {
+    /// Bytes read from the client at once, which is also the pipe capacity when spliced.
+    pub send_buffer: usize,
+    /// Bytes read from the upstream at once.
+    pub receive_buffer: usize,
+    pub half_close: HalfClose,
+    /// How long the other direction may go on once one side has closed, which cleans up tunnels
+    /// left half open. Without it, a propagated half-close waits for the other side, and a close
+    /// happens at once.
+    pub linger: Option<Duration>,
+}
+
+impl Default for Forwarding {
+    fn default() -> Self {
+        Forwarding {
+            send_buffer: DEFAULT_BUFFER,
+            receive_buffer: DEFAULT_BUFFER,
+            half_close: HalfClose::default(),
+            linger: None,
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct Forward<U>(pub U);
+pub struct Forward<U>(pub Remote<U>);
+
+/// The stream a session relays to, either connected to the target directly or opened by a
+/// [`Dial`](crate::Dial).
+pub enum Remote<U> {
+    Direct(U),
+    Dialed(Box<dyn Stream>),
+}
 
-impl<U: Stream> Forward<U> {
-    pub async fn run<S: Stream>(&mut self, mut client: S) -> Result<()> {
-        copy_bidirectional(&mut client, &mut self.0).await?;
-        Ok(())
+impl<U: fmt::Debug> fmt::Debug for Remote<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Remote::Direct(upstream) => f.debug_tuple("Direct").field(upstream).finish(),
+            Remote::Dialed(_) => f.write_str("Dialed"),
+        }
+    }
+}
+
+impl<U: Stream> AsyncRead for Remote<U> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Remote::Direct(upstream) => Pin::new(upstream).poll_read(cx, buf),
+            Remote::Dialed(upstream) => Pin::new(upstream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<U: Stream> AsyncWrite for Remote<U> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Remote::Direct(upstream) => Pin::new(upstream).poll_write(cx, buf),
+            Remote::Dialed(upstream) => Pin::new(upstream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Remote::Direct(upstream) => Pin::new(upstream).poll_flush(cx),
+            Remote::Dialed(upstream) => Pin::new(upstream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Remote::Direct(upstream) => Pin::new(upstream).poll_shutdown(cx),
+            Remote::Dialed(upstream) => Pin::new(upstream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl<U: Stream + Any> Forward<U> {
+    pub async fn run<S: Stream + Any>(
+        &mut self,
+        client: &mut Rewind<S>,
+        session: &mut Session,
+    ) -> Result<()> {
+        let (config, tracker) = (session.config.clone(), session.tracker.clone());
+        let (sent, received) = (&tracker.sent, &tracker.received);
+        let relay = relay(client, &mut self.0, sent, received, &config.forwarding);
+        let result = match (&config.accounting, &session.user) {
+            (Some(accounting), Some(user)) => {
+                let result = metered(relay, sent, received, accounting, &user.name).await;
+                accounting.save_in_background();
+                result
+            }
+            _ => relay.await.map_err(Error::from),
+        };
+        (session.sent, session.received) = (sent.load(Relaxed), received.load(Relaxed));
+        result
+    }
+}
+
+/// Relays both ways, counting the bytes read from each end. Between two TCP sockets on Linux the
+/// bytes are spliced within the kernel, once the client has no rewound bytes left.
+async fn relay<S: Stream + Any, U: Stream + Any>(
+    client: &mut Rewind<S>,
+    upstream: &mut Remote<U>,
+    sent: &AtomicU64,
+    received: &AtomicU64,
+    forwarding: &Forwarding,
+) -> IOResult<()> {
+    let propagate = forwarding.half_close == HalfClose::Propagate;
+    #[cfg(target_os = "linux")]
+    {
+        let tcp = client
+            .drained()
+            .and_then(|it| (it as &mut dyn Any).downcast_mut::<TcpStream>());
+        let direct = match upstream {
+            Remote::Direct(upstream) => (upstream as &mut dyn Any).downcast_mut::<TcpStream>(),
+            Remote::Dialed(_) => None,
+        };
+        if let (Some(client), Some(upstream)) = (tcp, direct) {
+            let (size, reverse_size) = (forwarding.send_buffer, forwarding.receive_buffer);
+            let send = splice::pump(client, upstream, sent, size, propagate);
+            let receive = splice::pump(upstream, client, received, reverse_size, propagate);
+            return both_ways(send, receive, forwarding).await;
+        }
+    }
+    let (mut client_read, mut client_write) = split(Counted(client, sent));
+    let (mut upstream_read, mut upstream_write) = split(Counted(upstream, received));
+    let send = copy(
+        &mut client_read,
+        &mut upstream_write,
+        forwarding.send_buffer,
+        propagate,
+    );
+    let receive = copy(
+        &mut upstream_read,
+        &mut client_write,
+        forwarding.receive_buffer,
+        propagate,
+    );
+    both_ways(send, receive, forwarding).await
+}
+
+/// Runs both directions until one ends, then lets the other go on as the half-close asks for.
+async fn both_ways<F, R>(send: F, receive: R, forwarding: &Forwarding) -> IOResult<()>
+where
+    F: Future<Output = IOResult<()>> + Send,
+    R: Future<Output = IOResult<()>> + Send,
+{
+    tokio::pin!(send, receive);
+    let other: Pin<&mut (dyn Future<Output = IOResult<()>> + Send)> = tokio::select! {
+        result = &mut send => {
+            result?;
+            receive
+        }
+        result = &mut receive => {
+            result?;
+            send
+        }
+    };
+    match (forwarding.half_close, forwarding.linger) {
+        (_, Some(linger)) => timeout(linger, other).await.unwrap_or(Ok(())),
+        (HalfClose::Propagate, None) => other.await,
+        (HalfClose::Close, None) => Ok(()),
+    }
+}
+
+/// Copies one direction through a buffer of the size, shutting down writing at the end if
+/// asked to.
+async fn copy<R: UnpinAsyncRead, W: UnpinAsyncWrite>(
+    from: &mut R,
+    to: &mut W,
+    size: usize,
+    propagate: bool,
+) -> IOResult<()> {
+    let mut buf = vec![0; size.max(1)];
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        to.write_all(&buf[..n]).await?;
+        to.flush().await?;
+    }
+    if propagate {
+        to.shutdown().await?;
+    }
+    Ok(())
+}
+
+async fn metered<F: Future<Output = IOResult<()>>>(
+    relay: F,
+    sent: &AtomicU64,
+    received: &AtomicU64,
+    accounting: &Accounting,
+    name: &str,
+) -> Result<()> {
+    let mut accounted = (0, 0);
+    let mut account = || {
+        let now = (sent.load(Relaxed), received.load(Relaxed));
+        accounting.record(name, now.0 - accounted.0, now.1 - accounted.1);
+        accounted = now;
+    };
+    let mut ticks = interval(ACCOUNTING_INTERVAL);
+    tokio::pin!(relay);
+    let result = loop {
+        tokio::select! {
+            result = &mut relay => break result.map_err(Error::from),
+            _ = ticks.tick() => {
+                account();
+                if accounting.exceeded(name) {
+                    break Err(Error::QuotaExceeded);
+                }
+            }
+        }
+    };
+    account();
+    result
+}
+
+/// Counts the bytes read from the stream.
+struct Counted<'a, S>(S, &'a AtomicU64);
+
+impl<S: Stream> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.0).poll_read(cx, buf);
+        self.1
+            .fetch_add((buf.filled().len() - filled) as u64, Relaxed);
+        poll
+    }
+}
+
+impl<S: Stream> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::io::{duplex, AsyncWriteExt};
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+        time::timeout,
+    };
 
-    use crate::test::AsyncExactRead;
+    use crate::{
+        config::Config,
+        error::Error,
+        quota::{Accounting, Quota},
+        rewind::Rewind,
+        session::Session,
+        test::AsyncExactRead,
+        IOResult,
+    };
 
-    use super::Forward;
+    use super::{Forward, Forwarding, HalfClose, Remote};
+
+    /// Forwards between the ends of duplex streams, returning the client and upstream ends.
+    fn forward(forwarding: Forwarding) -> (DuplexStream, DuplexStream, JoinHandle<()>) {
+        let (client, client2) = duplex(1024);
+        let (upstream, upstream2) = duplex(1024);
+        let config = Arc::new(Config::default().forwarding(forwarding));
+        let session = tokio::spawn(async move {
+            let mut session = Session::new(config);
+            let result = Forward(Remote::Direct(upstream2))
+                .run(&mut Rewind::new(client2), &mut session)
+                .await;
+            result.unwrap();
+        });
+        (client, upstream, session)
+    }
+
+    async fn read_to_end<S: AsyncReadExt + Unpin>(stream: &mut S) -> IOResult<Vec<u8>> {
+        let mut buf = vec![];
+        timeout(Duration::from_secs(1), stream.read_to_end(&mut buf)).await??;
+        Ok(buf)
+    }
 
     #[tokio::test]
     async fn copy_bidirectional() {
         let (mut a, a2) = duplex(usize::MAX);
         let (mut b, b2) = duplex(usize::MAX);
-        tokio::spawn(async move {
-            let mut forward = Forward(a2);
-            forward.run(b2).await
+        let session = tokio::spawn(async move {
+            let mut forward = Forward(Remote::Direct(a2));
+            let mut session = Session::new(Default::default());
+            forward
+                .run(&mut Rewind::new(b2), &mut session)
+                .await
+                .map(|_| session)
         });
 
         a.write_all(&[1, 2]).await.unwrap();
@@ -35,5 +364,113 @@ mod tests {
 
         assert_eq!(a.read_exact_bytes().await.unwrap(), [3, 4]);
         assert_eq!(b.read_exact_bytes().await.unwrap(), [1, 2]);
+
+        a.write_all(&[5]).await.unwrap();
+        assert_eq!(b.read_exact_bytes().await.unwrap(), [5]);
+        drop((a, b));
+        let session = session.await.unwrap().unwrap();
+        assert_eq!((session.bytes_sent(), session.bytes_received()), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn close_sessions_over_quota() {
+        let accounting = Arc::new(Accounting::new().quota(Quota {
+            daily: Some(4),
+            monthly: None,
+        }));
+        let config = Config::default().accounting(accounting.clone());
+        let (mut a, a2) = duplex(usize::MAX);
+        let (mut b, b2) = duplex(usize::MAX);
+        let session = tokio::spawn(async move {
+            let mut session = Session::new(Arc::new(config));
+            session.user = Some("alice".into());
+            let result = Forward(Remote::Direct(a2))
+                .run(&mut Rewind::new(b2), &mut session)
+                .await;
+            (result, session)
+        });
+
+        b.write_all(&[1, 2, 3]).await.unwrap();
+        assert_eq!(a.read_exact_bytes().await.unwrap(), [1, 2, 3]);
+        a.write_all(&[4, 5]).await.unwrap();
+        assert_eq!(b.read_exact_bytes().await.unwrap(), [4, 5]);
+        let (result, session) = session.await.unwrap();
+
+        assert!(matches!(result, Err(Error::QuotaExceeded)));
+        assert_eq!((session.bytes_sent(), session.bytes_received()), (3, 2));
+        assert_eq!(accounting.usage("alice").unwrap().today, 5);
+        assert!(session.permit("127.0.0.1:80".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn propagate_half_close() {
+        let (mut client, mut upstream, session) = forward(Forwarding {
+            send_buffer: 1,
+            ..Forwarding::default()
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut upstream).await.unwrap(), b"request");
+        upstream.write_all(b"response").await.unwrap();
+        drop(upstream);
+
+        assert_eq!(read_to_end(&mut client).await.unwrap(), b"response");
+        session.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_both_sides_at_once() {
+        let (mut client, mut upstream, session) = forward(Forwarding {
+            half_close: HalfClose::Close,
+            ..Forwarding::default()
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        session.await.unwrap();
+
+        assert_eq!(read_to_end(&mut upstream).await.unwrap(), b"request");
+        assert!(upstream.write_all(b"response").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn linger_after_half_close() {
+        let (mut client, mut upstream, session) = forward(Forwarding {
+            linger: Some(Duration::from_millis(50)),
+            ..Forwarding::default()
+        });
+
+        client.shutdown().await.unwrap();
+        upstream.write_all(b"late").await.unwrap();
+        session.await.unwrap();
+
+        assert_eq!(read_to_end(&mut client).await.unwrap(), b"late");
+        assert!(read_to_end(&mut upstream).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn close_spliced_sessions_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, client2) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (upstream, upstream2) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (mut client, mut upstream) = (client.unwrap(), upstream.unwrap());
+        let forwarding = Forwarding {
+            half_close: HalfClose::Close,
+            ..Forwarding::default()
+        };
+        let mut session = Session::new(Arc::new(Config::default().forwarding(forwarding)));
+        let mut forward = Forward(Remote::Direct(upstream2.unwrap().0));
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut client2 = Rewind::new(client2.unwrap().0);
+        forward.run(&mut client2, &mut session).await.unwrap();
+        drop((forward, client2));
+
+        assert_eq!(read_to_end(&mut upstream).await.unwrap(), b"request");
+        assert!(read_to_end(&mut client).await.unwrap().is_empty());
+        assert_eq!(session.bytes_sent(), 7);
     }
 }
@@ -0,0 +1,82 @@
+//! Entry points for the fuzz targets in `fuzz/`, only built by `cargo fuzz`.
+use std::{
+    future::{ready, Ready},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{duplex, sink, split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::ToSocketAddrs,
+};
+
+use crate::{
+    codec::MAX_MESSAGE_LEN, config::Config, error::Error, resolver::Resolve, rewind::Rewind,
+    BoxFuture, IOResult, Socks5, Upstream,
+};
+
+/// Runs the stages of one session over the input, sent by a client that closes once it's
+/// sent and reads whatever it's answered. Every domain resolves to a documentation address, and
+/// every upstream accepts the connection, ignores what it's sent and sends nothing.
+pub async fn session(config: Config, input: &[u8]) -> Result<(), Error> {
+    let config = Arc::new(config.resolver(Documentation));
+    let (client, server) = duplex(MAX_MESSAGE_LEN);
+    let (mut answers, mut requests) = split(client);
+    let input = input.to_vec();
+    tokio::spawn(async move {
+        let _ = requests.write_all(&input).await;
+        let _ = requests.shutdown().await;
+    });
+    tokio::spawn(async move { tokio::io::copy(&mut answers, &mut sink()).await });
+    let mut socks5 = Socks5::<Sink>::with_config(config);
+    socks5.try_process(&mut Rewind::new(server)).await
+}
+
+struct Documentation;
+
+impl Resolve for Documentation {
+    fn resolve<'a>(&'a self, _: &'a str, port: u16) -> BoxFuture<'a, Result<SocketAddr, Error>> {
+        Box::pin(async move { Ok(SocketAddr::from(([192, 0, 2, 1], port))) })
+    }
+}
+
+struct Sink;
+
+impl<'a> Upstream<'a> for Sink {
+    type Output = Ready<IOResult<Self>>;
+
+    fn connect<S: ToSocketAddrs + Send + 'a>(_: S) -> Self::Output {
+        ready(Ok(Sink))
+    }
+}
+
+impl AsyncRead for Sink {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        _: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Sink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,230 @@
+use std::{fmt, net::SocketAddr};
+
+use crate::{codec::Addr, error::Error, session::Session, BoxFuture, Result};
+
+/// Callbacks for the events of a session, for applications embedding the server.
+///
+/// Every callback but [`on_close`](Self::on_close) may end the session early by failing, usually
+/// with [`Error::Rejected`] carrying the SOCKS5 reply code to send. The default implementations
+/// do nothing.
+pub trait Hooks: Send + Sync {
+    /// Once the protocol of the client is known, before any handshake.
+    fn on_accept<'a>(&'a self, _session: &'a Session) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Once authentication is done, with [`Session::user`] set if the client authenticated.
+    fn after_auth<'a>(&'a self, _session: &'a Session) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Before the target is resolved and checked against the access rules. A rewritten target is
+    /// resolved and checked like any other.
+    fn before_connect<'a>(
+        &'a self,
+        _session: &'a Session,
+        _target: &'a mut Addr,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Once the upstream connection is established, before the client is told so.
+    fn after_connect<'a>(
+        &'a self,
+        _session: &'a Session,
+        _upstream: SocketAddr,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// When the session ends, before any error reply is sent to the client.
+    fn on_close<'a>(
+        &'a self,
+        _session: &'a Session,
+        _error: Option<&'a Error>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+impl Hooks for () {}
+
+impl fmt::Debug for dyn Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hooks")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        config::Config,
+        constant::{
+            CONNECT, CONNECTION_NOT_ALLOWED, CREDENTIAL_AUTH, CREDENTIAL_VERSION, DOMAIN_NAME,
+            IPV4, OK, RSV, VER,
+        },
+        credential::Credential,
+        test::AsyncExactRead,
+        Socks5,
+    };
+
+    /// Records every event and sends sessions for `upstream.test` to `upstream`.
+    #[derive(Default)]
+    struct Recorder {
+        upstream: Option<SocketAddr>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl Hooks for Recorder {
+        fn on_accept<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>> {
+            self.record(format!("accept {:?}", session.user()));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn after_auth<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>> {
+            self.record(format!("auth {:?}", session.user().map(|it| &it.name)));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn before_connect<'a>(
+            &'a self,
+            _: &'a Session,
+            target: &'a mut Addr,
+        ) -> BoxFuture<'a, Result<()>> {
+            self.record(format!("before {target}"));
+            Box::pin(async move {
+                match (self.upstream, &target) {
+                    (Some(upstream), Addr::Domain(domain, _)) if domain == "upstream.test" => {
+                        *target = Addr::Ip(upstream);
+                        Ok(())
+                    }
+                    _ => Err(Error::Rejected(CONNECTION_NOT_ALLOWED)),
+                }
+            })
+        }
+
+        fn after_connect<'a>(
+            &'a self,
+            _: &'a Session,
+            upstream: SocketAddr,
+        ) -> BoxFuture<'a, Result<()>> {
+            self.record(format!("after {}", upstream == self.upstream.unwrap()));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn on_close<'a>(
+            &'a self,
+            session: &'a Session,
+            error: Option<&'a Error>,
+        ) -> BoxFuture<'a, ()> {
+            self.record(format!(
+                "close {} {} {:?}",
+                session.bytes_sent(),
+                session.bytes_received(),
+                error.map(|err| err.to_string())
+            ));
+            Box::pin(async {})
+        }
+    }
+
+    async fn connect_request(client: &mut tokio::io::DuplexStream, domain: &str) {
+        let mut request = vec![VER, CONNECT, RSV, DOMAIN_NAME, domain.len() as u8];
+        request.extend(domain.as_bytes());
+        request.extend([0, 80]);
+        client.write_all(&request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn hooks_see_every_event() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let recorder = Recorder {
+            upstream: Some(listener.local_addr().unwrap()),
+            ..Default::default()
+        };
+        let events = recorder.events.clone();
+        let config = Config::new(Some(Credential::new("root", "pass"))).hooks(recorder);
+        let (mut client, server) = duplex(usize::MAX);
+        let session = tokio::spawn(Socks5::<TcpStream>::with_config(config.into()).start(server));
+
+        client.write_all(&[VER, 1, CREDENTIAL_AUTH]).await.unwrap();
+        client.write_all(b"\x01\x04root\x04pass").await.unwrap();
+        connect_request(&mut client, "upstream.test").await;
+        let (mut upstream, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            client.read_exact_bytes::<6>().await.unwrap(),
+            [VER, CREDENTIAL_AUTH, CREDENTIAL_VERSION, OK, VER, OK]
+        );
+        client.read_exact_bytes::<8>().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(upstream.read_exact_bytes().await.unwrap(), *b"ping");
+        upstream.write_all(b"pong!").await.unwrap();
+        drop(upstream);
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+
+        assert_eq!(response, b"pong!");
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "accept None",
+                "auth Some(\"root\")",
+                "before upstream.test:80",
+                "after true",
+                "close 4 5 None",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_short_circuit_with_reply() {
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let config = Config::default().hooks(recorder);
+        let (mut client, server) = duplex(usize::MAX);
+        let session = tokio::spawn(Socks5::<TcpStream>::with_config(config.into()).start(server));
+
+        client.write_all(&[VER, 1, 0]).await.unwrap();
+        connect_request(&mut client, "blocked.test").await;
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        session.await.unwrap().unwrap();
+
+        assert_eq!(
+            response,
+            [
+                VER,
+                0,
+                VER,
+                CONNECTION_NOT_ALLOWED,
+                RSV,
+                IPV4,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+        assert_eq!(
+            events.lock().unwrap().last().unwrap(),
+            "close 0 0 Some(\"rejected with reply 0x02\")"
+        );
+    }
+}
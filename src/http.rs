@@ -0,0 +1,430 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    codec::Addr,
+    connect::connect_upstream,
+    credential::PasswordVerifier,
+    error::Error::*,
+    forward::{Forward, Remote},
+    marker::Stream,
+    rewind::Rewind,
+    session::Session,
+    IOResult, Result, Stage, Upstream,
+};
+
+const MAX_HEAD_LEN: usize = 8 * 1024;
+const HTTP_PORT: u16 = 80;
+const CONNECTION_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+/// Hop-by-hop headers that are meant for the proxy and never forwarded upstream.
+const HOP_BY_HOP_HEADERS: [&str; 4] = [
+    "proxy-authorization",
+    "proxy-connection",
+    "connection",
+    "keep-alive",
+];
+
+/// Serves an HTTP proxy request: a `CONNECT host:port` tunnel, or a plain request with an
+/// absolute URI which is forwarded in origin form. Plain requests are sent upstream with
+/// `Connection: close`, so each client connection carries one request.
+#[derive(Debug)]
+pub struct Http(pub Option<Arc<dyn PasswordVerifier>>);
+
+impl Http {
+    pub async fn run<'a, S: Stream, U>(
+        &mut self,
+        client: &mut Rewind<S>,
+        session: &mut Session,
+    ) -> Result<Stage<U>>
+    where
+        U: Upstream<'a> + Stream,
+        U::Output: Future<Output = IOResult<U>>,
+    {
+        let head = read_head(client).await?;
+        let request = RequestHead::parse(&head)?;
+        session.command = Some(request.method.into());
+        if let Some(verifier) = &self.0 {
+            let Some((username, password)) = request.basic_authorization() else {
+                return Err(BadCredential);
+            };
+            let Some(user) = verifier.verify(&username, &password).await? else {
+                return Err(BadCredential);
+            };
+            session.user = Some(user);
+        }
+        session.hooks().after_auth(session).await?;
+
+        if request.method == "CONNECT" {
+            let addr = parse_authority(request.target, None)?;
+            let upstream = connect_upstream(session, addr).await?;
+            client.write_all(CONNECTION_ESTABLISHED).await?;
+            return Ok(Stage::Forward(Forward(upstream)));
+        }
+
+        let (authority, path) = split_absolute_uri(request.target)?;
+        let addr = parse_authority(authority, Some(HTTP_PORT))?;
+        let mut upstream: Remote<U> = connect_upstream(session, addr).await?;
+        upstream
+            .write_all(request.to_origin_form(authority, path).as_bytes())
+            .await?;
+        Ok(Stage::Forward(Forward(upstream)))
+    }
+}
+
+/// Reads up to the blank line ending the request head, rewinding whatever follows it.
+async fn read_head<S: Stream>(client: &mut Rewind<S>) -> Result<String> {
+    let mut buf = vec![];
+    let mut chunk = [0; 1024];
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf[searched..].windows(4).position(|it| it == b"\r\n\r\n") {
+            let end = searched + end + 4;
+            client.rewind(&buf[end..]);
+            buf.truncate(end);
+            return String::from_utf8(buf).map_err(|_| BadHttpRequest("request head isn't UTF-8"));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(BadHttpRequest("request head too large"));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RequestHead<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> RequestHead<'a> {
+    fn parse(head: &'a str) -> Result<Self> {
+        let mut lines = head.split("\r\n").take_while(|line| !line.is_empty());
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version), None) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(BadHttpRequest("malformed request line"));
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(BadHttpRequest("unsupported HTTP version"));
+        }
+        let headers = lines
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(name, value)| (name.trim(), value.trim()))
+                    .ok_or(BadHttpRequest("malformed header"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(RequestHead {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(it, _)| it.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    fn basic_authorization(&self) -> Option<(String, String)> {
+        let (scheme, token) = self.header("proxy-authorization")?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(STANDARD.decode(token.trim()).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_owned(), password.to_owned()))
+    }
+
+    /// Whether the header only concerns the connection to the proxy, as the hop-by-hop headers
+    /// and those the `Connection` header names do.
+    fn hop_by_hop(&self, name: &str) -> bool {
+        HOP_BY_HOP_HEADERS
+            .iter()
+            .any(|it| it.eq_ignore_ascii_case(name))
+            || self
+                .headers
+                .iter()
+                .filter(|(it, _)| it.eq_ignore_ascii_case("connection"))
+                .flat_map(|(_, value)| value.split(','))
+                .any(|it| it.trim().eq_ignore_ascii_case(name))
+    }
+
+    fn to_origin_form(&self, authority: &str, path: &str) -> String {
+        let slash = if path.starts_with('/') { "" } else { "/" };
+        let mut head = format!("{} {slash}{path} {}\r\n", self.method, self.version);
+        if self.header("host").is_none() || self.hop_by_hop("host") {
+            head.push_str(&format!("Host: {authority}\r\n"));
+        }
+        for (name, value) in &self.headers {
+            if !self.hop_by_hop(name) {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head
+    }
+}
+
+/// Splits an absolute URI into its authority and the rest, which may lack the leading `/`.
+fn split_absolute_uri(target: &str) -> Result<(&str, &str)> {
+    let rest = match target.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &target[7..],
+        _ => return Err(BadHttpRequest("only absolute http:// URIs can be proxied")),
+    };
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    // userinfo is deprecated in http URIs and never sent upstream.
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, it)| it);
+    Ok((authority, path))
+}
+
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Result<Addr> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port)),
+        _ => (authority, None),
+    };
+    let port = match (port, default_port) {
+        (Some(port), _) => port.parse().map_err(|_| BadHttpRequest("malformed port"))?,
+        (None, Some(port)) => port,
+        (None, None) => return Err(BadHttpRequest("missing port")),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|it| it.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() || host.len() > 255 {
+        return Err(BadHttpRequest("malformed host"));
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(Addr::Ip(SocketAddr::new(ip, port))),
+        Err(_) => Ok(Addr::Domain(host.to_owned(), port)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        access::AccessRules, config::Config, credential::Credential, test::AsyncExactRead,
+    };
+
+    fn session() -> Session {
+        Session::new(Default::default())
+    }
+
+    #[tokio::test]
+    async fn connect_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(None);
+        client
+            .write_all(format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n\r\nping").as_bytes())
+            .await
+            .unwrap();
+
+        let forward = http
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap();
+        assert!(matches!(forward,
+                Stage::Forward(Forward(Remote::Direct(upstream))) if upstream.peer_addr().unwrap() == addr));
+
+        let response = client
+            .read_exact_bytes::<{ CONNECTION_ESTABLISHED.len() }>()
+            .await
+            .unwrap();
+        assert_eq!(response, CONNECTION_ESTABLISHED);
+        assert_eq!(server.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+
+    #[tokio::test]
+    async fn forward_absolute_uri_in_origin_form() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(Some(Arc::new(Credential::new("root", "pass"))));
+        client
+            .write_all(
+                format!(
+                    "GET http://{addr}/index.html?q=1 HTTP/1.1\r\n\
+                     Host: {addr}\r\n\
+                     Proxy-Authorization: basic cm9vdDpwYXNz\r\n\
+                     Proxy-Connection: keep-alive\r\n\
+                     Connection: Upgrade, x-trace\r\n\
+                     Upgrade: websocket\r\n\
+                     X-Trace: 1\r\n\
+                     Accept: */*\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut session = session();
+        let forward = http
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await
+            .unwrap();
+        assert!(matches!(forward, Stage::Forward(_)));
+        assert_eq!(session.user, Some("root".into()));
+
+        let (mut upstream, _) = listener.accept().await.unwrap();
+        drop(forward);
+        let mut request = String::new();
+        upstream.read_to_string(&mut request).await.unwrap();
+        assert_eq!(
+            request,
+            format!(
+                "GET /index.html?q=1 HTTP/1.1\r\nHost: {addr}\r\nAccept: */*\r\n\
+                 Connection: close\r\n\r\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_without_proxy_authorization() {
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(Some(Arc::new(Credential::new("root", "pass"))));
+        client
+            .write_all(b"CONNECT localhost:80 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let err = http
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BadCredential));
+    }
+
+    #[tokio::test]
+    async fn fails_with_bad_proxy_authorization() {
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(Some(Arc::new(Credential::new("root", "pass"))));
+        client
+            .write_all(
+                b"CONNECT localhost:80 HTTP/1.1\r\nProxy-Authorization: Basic cm9vdDpiYWQ=\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let err = http
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BadCredential));
+    }
+
+    #[tokio::test]
+    async fn fails_with_denied_destination() {
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(None);
+        let config =
+            Config::default().access(AccessRules::default().deny("127.0.0.0/8".parse().unwrap()));
+        client
+            .write_all(b"CONNECT 127.0.0.1:80 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let err = http
+            .run::<_, TcpStream>(&mut server, &mut Session::new(config.into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NotAllowed(_)));
+    }
+
+    #[tokio::test]
+    async fn fails_with_origin_form_request() {
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(None);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let err = http
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BadHttpRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn fails_with_too_large_head() {
+        let (mut client, server) = duplex(usize::MAX);
+        let mut server = Rewind::new(server);
+        let mut http = Http(None);
+        client
+            .write_all(b"GET http://localhost/ HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        client.write_all(&[b'a'; MAX_HEAD_LEN]).await.unwrap();
+
+        let err = http
+            .run::<_, TcpStream>(&mut server, &mut session())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BadHttpRequest(_)));
+    }
+
+    #[test]
+    fn parse_authorities() {
+        assert_eq!(
+            parse_authority("example.com:443", None).unwrap(),
+            Addr::Domain("example.com".into(), 443)
+        );
+        assert_eq!(
+            parse_authority("[::1]", Some(80)).unwrap(),
+            Addr::Ip("[::1]:80".parse().unwrap())
+        );
+        assert_eq!(
+            parse_authority("[::1]:8080", None).unwrap(),
+            Addr::Ip("[::1]:8080".parse().unwrap())
+        );
+        assert!(parse_authority("example.com", None).is_err());
+        assert!(parse_authority("example.com:http", None).is_err());
+    }
+
+    #[test]
+    fn split_absolute_uris() {
+        assert_eq!(
+            split_absolute_uri("http://user@host:8080/a?b").unwrap(),
+            ("host:8080", "/a?b")
+        );
+        assert_eq!(split_absolute_uri("HTTP://host").unwrap(), ("host", ""));
+        assert_eq!(split_absolute_uri("http://host?q").unwrap(), ("host", "?q"));
+        assert!(split_absolute_uri("https://host/").is_err());
+    }
+}
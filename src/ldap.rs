@@ -0,0 +1,363 @@
+//! Password verification by a simple bind against an LDAP directory.
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::Connector;
+use crate::{
+    auth::User,
+    credential::PasswordVerifier,
+    marker::{Stream, UnpinAsyncRead},
+    BoxFuture, IOResult, Result,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Responses are tiny, anything bigger is a broken or hostile server.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0A;
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SIMPLE_AUTH: u8 = 0x80;
+const LDAP_VERSION: u8 = 3;
+const SUCCESS: u8 = 0;
+const INVALID_CREDENTIALS: u8 = 49;
+
+/// Binds to the directory as the DN built from a template, where `{username}` stands for the
+/// escaped username, such as `uid={username},ou=people,dc=example,dc=com`. The user is accepted
+/// when the bind succeeds and gets the DN as its `dn` attribute.
+#[derive(Debug, Clone)]
+pub struct Ldap {
+    addr: String,
+    bind_dn: String,
+    timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<Connector>,
+}
+
+impl Ldap {
+    /// `addr` is the `host:port` of the directory, usually on port 389.
+    pub fn new<A, D>(addr: A, bind_dn: D) -> Self
+    where
+        A: Into<String>,
+        D: Into<String>,
+    {
+        Ldap {
+            addr: addr.into(),
+            bind_dn: bind_dn.into(),
+            timeout: DEFAULT_TIMEOUT,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Binds over LDAPS, usually on port 636, verifying the certificate of the directory for the
+    /// host of its address against the PEM encoded CA bundle, so that passwords never cross the
+    /// network in plaintext.
+    #[cfg(feature = "tls")]
+    pub fn tls<P: AsRef<Path>>(mut self, ca: P) -> IOResult<Self> {
+        self.tls = Some(Connector::for_addr(ca, &self.addr)?);
+        Ok(self)
+    }
+
+    /// How long a bind may take before it fails, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn connect(&self) -> IOResult<Box<dyn Stream>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return Ok(Box::new(tls.connect(stream).await?));
+        }
+        Ok(Box::new(stream))
+    }
+
+    async fn bind(&self, dn: &str, password: &str) -> IOResult<u8> {
+        let mut server = self.connect().await?;
+        let request = tlv(
+            BIND_REQUEST,
+            &[
+                tlv(INTEGER, &[LDAP_VERSION]),
+                tlv(OCTET_STRING, dn.as_bytes()),
+                tlv(SIMPLE_AUTH, password.as_bytes()),
+            ]
+            .concat(),
+        );
+        server.write_all(&message(1, &request)).await?;
+        let response = read_tlv(&mut server, SEQUENCE).await?;
+        let code =
+            parse_bind_response(&response).ok_or_else(|| invalid_data("bad bind response"))?;
+        // the server closes the connection on unbind, a failure changes nothing.
+        let _ = server
+            .write_all(&message(2, &tlv(UNBIND_REQUEST, &[])))
+            .await;
+        Ok(code)
+    }
+}
+
+impl PasswordVerifier for Ldap {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move {
+            // a bind without a password is an anonymous bind, which most servers accept.
+            if username.is_empty() || password.is_empty() {
+                return Ok(None);
+            }
+            let dn = self.bind_dn.replace("{username}", &escape(username));
+            let code = timeout(self.timeout, self.bind(&dn, password))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            match code {
+                SUCCESS => {
+                    let mut user = User::new(username);
+                    user.attributes.insert("dn".into(), dn);
+                    Ok(Some(user))
+                }
+                INVALID_CREDENTIALS => Ok(None),
+                code => {
+                    Err(io::Error::other(format!("LDAP bind failed with result {code}")).into())
+                }
+            }
+        })
+    }
+}
+
+/// Escapes a DN attribute value as RFC 4514 requires, so a username can't inject extra RDNs.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => escaped.push('\\'),
+            '#' | ' ' if i == 0 => escaped.push('\\'),
+            ' ' if i == last => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn message(id: u8, op: &[u8]) -> Vec<u8> {
+    tlv(SEQUENCE, &[tlv(INTEGER, &[id]), op.to_vec()].concat())
+}
+
+/// BER encodes one element with a definite length.
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match value.len() {
+        len @ 0..=0x7F => out.push(len as u8),
+        len => {
+            let bytes = (len as u32).to_be_bytes();
+            let skip = bytes.iter().take_while(|&&it| it == 0).count();
+            out.push(0x80 | (4 - skip) as u8);
+            out.extend(&bytes[skip..]);
+        }
+    }
+    out.extend(value);
+    out
+}
+
+async fn read_tlv<R: UnpinAsyncRead>(server: &mut R, tag: u8) -> IOResult<Vec<u8>> {
+    if server.read_u8().await? != tag {
+        return Err(invalid_data("unexpected tag"));
+    }
+    let len = match server.read_u8().await? {
+        len @ 0..=0x7F => len as usize,
+        n @ 0x81..=0x84 => {
+            let mut bytes = [0; 4];
+            let n = (n & 0x7F) as usize;
+            server.read_exact(&mut bytes[4 - n..]).await?;
+            u32::from_be_bytes(bytes) as usize
+        }
+        _ => return Err(invalid_data("unsupported length")),
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid_data("message too long"));
+    }
+    let mut value = vec![0; len];
+    server.read_exact(&mut value).await?;
+    Ok(value)
+}
+
+/// Splits the element of the expected tag off the front of `buf`, returning its value and the
+/// rest.
+fn split_tlv(buf: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, buf) = buf.split_first()?;
+    let (&len, buf) = buf.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (len, buf) = match len {
+        0..=0x7F => (len as usize, buf),
+        0x81..=0x84 => {
+            let n = (len & 0x7F) as usize;
+            let (bytes, buf) = buf.split_at_checked(n)?;
+            let len = bytes.iter().fold(0, |len, &it| len << 8 | it as usize);
+            (len, buf)
+        }
+        _ => return None,
+    };
+    buf.split_at_checked(len)
+}
+
+/// Returns the result code of a `BindResponse` inside an `LDAPMessage`.
+fn parse_bind_response(message: &[u8]) -> Option<u8> {
+    let (_, rest) = split_tlv(message, INTEGER)?;
+    let (response, _) = split_tlv(rest, BIND_RESPONSE)?;
+    match split_tlv(response, ENUMERATED)? {
+        ([code], _) => Some(*code),
+        _ => None,
+    }
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("LDAP: {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    /// A directory holding `uid=alice,dc=example` with password `secret`, answering `busy` for
+    /// any other DN.
+    async fn directory() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                answer(client).await;
+            }
+        });
+        addr
+    }
+
+    async fn answer<S: Stream>(mut client: S) {
+        let request = read_tlv(&mut client, SEQUENCE).await.unwrap();
+        let (_, rest) = split_tlv(&request, INTEGER).unwrap();
+        let (bind, _) = split_tlv(rest, BIND_REQUEST).unwrap();
+        let (_, rest) = split_tlv(bind, INTEGER).unwrap();
+        let (dn, rest) = split_tlv(rest, OCTET_STRING).unwrap();
+        let (password, _) = split_tlv(rest, SIMPLE_AUTH).unwrap();
+        let code = match (dn, password) {
+            (b"uid=alice,dc=example", b"secret") => SUCCESS,
+            (b"uid=alice,dc=example", _) => INVALID_CREDENTIALS,
+            _ => 51,
+        };
+        let response = [
+            tlv(ENUMERATED, &[code]),
+            tlv(OCTET_STRING, b""),
+            tlv(OCTET_STRING, b""),
+        ]
+        .concat();
+        let response = message(1, &tlv(BIND_RESPONSE, &response));
+        client.write_all(&response).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_as_user() {
+        let ldap = Ldap::new(directory().await, "uid={username},dc=example");
+
+        let user = ldap.verify("alice", "secret").await.unwrap().unwrap();
+
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.attributes["dn"], "uid=alice,dc=example");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_credentials() {
+        let ldap = Ldap::new(directory().await, "uid={username},dc=example");
+
+        assert_eq!(ldap.verify("alice", "bad").await.unwrap(), None);
+        assert_eq!(ldap.verify("alice", "").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn fails_with_other_results() {
+        let ldap = Ldap::new(directory().await, "uid={username},dc=example");
+
+        assert!(ldap.verify("bob", "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_with_unresponsive_directory() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let ldap = Ldap::new(addr, "uid={username}").timeout(Duration::from_millis(50));
+
+        assert!(ldap.verify("alice", "secret").await.is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn bind_over_ldaps() {
+        use crate::tls::TlsConfig;
+
+        const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+        let acceptor = TlsConfig::new(
+            format!("{FIXTURES}/server.pem"),
+            format!("{FIXTURES}/server.key"),
+        )
+        .acceptor()
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            answer(acceptor.accept(client).await.unwrap()).await;
+        });
+        let ldap = Ldap::new(format!("localhost:{port}"), "uid={username},dc=example")
+            .tls(format!("{FIXTURES}/ca.pem"))
+            .unwrap();
+
+        let user = ldap.verify("alice", "secret").await.unwrap().unwrap();
+
+        assert_eq!(user.attributes["dn"], "uid=alice,dc=example");
+    }
+
+    #[test]
+    fn escape_dn_values() {
+        assert_eq!(escape("alice"), "alice");
+        assert_eq!(escape("a,ou=admins"), "a\\,ou\\=admins");
+        assert_eq!(escape("#a b "), "\\#a b\\ ");
+        assert_eq!(escape("a\0"), "a\\00");
+    }
+
+    #[test]
+    fn long_form_lengths() {
+        let value = vec![7; 300];
+        let encoded = tlv(OCTET_STRING, &value);
+
+        assert_eq!(encoded[..4], [OCTET_STRING, 0x82, 0x01, 0x2C]);
+        assert_eq!(
+            split_tlv(&encoded, OCTET_STRING),
+            Some((&value[..], &[][..]))
+        );
+    }
+}
@@ -1,76 +1,283 @@
-#[macro_use]
-mod extract;
+mod access;
+mod access_log;
+#[cfg(feature = "admin")]
+mod admin;
+mod auth;
+pub mod codec;
+mod config;
 mod connect;
-mod constant;
+pub mod constant;
 mod credential;
+mod detect;
 mod error;
 mod forward;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzz;
+mod hooks;
+mod http;
+#[cfg(feature = "ldap")]
+mod ldap;
 mod marker;
+mod mux;
 mod negotiation;
+#[cfg(feature = "obfs")]
+mod obfs;
+mod quota;
+mod registry;
+mod resolver;
+mod rewind;
+mod server;
+mod session;
+mod socks4;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stats;
+#[cfg(unix)]
+pub mod systemd;
 #[cfg(test)]
 mod test;
+#[cfg(feature = "tls")]
+mod tls;
+mod transparent;
+mod tunnel;
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+mod upgrade;
+#[cfg(feature = "webhook")]
+mod webhook;
 
 use std::{
+    io,
+    net::SocketAddr,
     ops::ControlFlow::{self, *},
     pin::Pin,
+    sync::Arc,
 };
 
+pub use access::{AccessRules, InvalidNetwork, Network};
+pub use access_log::{AccessLog, LogFormat};
+#[cfg(feature = "admin")]
+pub use admin::Admin;
+use auth::Authentication;
+pub use auth::{AuthMethod, AuthMethods, NoAuth, User};
+use codec::{Decode, Encode, EncodeError, ParseError, MAX_MESSAGE_LEN};
+pub use config::Config;
 use connect::Connect;
+use constant::{GENERAL_FAILURE, OK};
 use core::future::Future;
-pub use credential::Credential;
-use error::Error;
+pub use credential::{Cached, Credential, PasswordAuth, PasswordVerifier, Users};
+use detect::Detect;
+pub use error::Error;
 use forward::Forward;
+pub use forward::{Forwarding, HalfClose};
+pub use hooks::Hooks;
+use http::Http;
+#[cfg(feature = "ldap")]
+pub use ldap::Ldap;
 pub use marker::Stream;
-use marker::UnpinAsyncRead;
+use marker::{UnpinAsyncRead, UnpinAsyncWrite};
+pub use mux::{Multiplex, MuxListener, MuxStream};
 use negotiation::Negotiation;
+#[cfg(feature = "obfs")]
+pub use obfs::{serve_obfs, serve_obfs_local, ObfsKey, ObfsListener, ObfsStream};
+pub use quota::{Accounting, Quota, Usage};
+pub use registry::SessionInfo;
+pub use resolver::{Resolve, SystemResolver};
+use rewind::Rewind;
+pub use server::{Accept, Control, Incoming, ListenAddr, Server, ServerBuilder, ServerHandle};
+pub use session::Session;
+use socks4::Socks4;
+pub use stats::Stats;
+#[cfg(feature = "tls")]
+pub use tls::{serve_tls, TlsConfig, TlsListener};
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream, ToSocketAddrs},
+    time::{timeout_at, Instant},
 };
+use transparent::Transparent;
+#[cfg(target_os = "linux")]
+pub use transparent::TransparentListener;
+pub use tunnel::{Dial, Tunnel};
+#[cfg(unix)]
+pub use unix::UnixListener;
+#[cfg(feature = "webhook")]
+pub use webhook::Webhook;
 
 type Result<T> = std::result::Result<T, Error>;
 type IOResult<T> = std::io::Result<T>;
-type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
+/// Serves on the sockets handed over by the process upgraded from or by systemd, or else on the
+/// port of the loopback interface.
 pub async fn run(port: u16, credential: Option<Credential>) -> IOResult<()> {
-    let server = TcpListener::bind(format!("127.0.0.1:{port}")).await?;
-    loop {
-        tokio::spawn(Socks5::<TcpStream>::new(credential.clone()).start(server.accept().await?.0));
+    let mut server = listen(port, credential).await?;
+    #[cfg(unix)]
+    return systemd::supervise(&mut server).await;
+    #[cfg(not(unix))]
+    server.join().await
+}
+
+/// Starts serving on the same sockets as [`run`] without waiting for the server, which accepts
+/// clients once this returns. Port `0` binds an ephemeral port, which the handle's
+/// [`local_addrs`](ServerHandle::local_addrs) report.
+pub async fn listen(port: u16, credential: Option<Credential>) -> IOResult<ServerHandle> {
+    let mut builder = Server::builder().config(Config::new(credential));
+    #[cfg(unix)]
+    {
+        builder = builder.upgraded_listeners()?;
+        if !builder.has_listeners() {
+            builder = builder.systemd_listeners()?;
+        }
+    }
+    if !builder.has_listeners() {
+        builder = builder.listener(TcpListener::bind(format!("127.0.0.1:{port}")).await?);
+    }
+    builder.start()
+}
+
+/// Like [`run`], on a Unix socket file that only its owner may connect to.
+#[cfg(unix)]
+pub async fn run_unix<P: AsRef<std::path::Path>>(
+    path: P,
+    credential: Option<Credential>,
+) -> IOResult<()> {
+    let mut builder = Server::builder()
+        .config(Config::new(credential))
+        .upgraded_listeners()?;
+    if !builder.has_listeners() {
+        builder = builder.listener(UnixListener::bind(path)?);
     }
+    systemd::supervise(&mut builder.start()?).await
+}
+
+pub async fn serve(server: TcpListener, config: Config) -> IOResult<()> {
+    Server::builder()
+        .config(config)
+        .listener(server)
+        .start()?
+        .join()
+        .await
 }
 
 pub struct Socks5<U> {
     stage: Stage<U>,
+    session: Session,
 }
 
 impl<U> Socks5<U> {
     pub fn new(credential: Option<Credential>) -> Self {
+        Self::with_config(Arc::new(Config::new(credential)))
+    }
+
+    pub fn with_config(config: Arc<Config>) -> Self {
         Socks5 {
-            stage: Stage::Negotiation(Negotiation(credential)),
+            stage: Stage::Detect(Detect),
+            session: Session::new(config),
         }
     }
+
+    /// The address the client connects from, checked against the trusted networks.
+    pub fn peer(mut self, addr: SocketAddr) -> Self {
+        self.session.peer = Some(addr);
+        self
+    }
+
+    /// An identity the client established before the SOCKS handshake, such as a verified TLS
+    /// client certificate. The client skips authentication and gets the authenticated access
+    /// rules.
+    pub fn user(mut self, user: User) -> Self {
+        self.session.user = Some(user);
+        self
+    }
+
+    /// Connects the client to the destination it was redirected from, as a transparent proxy
+    /// does, instead of waiting for its handshake.
+    pub fn transparent(mut self, destination: SocketAddr) -> Self {
+        self.stage = Stage::Transparent(Transparent(destination));
+        self
+    }
 }
 
 impl<'a, U> Socks5<U>
 where
-    U: for<'b> Upstream<'b> + Stream,
+    U: for<'b> Upstream<'b> + Stream + 'static,
     <U as Upstream<'a>>::Output: Future<Output = IOResult<U>>,
 {
-    pub async fn start<S: Stream>(mut self, mut client: S) -> IOResult<()> {
-        match self.try_process(&mut client).await {
-            Err(err) => err.write(&mut client).await,
+    /// Serves the client until the session ends. The client is owned so that a session between
+    /// two TCP sockets can be relayed within the kernel.
+    pub async fn start<S: Stream + 'static>(mut self, client: S) -> IOResult<()> {
+        let mut client = Rewind::new(client);
+        let config = self.session.config.clone();
+        let tracker = self.session.tracker.clone();
+        config.registry.insert(tracker.clone());
+        config.stats.open();
+        let result = tokio::select! {
+            result = self.try_process(&mut client) => result,
+            _ = tracker.terminated() => Err(Error::Terminated),
+        };
+        config.registry.remove(tracker.id);
+        config
+            .hooks
+            .on_close(&self.session, result.as_ref().err())
+            .await;
+        config.stats.close(&self.session, result.is_err());
+        if let Some(log) = &config.access_log {
+            let reply = match (&result, &self.stage) {
+                (Ok(_), _) | (Err(_), Stage::Forward(_)) => Some(OK),
+                (Err(err), Stage::Socks4(_) | Stage::Http(_)) => {
+                    Some(err.reply().unwrap_or(GENERAL_FAILURE))
+                }
+                (Err(err), _) => err.reply(),
+            };
+            log.record(&self.session, reply);
+        }
+        match result {
+            Err(err) => match self.stage {
+                Stage::Socks4(_) => err.write_socks4(&mut client).await,
+                Stage::Http(_) => err.write_http(&mut client).await,
+                Stage::Authentication(_) => err.write_auth(&mut client).await,
+                Stage::Connect(_) => err.write_reply(&mut client).await,
+                // the relayed stream is under way, which a reply would corrupt, or the client
+                // expects none.
+                Stage::Forward(_) | Stage::Transparent(_) => match err {
+                    Error::IO(err) => Err(err),
+                    _ => Ok(()),
+                },
+                _ => err.write(&mut client).await,
+            },
             Ok(_) => Ok(()),
         }
     }
 
-    pub(crate) async fn try_process<S: Stream>(&mut self, mut client: S) -> Result<()> {
-        while let Continue(result) = self.run(&mut client).await {
-            result?;
+    pub(crate) async fn try_process<S: Stream + 'static>(
+        &mut self,
+        client: &mut Rewind<S>,
+    ) -> Result<()> {
+        let deadline = (self.session.config.handshake_timeout).map(|it| Instant::now() + it);
+        loop {
+            self.session.track(self.stage.name());
+            let flow = match deadline {
+                Some(deadline) if !matches!(self.stage, Stage::Forward(_)) => {
+                    timeout_at(deadline, self.run(client))
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+                }
+                _ => self.run(client).await,
+            };
+            match flow {
+                Continue(result) => result?,
+                Break(()) => return Ok(()),
+            }
         }
-        Ok(())
     }
 
-    async fn run<S: Stream>(&mut self, client: S) -> ControlFlow<(), Result<()>> {
+    async fn run<S: Stream + 'static>(
+        &mut self,
+        client: &mut Rewind<S>,
+    ) -> ControlFlow<(), Result<()>> {
         macro_rules! try_await {
             ($future: expr) => {
                 match $future.await {
@@ -81,10 +288,21 @@ where
         }
 
         self.stage = match &mut self.stage {
+            Stage::Detect(stage) => {
+                self.stage = try_await!(stage.run(client, &self.session));
+                try_await!(self.session.hooks().on_accept(&self.session));
+                return Continue(Ok(()));
+            }
+            Stage::Socks4(stage) => try_await!(stage.run(client, &mut self.session)),
+            Stage::Http(stage) => try_await!(stage.run(client, &mut self.session)),
             Stage::Negotiation(stage) => try_await!(stage.run(client)),
-            Stage::Authentication(stage) => try_await!(stage.run(client)),
-            Stage::Connect(stage) => try_await!(stage.run(client)),
-            Stage::Forward(stage) => return Break(try_await!(stage.run(client))),
+            Stage::Authentication(stage) => try_await!(stage.run(client, &mut self.session)),
+            Stage::Connect(stage) => try_await!(stage.run(client, &mut self.session)),
+            Stage::Transparent(stage) => try_await!(stage.run(&mut self.session)),
+            Stage::Forward(stage) => {
+                try_await!(stage.run(client, &mut self.session));
+                return Break(());
+            }
         };
         Continue(Ok(()))
     }
@@ -92,12 +310,31 @@ where
 
 #[derive(Debug)]
 enum Stage<U = TcpStream> {
+    Detect(Detect),
+    Socks4(Socks4),
+    Http(Http),
     Negotiation(Negotiation),
-    Authentication(Credential),
+    Authentication(Authentication),
     Connect(Connect),
+    Transparent(Transparent),
     Forward(Forward<U>),
 }
 
+impl<U> Stage<U> {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Detect(_) => "detect",
+            Stage::Socks4(_) => "socks4",
+            Stage::Http(_) => "http",
+            Stage::Negotiation(_) => "negotiation",
+            Stage::Authentication(_) => "authentication",
+            Stage::Connect(_) => "connect",
+            Stage::Transparent(_) => "transparent",
+            Stage::Forward(_) => "forward",
+        }
+    }
+}
+
 pub trait Upstream<'a> {
     type Output;
 
@@ -112,13 +349,117 @@ impl<'a> Upstream<'a> for TcpStream {
     }
 }
 
-async fn read_vec_u8<R: UnpinAsyncRead>(mut client: R, n: usize) -> IOResult<Vec<u8>> {
-    let mut buf = unsafe {
-        let mut buf = Vec::with_capacity(n);
-        #[allow(clippy::uninit_vec)]
-        buf.set_len(n);
-        buf
+/// Reads exactly one message, pulling only as many bytes as the decoder asks for so nothing
+/// that follows the message is consumed. The buffer is as long as the longest handshake message
+/// and held in the session future, so a client can't make the handshake allocate more than the
+/// decoded fields.
+async fn read_message<M: Decode, R: UnpinAsyncRead>(mut client: R) -> Result<M> {
+    let mut buf = [0; MAX_MESSAGE_LEN];
+    let mut filled = 0;
+    loop {
+        match M::decode(&buf[..filled]) {
+            Ok((message, _)) => return Ok(message),
+            Err(ParseError::Incomplete(n)) if n <= MAX_MESSAGE_LEN - filled => {
+                client.read_exact(&mut buf[filled..filled + n]).await?;
+                filled += n;
+            }
+            Err(ParseError::Incomplete(_)) => return Err(ParseError::MessageTooLong.into()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Writes one message, encoded into a buffer as long as the longest handshake message.
+async fn write_message<M: Encode, W: UnpinAsyncWrite>(mut client: W, message: &M) -> IOResult<()> {
+    let mut buf = [0; MAX_MESSAGE_LEN];
+    let buf = buf
+        .get_mut(..message.encoded_len())
+        .ok_or(EncodeError::MessageTooLong)?;
+    message.encode(buf)?;
+    client.write_all(buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        codec::{Greeting, Reply},
+        constant::{NO_AUTH, VER},
+        test::AsyncExactRead,
     };
-    client.read_exact(&mut buf).await?;
-    Ok(buf)
+
+    /// Never has enough bytes to decode.
+    #[derive(Debug)]
+    struct Endless;
+
+    impl Decode for Endless {
+        fn decode(_: &[u8]) -> std::result::Result<(Self, usize), ParseError> {
+            Err(ParseError::Incomplete(100))
+        }
+    }
+
+    #[tokio::test]
+    async fn read_messages_exactly() {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&[VER, 2, NO_AUTH, 2, VER]).await.unwrap();
+
+        let greeting = read_message::<Greeting, _>(&mut server).await.unwrap();
+
+        assert_eq!(greeting.methods, [NO_AUTH, 2]);
+        assert_eq!(server.read_exact_bytes().await.unwrap(), [VER]);
+    }
+
+    #[tokio::test]
+    async fn bound_message_length() {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&[0; MAX_MESSAGE_LEN]).await.unwrap();
+
+        let err = read_message::<Endless, _>(&mut server).await.unwrap_err();
+
+        assert!(matches!(&err, Error::IO(err) if err.kind() == io::ErrorKind::InvalidData));
+        assert_eq!(err.to_string(), "message too long");
+        assert_eq!(server.read_exact_bytes().await.unwrap(), [0; 20]);
+    }
+
+    #[tokio::test]
+    async fn write_messages() {
+        let (mut client, mut server) = duplex(1024);
+        let reply = Reply {
+            reply: OK,
+            addr: codec::Addr::Domain("a".repeat(255), 80),
+        };
+
+        write_message(&mut server, &reply).await.unwrap();
+
+        assert_eq!(
+            client.read_exact_bytes::<262>().await.unwrap(),
+            *reply.to_vec().unwrap()
+        );
+    }
+
+    /// Longer than any handshake message.
+    struct Oversized;
+
+    impl Encode for Oversized {
+        fn encoded_len(&self) -> usize {
+            MAX_MESSAGE_LEN + 1
+        }
+
+        fn encode(&self, dst: &mut [u8]) -> std::result::Result<(), EncodeError> {
+            dst[..self.encoded_len()].fill(0);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_to_write_oversized_messages() {
+        let mut out = vec![];
+
+        let err = write_message(&mut out, &Oversized).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+    }
 }
@@ -1,19 +1,113 @@
 use std::env::args;
 
-use socks5::Credential;
+use socks5::{Config, Credential, Tunnel};
+use tokio::net::TcpListener;
 
 const DEFAULT_SOCKS5_PORT: u16 = 1080;
 
 #[tokio::main]
 async fn main() {
-    let mut args = args().skip(1);
-    let credential = args.next().and_then(|it| {
+    let mut args = args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("tunnel") => return tunnel(args.skip(1)).await,
+        #[cfg(feature = "obfs")]
+        Some("obfs") => return obfs::remote(args.skip(1)).await,
+        #[cfg(feature = "obfs")]
+        Some("local") => return obfs::local(args.skip(1)).await,
+        #[cfg(target_os = "linux")]
+        Some("transparent") => return transparent(args.skip(1)).await,
+        _ => (),
+    }
+    let credential = credential(args.next());
+    match args.next() {
+        #[cfg(unix)]
+        Some(path) if path.contains('/') => socks5::run_unix(path, credential).await.unwrap(),
+        port => socks5::run(self::port(port), credential).await.unwrap(),
+    }
+}
+
+/// `socks5 tunnel <remote host:port> [user:pass] [port] [ca.pem]` serves the loopback interface
+/// through the remote instance, over TLS if the CA of its certificate is given.
+async fn tunnel(mut args: impl Iterator<Item = String>) {
+    let remote = args.next().expect("a remote address");
+    let mut tunnel = Tunnel::new(&remote);
+    if let Some(credential) = credential(args.next()) {
+        tunnel = tunnel.credential(credential);
+    }
+    let port = port(args.next());
+    #[cfg(feature = "tls")]
+    if let Some(ca) = args.next() {
+        let host = remote.rsplit_once(':').map_or(&*remote, |(host, _)| host);
+        tunnel = tunnel.tls(ca, host.trim_matches(['[', ']'])).unwrap();
+    }
+    let server = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    socks5::serve(server, Config::default().dial(tunnel))
+        .await
+        .unwrap()
+}
+
+/// `socks5 transparent [port]` serves the connections an iptables `REDIRECT` rule diverts to the
+/// port, on every interface.
+#[cfg(target_os = "linux")]
+async fn transparent(mut args: impl Iterator<Item = String>) {
+    let listener = TcpListener::bind(("0.0.0.0", port(args.next())))
+        .await
+        .unwrap();
+    socks5::Server::builder()
+        .listener(socks5::TransparentListener::redirect(listener))
+        .start()
+        .unwrap()
+        .join()
+        .await
+        .unwrap()
+}
+
+fn credential(arg: Option<String>) -> Option<Credential> {
+    arg.and_then(|it| {
         it.split_once(':')
             .map(|(name, pass)| Credential::new(name, pass))
-    });
-    let port = args
-        .next()
-        .map(|it| it.parse::<u16>())
-        .unwrap_or(Ok(DEFAULT_SOCKS5_PORT));
-    socks5::run(port.unwrap(), credential).await.unwrap();
+    })
+}
+
+fn port(arg: Option<String>) -> u16 {
+    arg.map(|it| it.parse::<u16>())
+        .unwrap_or(Ok(DEFAULT_SOCKS5_PORT))
+        .unwrap()
+}
+
+#[cfg(feature = "obfs")]
+mod obfs {
+    use std::{env, fs};
+
+    use socks5::{Config, ObfsKey};
+    use tokio::net::TcpListener;
+
+    /// `socks5 obfs [user:pass] [port]` serves obfuscated clients on every interface.
+    pub async fn remote(mut args: impl Iterator<Item = String>) {
+        let key = key();
+        let config = Config::new(super::credential(args.next()));
+        let port = super::port(args.next());
+        let server = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
+        socks5::serve_obfs(server, key, config).await.unwrap()
+    }
+
+    /// `socks5 local <remote host:port> [port]` tunnels the clients of the loopback interface to
+    /// the remote instance.
+    pub async fn local(mut args: impl Iterator<Item = String>) {
+        let key = key();
+        let remote = args.next().expect("a remote address");
+        let port = super::port(args.next());
+        let server = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        socks5::serve_obfs_local(server, remote, key).await.unwrap()
+    }
+
+    /// The key of the file `SOCKS5_OBFS_KEY_FILE` names, or of `SOCKS5_OBFS_KEY`, which keeps
+    /// it out of the process list.
+    fn key() -> ObfsKey {
+        let key = match env::var_os("SOCKS5_OBFS_KEY_FILE") {
+            Some(path) => fs::read_to_string(path).expect("a readable key file"),
+            None => env::var("SOCKS5_OBFS_KEY").expect("SOCKS5_OBFS_KEY or SOCKS5_OBFS_KEY_FILE"),
+        };
+        ObfsKey::new(key.trim_end())
+    }
 }
@@ -3,8 +3,8 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 pub trait UnpinAsyncRead: AsyncRead + Unpin {}
 pub trait UnpinAsyncWrite: AsyncWrite + Unpin {}
-pub trait Stream: AsyncRead + AsyncWrite + Unpin {}
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
 
 impl<T: ?Sized> UnpinAsyncRead for T where T: AsyncRead + Unpin {}
 impl<T: ?Sized> UnpinAsyncWrite for T where T: AsyncWrite + Unpin {}
-impl<T: ?Sized> Stream for T where T: AsyncRead + AsyncWrite + Unpin {}
+impl<T: ?Sized> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
@@ -0,0 +1,923 @@
+//! Sessions multiplexed over a few persistent links between two instances, as streams with flow
+//! control of their own, framed as yamux frames are.
+//!
+//! Every frame starts with a 12 byte header: the version, the type, the flags, the stream id and
+//! a length, which is the size of the payload of data frames and the value of the others. A
+//! stream opens with a `SYN` frame, half-closes with a `FIN` one and resets with a `RST` one,
+//! and may only be sent the bytes of its window, which its reader widens as it reads them.
+use std::{
+    collections::{HashMap, VecDeque},
+    future::{poll_fn, Future},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+    net::TcpListener,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    task::JoinHandle,
+    time::{interval, sleep_until, timeout, Instant, MissedTickBehavior},
+};
+
+use crate::{
+    marker::Stream,
+    server::{back_off, Accept, Incoming, ListenAddr},
+    BoxFuture, IOResult,
+};
+
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+/// The window of every stream until the first window update of its reader.
+const INITIAL_WINDOW: u32 = 256 * 1024;
+/// The largest payload of the data frames sent.
+const MAX_FRAME: usize = 16 * 1024;
+/// The streams accepted from the links of a [`MuxListener`] before they're served.
+const BACKLOG: usize = 128;
+/// The streams open at once on a link, beyond which the peer's are reset.
+const MAX_STREAMS: usize = 512;
+/// The frames queued on a link before it's written. The last [`MAX_STREAMS`] are kept for the
+/// resets of dropped streams, which can't wait.
+const QUEUE: usize = 2 * MAX_STREAMS;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+const DATA: u8 = 0;
+const WINDOW_UPDATE: u8 = 1;
+const PING: u8 = 2;
+const GO_AWAY: u8 = 3;
+
+const SYN: u16 = 1;
+const ACK: u16 = 2;
+const FIN: u16 = 4;
+const RST: u16 = 8;
+
+/// The settings of multiplexed links, which both ends should share.
+#[derive(Debug, Clone)]
+pub struct Multiplex {
+    /// The links opened to the remote instance, which new streams are spread over.
+    pub links: usize,
+    /// The bytes a stream is sent ahead of its reader, at least 256 KiB.
+    pub window: u32,
+    /// How often links are pinged. A link that receives nothing for twice as long is closed.
+    pub keepalive: Duration,
+    /// The longest wait before reconnecting a link, which doubles from 100ms after every failure.
+    pub max_backoff: Duration,
+}
+
+impl Default for Multiplex {
+    fn default() -> Self {
+        Multiplex {
+            links: 4,
+            window: INITIAL_WINDOW,
+            keepalive: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u16,
+    stream: u32,
+    /// The length of data frames, which is that of the payload, or the value of the others.
+    value: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn data(stream: u32, flags: u16, payload: Vec<u8>) -> Self {
+        Frame {
+            kind: DATA,
+            flags,
+            stream,
+            value: payload.len() as u32,
+            payload,
+        }
+    }
+
+    fn control(kind: u8, flags: u16, stream: u32, value: u32) -> Self {
+        Frame {
+            kind,
+            flags,
+            stream,
+            value,
+            payload: vec![],
+        }
+    }
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0] = VERSION;
+        header[1] = self.kind;
+        header[2..4].copy_from_slice(&self.flags.to_be_bytes());
+        header[4..8].copy_from_slice(&self.stream.to_be_bytes());
+        header[8..].copy_from_slice(&self.value.to_be_bytes());
+        header
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Received and not read yet.
+    inbound: VecDeque<u8>,
+    /// Read and not returned to the sender's window yet.
+    unacked: u32,
+    send_window: u32,
+    eof: bool,
+    fin_sent: bool,
+    reset: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl State {
+    fn wake(&mut self) {
+        for waker in [self.reader.take(), self.writer.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+}
+
+/// A connection carrying the streams, served by a task that stops once it fails.
+struct Link {
+    frames: mpsc::Sender<Frame>,
+    /// The tasks waiting for room in the queue.
+    blocked: Mutex<Vec<Waker>>,
+    /// The latest ping of the peer that's still to be answered.
+    pong: Mutex<Option<u32>>,
+    pinged: Notify,
+    /// `None` once the link is closed.
+    streams: Mutex<Option<HashMap<u32, Arc<Mutex<State>>>>>,
+    next_id: AtomicU32,
+    window: u32,
+    shutdown: Notify,
+}
+
+type Accepted = (
+    mpsc::Sender<IOResult<Incoming<MuxStream>>>,
+    Option<SocketAddr>,
+);
+
+impl Link {
+    /// Serves the link, accepting the streams the peer opens if `accepted` is set and opening
+    /// them otherwise.
+    fn spawn(
+        stream: Box<dyn Stream>,
+        settings: &Multiplex,
+        accepted: Option<Accepted>,
+    ) -> Arc<Self> {
+        let (frames, outgoing) = mpsc::channel(QUEUE);
+        let link = Arc::new(Link {
+            frames,
+            blocked: Mutex::new(vec![]),
+            pong: Mutex::new(None),
+            pinged: Notify::new(),
+            streams: Mutex::new(Some(HashMap::new())),
+            // the opening side uses odd ids, as a yamux client does.
+            next_id: AtomicU32::new(1),
+            window: settings.window.max(INITIAL_WINDOW),
+            shutdown: Notify::new(),
+        });
+        let keepalive = settings.keepalive;
+        tokio::spawn({
+            let link = link.clone();
+            async move {
+                let (reader, writer) = split(stream);
+                tokio::select! {
+                    _ = link.read_loop(reader, accepted, keepalive * 2) => (),
+                    _ = link.write_loop(writer, outgoing) => (),
+                    _ = link.keepalive(keepalive) => (),
+                    _ = link.shutdown.notified() => (),
+                }
+                link.close();
+            }
+        });
+        link
+    }
+
+    fn is_open(&self) -> bool {
+        self.streams.lock().unwrap().is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.streams
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, HashMap::len)
+    }
+
+    /// Queues the frame, closing the link if the queue is full.
+    fn send(&self, frame: Frame) -> IOResult<()> {
+        self.frames.try_send(frame).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                self.shutdown.notify_one();
+                io::Error::new(ErrorKind::OutOfMemory, "link queue full")
+            }
+            TrySendError::Closed(_) => ErrorKind::BrokenPipe.into(),
+        })
+    }
+
+    /// Whether the queue has room left besides the resets of every stream, registering the
+    /// task to be woken once it may have otherwise.
+    fn poll_room(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.frames.capacity() > MAX_STREAMS {
+            return Poll::Ready(());
+        }
+        self.blocked.lock().unwrap().push(cx.waker().clone());
+        // the writer may have made room before the task was registered.
+        if self.frames.capacity() > MAX_STREAMS {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    fn poll_send(&self, cx: &mut Context<'_>, frame: impl FnOnce() -> Frame) -> Poll<IOResult<()>> {
+        self.poll_room(cx).map(|_| self.send(frame()))
+    }
+
+    fn register(self: &Arc<Self>, id: u32) -> IOResult<MuxStream> {
+        let state = Arc::new(Mutex::new(State {
+            send_window: INITIAL_WINDOW,
+            ..State::default()
+        }));
+        self.streams
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?
+            .insert(id, state.clone());
+        Ok(MuxStream {
+            id,
+            link: self.clone(),
+            state,
+        })
+    }
+
+    fn open(self: &Arc<Self>) -> IOResult<MuxStream> {
+        if self.len() >= MAX_STREAMS {
+            return Err(io::Error::other("too many streams"));
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.register(id)?;
+        self.send(Frame::control(
+            WINDOW_UPDATE,
+            SYN,
+            id,
+            self.window - INITIAL_WINDOW,
+        ))?;
+        Ok(stream)
+    }
+
+    /// Resets every stream, failing their reads and writes.
+    fn close(&self) {
+        let streams = self.streams.lock().unwrap().take();
+        for state in streams.into_iter().flat_map(HashMap::into_values) {
+            let mut state = state.lock().unwrap();
+            state.reset = true;
+            state.wake();
+        }
+    }
+
+    /// Reads the frames of the peer, waiting for room in the queue before each of them, and
+    /// fails once there's been none for `idle`, as when nothing was received for as long.
+    async fn read_loop<R: AsyncRead + Unpin>(
+        self: &Arc<Self>,
+        mut reader: R,
+        accepted: Option<Accepted>,
+        idle: Duration,
+    ) -> IOResult<()> {
+        loop {
+            timeout(idle, poll_fn(|cx| self.poll_room(cx)))
+                .await
+                .map_err(|_| io::Error::new(ErrorKind::TimedOut, "link queue full"))?;
+            let mut header = [0; HEADER_LEN];
+            timeout(idle, reader.read_exact(&mut header))
+                .await
+                .map_err(|_| io::Error::from(ErrorKind::TimedOut))??;
+            if header[0] != VERSION {
+                return Err(invalid_data("unsupported version"));
+            }
+            let kind = header[1];
+            let flags = u16::from_be_bytes([header[2], header[3]]);
+            let stream = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let value = u32::from_be_bytes(header[8..].try_into().unwrap());
+            match kind {
+                DATA => {
+                    if value > self.window {
+                        return Err(invalid_data("frame larger than the window"));
+                    }
+                    let mut payload = vec![0; value as usize];
+                    reader.read_exact(&mut payload).await?;
+                    self.receive(flags, stream, 0, payload, accepted.as_ref())?;
+                }
+                WINDOW_UPDATE => self.receive(flags, stream, value, vec![], accepted.as_ref())?,
+                PING if flags & SYN != 0 => {
+                    *self.pong.lock().unwrap() = Some(value);
+                    self.pinged.notify_one();
+                }
+                PING => (),
+                GO_AWAY => return Ok(()),
+                _ => return Err(invalid_data("unknown frame type")),
+            }
+        }
+    }
+
+    fn receive(
+        self: &Arc<Self>,
+        flags: u16,
+        id: u32,
+        window: u32,
+        payload: Vec<u8>,
+        accepted: Option<&Accepted>,
+    ) -> IOResult<()> {
+        if flags & SYN != 0 {
+            let Some((streams, peer)) = accepted else {
+                return Err(invalid_data("stream opened by the accepting side"));
+            };
+            if self.state(id).is_some() {
+                return Err(invalid_data("stream opened twice"));
+            }
+            if self.len() >= MAX_STREAMS {
+                return self.send(Frame::data(id, RST, vec![]));
+            }
+            let stream = self.register(id)?;
+            self.send(Frame::control(
+                WINDOW_UPDATE,
+                ACK,
+                id,
+                self.window - INITIAL_WINDOW,
+            ))?;
+            let incoming = Incoming {
+                peer: *peer,
+                ..Incoming::new(stream)
+            };
+            // a stream the server can't take up in time is dropped, which resets it.
+            let _ = streams.try_send(Ok(incoming));
+        }
+        // frames of streams closed here already are dropped.
+        let Some(state) = self.state(id) else {
+            return Ok(());
+        };
+        let mut state = state.lock().unwrap();
+        state.send_window = state.send_window.saturating_add(window);
+        if state.inbound.len() + state.unacked as usize + payload.len() > self.window as usize {
+            return Err(invalid_data("window exceeded"));
+        }
+        if !state.eof {
+            state.inbound.extend(payload);
+        }
+        state.eof |= flags & FIN != 0;
+        state.reset |= flags & RST != 0;
+        state.wake();
+        Ok(())
+    }
+
+    fn state(&self, id: u32) -> Option<Arc<Mutex<State>>> {
+        self.streams.lock().unwrap().as_ref()?.get(&id).cloned()
+    }
+
+    async fn keepalive(&self, period: Duration) -> IOResult<()> {
+        let mut ping = interval(period);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ping.tick().await;
+        let mut opaque = 0u32;
+        loop {
+            ping.tick().await;
+            poll_fn(|cx| self.poll_send(cx, || Frame::control(PING, SYN, 0, opaque))).await?;
+            opaque = opaque.wrapping_add(1);
+        }
+    }
+
+    /// Writes the queued frames, answering the latest ping of the peer first.
+    async fn write_loop<W: AsyncWrite + Unpin>(
+        &self,
+        writer: W,
+        mut frames: mpsc::Receiver<Frame>,
+    ) -> IOResult<()> {
+        let mut writer = BufWriter::new(writer);
+        loop {
+            let frame = tokio::select! {
+                biased;
+                _ = self.pinged.notified() => match self.pong.lock().unwrap().take() {
+                    Some(opaque) => Frame::control(PING, ACK, 0, opaque),
+                    None => continue,
+                },
+                frame = frames.recv() => match frame {
+                    Some(frame) => frame,
+                    None => return Ok(()),
+                },
+            };
+            for waker in self.blocked.lock().unwrap().drain(..) {
+                waker.wake();
+            }
+            writer.write_all(&frame.header()).await?;
+            writer.write_all(&frame.payload).await?;
+            if frames.is_empty() {
+                writer.flush().await?;
+            }
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// A stream of a multiplexed link, which is reset when dropped before both ends closed it.
+pub struct MuxStream {
+    id: u32,
+    link: Arc<Link>,
+    state: Arc<Mutex<State>>,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        let read = !state.inbound.is_empty();
+        if read {
+            let n = buf.remaining().min(state.inbound.len());
+            let (front, back) = state.inbound.as_slices();
+            let split = n.min(front.len());
+            buf.put_slice(&front[..split]);
+            buf.put_slice(&back[..n - split]);
+            state.inbound.drain(..n);
+            state.unacked += n as u32;
+        }
+        let unacked = state.unacked;
+        // without room in the queue, the update is sent by a later read, once woken.
+        if unacked >= self.link.window / 2
+            && self
+                .link
+                .poll_send(cx, || Frame::control(WINDOW_UPDATE, 0, self.id, unacked))
+                .is_ready()
+        {
+            // a closed link resets the stream, which fails the next read.
+            state.unacked = 0;
+        }
+        if read {
+            return Poll::Ready(Ok(()));
+        }
+        if state.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if state.reset {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+        state.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+        if state.fin_sent {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if state.send_window == 0 {
+            state.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.send_window as usize).min(MAX_FRAME);
+        ready!(self
+            .link
+            .poll_send(cx, || Frame::data(self.id, 0, buf[..n].to_vec())))?;
+        state.send_window -= n as u32;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.fin_sent && !state.reset {
+            ready!(self
+                .link
+                .poll_send(cx, || Frame::data(self.id, FIN, vec![])))?;
+            state.fin_sent = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap();
+        if !(state.reset || state.fin_sent && state.eof) {
+            let _ = self.link.send(Frame::data(self.id, RST, vec![]));
+        }
+        drop(state);
+        if let Some(streams) = self.link.streams.lock().unwrap().as_mut() {
+            streams.remove(&self.id);
+        }
+    }
+}
+
+/// The links opened to a remote instance, reconnected with back-off once they fail.
+pub(crate) struct Pool {
+    settings: Multiplex,
+    links: Mutex<Vec<Arc<Link>>>,
+    backoff: Mutex<Backoff>,
+}
+
+struct Backoff {
+    delay: Duration,
+    retry_at: Option<Instant>,
+    /// The links being connected, which count towards the configured links.
+    connecting: usize,
+}
+
+/// A link being connected, until the attempt ends or is dropped.
+struct Connecting<'a>(&'a Pool);
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        self.0.backoff.lock().unwrap().connecting -= 1;
+    }
+}
+
+impl Pool {
+    pub(crate) fn new(settings: Multiplex) -> Self {
+        Pool {
+            settings,
+            links: Mutex::new(vec![]),
+            backoff: Mutex::new(Backoff {
+                delay: MIN_BACKOFF,
+                retry_at: None,
+                connecting: 0,
+            }),
+        }
+    }
+
+    /// Opens a stream on the least busy link, connecting a new one with `connect` while there
+    /// are fewer than the configured links and the last attempt isn't backing off.
+    pub(crate) async fn open<F, C>(&self, connect: F) -> IOResult<MuxStream>
+    where
+        F: FnOnce() -> C,
+        C: Future<Output = IOResult<Box<dyn Stream>>>,
+    {
+        let (idlest, retry_at) = {
+            let mut links = self.links.lock().unwrap();
+            links.retain(|it| it.is_open());
+            let idlest = links.iter().min_by_key(|it| it.len()).cloned();
+            let mut backoff = self.backoff.lock().unwrap();
+            let backing_off = backoff.retry_at.is_some_and(|it| it > Instant::now());
+            let full = links.len() + backoff.connecting >= self.settings.links;
+            if let Some(link) = idlest.as_ref().filter(|_| full || backing_off) {
+                return link.open();
+            }
+            backoff.connecting += 1;
+            (idlest, backoff.retry_at)
+        };
+        let connecting = Connecting(self);
+        if let Some(retry_at) = retry_at {
+            sleep_until(retry_at).await;
+        }
+        let connected = connect().await;
+        drop(connecting);
+        let mut backoff = self.backoff.lock().unwrap();
+        match connected {
+            Ok(stream) => {
+                backoff.delay = MIN_BACKOFF;
+                backoff.retry_at = None;
+                drop(backoff);
+                let link = Link::spawn(stream, &self.settings, None);
+                self.links.lock().unwrap().push(link.clone());
+                link.open()
+            }
+            Err(e) => {
+                backoff.retry_at = Some(Instant::now() + backoff.delay);
+                backoff.delay = (backoff.delay * 2).min(self.settings.max_backoff);
+                drop(backoff);
+                match idlest {
+                    Some(link) => link.open(),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for link in self.links.get_mut().unwrap().drain(..) {
+            link.shutdown.notify_one();
+        }
+    }
+}
+
+/// Accepts multiplexed links on a TCP listener, serving each of their streams as a client, to
+/// be added to a [`ServerBuilder`](crate::ServerBuilder) as any other listener.
+pub struct MuxListener {
+    addr: SocketAddr,
+    streams: tokio::sync::Mutex<mpsc::Receiver<IOResult<Incoming<MuxStream>>>>,
+    links: JoinHandle<()>,
+}
+
+impl MuxListener {
+    /// Starts accepting links, which must be done within a runtime.
+    pub fn new(listener: TcpListener, settings: Multiplex) -> IOResult<Self> {
+        let addr = listener.local_addr()?;
+        let (streams, receiver) = mpsc::channel(BACKLOG);
+        let links = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let accepted = (streams.clone(), Some(peer));
+                        Link::spawn(Box::new(stream), &settings, Some(accepted));
+                    }
+                    Err(e) => {
+                        // passed on to be counted by the server.
+                        let _ = streams.send(Err(e)).await;
+                        back_off().await;
+                    }
+                }
+            }
+        });
+        Ok(MuxListener {
+            addr,
+            streams: tokio::sync::Mutex::new(receiver),
+            links,
+        })
+    }
+}
+
+impl Accept for MuxListener {
+    type Stream = MuxStream;
+
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<MuxStream>>> {
+        Box::pin(async {
+            let mut streams = self.streams.lock().await;
+            streams
+                .recv()
+                .await
+                .unwrap_or_else(|| Err(ErrorKind::NotConnected.into()))
+        })
+    }
+
+    fn local_addr(&self) -> IOResult<ListenAddr> {
+        Ok(ListenAddr::Inet(self.addr))
+    }
+}
+
+impl Drop for MuxListener {
+    fn drop(&mut self) {
+        self.links.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        future::{pending, ready},
+        io::{self, ErrorKind},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{duplex, sink, AsyncReadExt, AsyncWriteExt},
+        sync::mpsc,
+        time::{sleep, timeout, Instant},
+    };
+
+    use super::{
+        Accepted, Frame, Link, Multiplex, MuxStream, Pool, ACK, BACKLOG, HEADER_LEN,
+        INITIAL_WINDOW, MAX_STREAMS, MIN_BACKOFF, PING, SYN, WINDOW_UPDATE,
+    };
+    use crate::{marker::Stream, server::Incoming, test::AsyncExactRead, IOResult};
+
+    type Streams = mpsc::Receiver<IOResult<Incoming<MuxStream>>>;
+
+    /// Connects a link to a peer accepting its streams.
+    fn pair(settings: &Multiplex) -> (Arc<Link>, Streams) {
+        let (client, server) = duplex(1 << 20);
+        let (streams, accepted) = mpsc::channel(BACKLOG);
+        let peer: Accepted = (streams, None);
+        Link::spawn(Box::new(server), settings, Some(peer));
+        (Link::spawn(Box::new(client), settings, None), accepted)
+    }
+
+    async fn accept(streams: &mut Streams) -> MuxStream {
+        streams.recv().await.unwrap().unwrap().stream
+    }
+
+    #[tokio::test]
+    async fn streams_are_independent() {
+        let (link, mut streams) = pair(&Multiplex::default());
+        let mut first = link.open().unwrap();
+        let mut second = link.open().unwrap();
+        first.write_all(b"first").await.unwrap();
+        second.write_all(b"second").await.unwrap();
+
+        let mut first_peer = accept(&mut streams).await;
+        let mut second_peer = accept(&mut streams).await;
+        assert_eq!(second_peer.read_exact_bytes().await.unwrap(), *b"second");
+        assert_eq!(first_peer.read_exact_bytes().await.unwrap(), *b"first");
+        second_peer.write_all(b"pong").await.unwrap();
+        assert_eq!(second.read_exact_bytes().await.unwrap(), *b"pong");
+
+        first.shutdown().await.unwrap();
+        assert_eq!(first_peer.read(&mut [0]).await.unwrap(), 0);
+        drop(second);
+        let e = second_peer.read(&mut [0]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(link.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn writers_wait_for_the_window_of_readers() {
+        let (link, mut streams) = pair(&Multiplex::default());
+        let mut client = link.open().unwrap();
+        let data = vec![7; 1 << 20];
+        let mut sent = 0;
+        while let Ok(n) = timeout(Duration::from_millis(50), client.write(&data[sent..])).await {
+            sent += n.unwrap();
+        }
+        assert_eq!(sent, INITIAL_WINDOW as usize);
+
+        let mut server = accept(&mut streams).await;
+        let reader = tokio::spawn(async move {
+            let mut received = vec![];
+            server.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        client.write_all(&data[sent..]).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(reader.await.unwrap(), data);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_answered_links_open() {
+        let settings = Multiplex {
+            keepalive: Duration::from_secs(1),
+            ..Multiplex::default()
+        };
+        let (link, _streams) = pair(&settings);
+        sleep(Duration::from_secs(10)).await;
+        assert!(link.is_open());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_links_the_peer_stopped_answering() {
+        let settings = Multiplex {
+            keepalive: Duration::from_secs(1),
+            ..Multiplex::default()
+        };
+        let (client, mut server) = duplex(1 << 20);
+        let link = Link::spawn(Box::new(client), &settings, None);
+        let mut stream = link.open().unwrap();
+        // the peer reads the pings and never answers them.
+        tokio::spawn(async move { tokio::io::copy(&mut server, &mut sink()).await });
+
+        let started = Instant::now();
+        let e = stream.read(&mut [0]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert!(!link.is_open());
+    }
+
+    #[tokio::test]
+    async fn answer_only_the_latest_ping() {
+        let (client, mut server) = duplex(1 << 20);
+        let _link = Link::spawn(Box::new(client), &Multiplex::default(), None);
+        let pings: Vec<u8> = (0..1000)
+            .flat_map(|opaque| Frame::control(PING, SYN, 0, opaque).header())
+            .collect();
+        server.write_all(&pings).await.unwrap();
+
+        let mut pongs = 0;
+        loop {
+            let header: [u8; HEADER_LEN] = server.read_exact_bytes().await.unwrap();
+            pongs += 1;
+            if header == Frame::control(PING, ACK, 0, 999).header() {
+                break;
+            }
+        }
+        assert!(pongs < 1000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cap_the_streams_of_links() {
+        let (client, mut server) = duplex(1 << 20);
+        let (streams, _accepted) = mpsc::channel(2 * MAX_STREAMS);
+        let peer: Accepted = (streams, None);
+        let link = Link::spawn(Box::new(client), &Multiplex::default(), Some(peer));
+        let syns: Vec<u8> = (0..MAX_STREAMS as u32 + 10)
+            .flat_map(|id| Frame::control(WINDOW_UPDATE, SYN, 2 * id + 1, 0).header())
+            .collect();
+        server.write_all(&syns).await.unwrap();
+        tokio::spawn(async move { tokio::io::copy(&mut server, &mut sink()).await });
+
+        sleep(Duration::from_millis(1)).await;
+        assert_eq!(link.len(), MAX_STREAMS);
+        assert!(link.open().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_links_whose_queue_stays_full() {
+        let settings = Multiplex {
+            keepalive: Duration::from_secs(1),
+            ..Multiplex::default()
+        };
+        // the peer opens streams and never reads what they're answered.
+        let (client, mut server) = duplex(64);
+        let (streams, _accepted) = mpsc::channel(BACKLOG);
+        let peer: Accepted = (streams, None);
+        let link = Link::spawn(Box::new(client), &settings, Some(peer));
+        let mut opened = 0u32;
+        while opened < 100_000 {
+            let syn = Frame::control(WINDOW_UPDATE, SYN, 2 * opened + 1, 0).header();
+            if server.write_all(&syn).await.is_err() {
+                break;
+            }
+            opened += 1;
+        }
+        assert!(opened < 100_000);
+        assert!(!link.is_open());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reuse_links_and_reconnect_with_back_off() {
+        let pool = Pool::new(Multiplex {
+            links: 1,
+            ..Multiplex::default()
+        });
+        let (peers, _streams) = mpsc::channel(BACKLOG);
+        let connects = Cell::new(0);
+        let connect = || {
+            connects.set(connects.get() + 1);
+            let (client, server) = duplex(1 << 20);
+            let peer: Accepted = (peers.clone(), None);
+            Link::spawn(Box::new(server), &Multiplex::default(), Some(peer));
+            ready(Ok(Box::new(client) as Box<dyn Stream>))
+        };
+        let mut first = pool.open(&connect).await.unwrap();
+        let _second = pool.open(&connect).await.unwrap();
+        assert_eq!(connects.get(), 1);
+
+        // a closed link resets its streams and is replaced by the next one connected.
+        pool.links.lock().unwrap()[0].shutdown.notify_one();
+        assert!(first.read(&mut [0]).await.is_err());
+
+        let refused = || ready(Err(io::Error::from(ErrorKind::ConnectionRefused)));
+        assert!(pool.open(refused).await.is_err());
+        let started = Instant::now();
+        pool.open(&connect).await.unwrap();
+        assert_eq!(started.elapsed(), MIN_BACKOFF);
+        assert_eq!(connects.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn open_streams_while_a_link_connects() {
+        let pool = Pool::new(Multiplex {
+            links: 2,
+            ..Multiplex::default()
+        });
+        let (peers, _streams) = mpsc::channel(BACKLOG);
+        let connect = || {
+            let (client, server) = duplex(1 << 20);
+            let peer: Accepted = (peers.clone(), None);
+            Link::spawn(Box::new(server), &Multiplex::default(), Some(peer));
+            ready(Ok(Box::new(client) as Box<dyn Stream>))
+        };
+        pool.open(connect).await.unwrap();
+
+        let stalled = pool.open(pending);
+        tokio::pin!(stalled);
+        assert!(timeout(Duration::from_millis(10), &mut stalled)
+            .await
+            .is_err());
+        let opened = timeout(Duration::from_millis(10), pool.open(connect)).await;
+        assert!(opened.unwrap().is_ok());
+        assert_eq!(pool.links.lock().unwrap().len(), 1);
+    }
+}
@@ -1,47 +1,40 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use crate::connect::Connect;
-use crate::constant::{CREDENTIAL_AUTH, NO_AUTH, VER};
-use crate::credential::Credential;
+use crate::auth::{AuthMethods, Authentication};
+use crate::codec::{Greeting, MethodSelection};
 use crate::error::Error;
-use crate::extract::try_extract_version;
 use crate::marker::{Stream, UnpinAsyncRead};
-use crate::{read_vec_u8, Result, Stage};
+use crate::{read_message, write_message, Result, Stage};
 
 #[derive(Debug)]
-pub struct Negotiation(pub Option<Credential>);
+pub struct Negotiation(pub AuthMethods);
 
 impl Negotiation {
     pub async fn run<S: Stream, U>(&mut self, mut client: S) -> Result<Stage<U>> {
         let methods = try_extract_methods(&mut client).await?;
-
-        if let Some(credential) = &self.0 {
-            if !methods.contains(&CREDENTIAL_AUTH) {
-                return Err(Error::UnacceptableMethods(methods));
-            }
-            client.write_all(&[VER, CREDENTIAL_AUTH]).await?;
-            return Ok(Stage::Authentication(credential.clone()));
-        }
-
-        if !methods.contains(&NO_AUTH) {
+        let Some(method) = self.0.select(&methods) else {
             return Err(Error::UnacceptableMethods(methods));
-        }
-        client.write_all(&[VER, NO_AUTH]).await?;
-        Ok(Stage::Connect(Connect))
+        };
+        write_message(
+            &mut client,
+            &MethodSelection {
+                method: method.code(),
+            },
+        )
+        .await?;
+        Ok(Stage::Authentication(Authentication(method.clone())))
     }
 }
 
-extract!(nmethods == 0 => Error::NoAuthMethods);
-
-async fn try_extract_methods<T: UnpinAsyncRead>(mut client: T) -> Result<Vec<u8>> {
-    _ = try_extract_version(&mut client).await?;
-    let nmethods = try_extract_nmethods(&mut client).await.map(usize::from)?;
-    Ok(read_vec_u8(client, nmethods).await?)
+async fn try_extract_methods<T: UnpinAsyncRead>(client: T) -> Result<Vec<u8>> {
+    let Greeting { methods } = read_message(client).await?;
+    Ok(methods)
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::{io::duplex, net::TcpStream};
+    use tokio::{
+        io::{duplex, AsyncWriteExt},
+        net::TcpStream,
+    };
 
     use crate::{
         constant::{CREDENTIAL_AUTH, NO_AUTH, VER},
@@ -55,19 +48,19 @@ mod tests {
     #[tokio::test]
     async fn no_auth_negotiation() {
         let (mut client, mut server) = duplex(100);
-        let mut negotiation = Negotiation(None);
+        let mut negotiation = Negotiation(None.into());
         client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
 
         let result = negotiation.run::<_, TcpStream>(&mut server).await;
 
-        assert!(matches!(result, Ok(Stage::Connect(_))));
+        assert!(matches!(result, Ok(Stage::Authentication(method)) if method.0.code() == NO_AUTH));
         assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
     }
 
     #[tokio::test]
     async fn fails_with_no_auth_negotiation_if_credential_was_provided() {
         let (mut client, mut server) = duplex(100);
-        let mut negotiation = Negotiation(Some(Credential::new("root", "root")));
+        let mut negotiation = Negotiation(Some(Credential::new("root", "root")).into());
         client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
 
         let result = negotiation.run::<_, TcpStream>(&mut server).await;
@@ -78,13 +71,13 @@ mod tests {
     #[tokio::test]
     async fn credential_auth_negotiation() {
         let (mut client, mut server) = duplex(100);
-        let mut negotiation = Negotiation(Some(Credential::new("socks5", "password")));
+        let mut negotiation = Negotiation(Some(Credential::new("socks5", "password")).into());
         client.write_all(&[VER, 1, CREDENTIAL_AUTH]).await.unwrap();
 
         let result = negotiation.run::<_, TcpStream>(&mut server).await;
 
         assert!(
-            matches!(result, Ok(Stage::Authentication(credential)) if credential == Credential::new("socks5", "password"))
+            matches!(result, Ok(Stage::Authentication(method)) if method.0.code() == CREDENTIAL_AUTH)
         );
         assert_eq!(
             client.read_exact_bytes().await.unwrap(),
@@ -95,7 +88,7 @@ mod tests {
     #[tokio::test]
     async fn fails_with_err_version() {
         let (mut client, mut server) = duplex(100);
-        let mut negotiation = Negotiation(None);
+        let mut negotiation = Negotiation(None.into());
         client.write_all(&[0x6, 1, NO_AUTH]).await.unwrap();
 
         let err = negotiation
@@ -109,7 +102,7 @@ mod tests {
     #[tokio::test]
     async fn fails_without_any_authentication_methods() {
         let (mut client, mut server) = duplex(100);
-        let mut negotiation = Negotiation(None);
+        let mut negotiation = Negotiation(None.into());
         client.write_all(&[VER, 0]).await.unwrap();
 
         let err = negotiation
@@ -123,7 +116,7 @@ mod tests {
     #[tokio::test]
     async fn fails_with_unacceptable_methods() {
         let (mut client, mut server) = duplex(100);
-        let mut negotiation = Negotiation(None);
+        let mut negotiation = Negotiation(None.into());
         client.write_all(&[VER, 1, 0x3]).await.unwrap();
 
         let err = negotiation
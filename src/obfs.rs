@@ -0,0 +1,549 @@
+//! An AEAD encrypted transport in the style of Shadowsocks, so that neither the SOCKS handshake
+//! nor the relayed bytes can be fingerprinted on the network. Each direction starts with a random
+//! salt, which derives its key from the pre-shared one, followed by chunks of an encrypted length
+//! and an encrypted payload.
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt, io,
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+    pbkdf2::{self, PBKDF2_HMAC_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    config::Config,
+    server::{back_off, Accept, Incoming, ListenAddr, Server},
+    BoxFuture, IOResult,
+};
+
+const SALT_LEN: usize = 32;
+/// The salts of the streams read most recently, which are refused if they're read again.
+const SEEN_SALTS: usize = 65536;
+/// The rounds of PBKDF2 deriving the key from a passphrase.
+const KDF_ROUNDS: u32 = 100_000;
+const TAG_LEN: usize = 16;
+/// The largest payload of a chunk, as in Shadowsocks.
+const MAX_PAYLOAD: usize = 0x3FFF;
+const READ_CHUNK: usize = 4096;
+
+/// The key both ends of an obfuscated link share, along with the salts of the streams it
+/// recently decrypted, so that a recorded stream can't be replayed.
+#[derive(Clone)]
+pub struct ObfsKey(Arc<Shared>);
+
+struct Shared {
+    key: [u8; 32],
+    seen: Mutex<SeenSalts>,
+}
+
+/// A bounded set of salts, which forgets the oldest once full.
+#[derive(Default)]
+struct SeenSalts {
+    salts: HashSet<[u8; SALT_LEN]>,
+    order: VecDeque<[u8; SALT_LEN]>,
+}
+
+impl SeenSalts {
+    /// Remembers the salt, returning whether it was new.
+    fn insert(&mut self, salt: [u8; SALT_LEN]) -> bool {
+        if !self.salts.insert(salt) {
+            return false;
+        }
+        if self.order.len() == SEEN_SALTS {
+            let oldest = self.order.pop_front().expect("the salts are full");
+            self.salts.remove(&oldest);
+        }
+        self.order.push_back(salt);
+        true
+    }
+}
+
+impl ObfsKey {
+    /// Derives the key from a passphrase with PBKDF2, which slows down guessing it from a
+    /// recorded stream but can't make a short or common passphrase safe: it should be long and
+    /// random, such as 32 bytes from a secure generator.
+    pub fn new<P: AsRef<[u8]>>(passphrase: P) -> Self {
+        let mut key = [0; 32];
+        let rounds = NonZeroU32::new(KDF_ROUNDS).expect("rounds aren't zero");
+        pbkdf2::derive(
+            PBKDF2_HMAC_SHA256,
+            rounds,
+            b"socks5-obfs",
+            passphrase.as_ref(),
+            &mut key,
+        );
+        ObfsKey(Arc::new(Shared {
+            key,
+            seen: Mutex::default(),
+        }))
+    }
+
+    /// Encrypts the stream to the other end of the link.
+    pub fn wrap<S>(&self, stream: S) -> IOResult<ObfsStream<S>> {
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| io::Error::other("no random salt"))?;
+        Ok(ObfsStream {
+            inner: stream,
+            key: self.clone(),
+            opener: None,
+            sealed: vec![],
+            payload_len: None,
+            plain: vec![],
+            read: 0,
+            sealer: self.cipher(&salt),
+            out: salt.to_vec(),
+            written: 0,
+        })
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Cipher {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.0.key);
+        let okm = prk
+            .expand(&[b"ss-subkey"], &CHACHA20_POLY1305)
+            .expect("the key is short enough to expand");
+        Cipher {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            nonce: 0,
+        }
+    }
+}
+
+impl fmt::Debug for ObfsKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ObfsKey(..)")
+    }
+}
+
+/// The key of one direction, with the nonce it's used with next.
+struct Cipher {
+    key: LessSafeKey,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Appends the encrypted plaintext and its tag to `out`.
+    fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        let nonce = self.next_nonce();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), &mut out[start..])
+            .expect("a chunk is short enough to seal");
+        out.extend_from_slice(tag.as_ref());
+    }
+
+    /// Decrypts the ciphertext followed by its tag in place, returning the plaintext.
+    fn open<'a>(&mut self, sealed: &'a mut [u8]) -> IOResult<&'a mut [u8]> {
+        let nonce = self.next_nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), sealed)
+            .map_err(|_| invalid_data("bad obfuscated chunk"))
+    }
+}
+
+/// A stream encrypted with an [`ObfsKey`]. Writes are sealed into chunks that are sent on the
+/// next write, flush or shutdown if the inner stream can't take them at once.
+pub struct ObfsStream<S> {
+    inner: S,
+    key: ObfsKey,
+    /// The cipher of what's read, once its salt has been.
+    opener: Option<Cipher>,
+    /// What's read and not decrypted yet.
+    sealed: Vec<u8>,
+    /// The length of the payload of the chunk being read, once decrypted.
+    payload_len: Option<usize>,
+    /// The payload of the last chunk, of which `read` bytes have been read.
+    plain: Vec<u8>,
+    read: usize,
+    sealer: Cipher,
+    /// What's sealed, of which `written` bytes have been written, starting with the salt.
+    out: Vec<u8>,
+    written: usize,
+}
+
+impl<S> ObfsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Decrypts the next chunk into `plain` once it's read whole, returning whether it was.
+    fn open_chunk(&mut self) -> IOResult<bool> {
+        let opener = match &mut self.opener {
+            Some(opener) => opener,
+            None if self.sealed.len() < SALT_LEN => return Ok(false),
+            None => {
+                let salt: [u8; SALT_LEN] = self.sealed[..SALT_LEN].try_into().expect("a salt");
+                if !self.key.0.seen.lock().unwrap().insert(salt) {
+                    return Err(invalid_data("replayed obfuscated stream"));
+                }
+                let opener = self.key.cipher(&salt);
+                self.sealed.drain(..SALT_LEN);
+                self.opener.insert(opener)
+            }
+        };
+        let len = match self.payload_len {
+            Some(len) => len,
+            None if self.sealed.len() < 2 + TAG_LEN => return Ok(false),
+            None => {
+                let len = opener.open(&mut self.sealed[..2 + TAG_LEN])?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                if len == 0 || len > MAX_PAYLOAD {
+                    return Err(invalid_data("bad obfuscated chunk length"));
+                }
+                self.sealed.drain(..2 + TAG_LEN);
+                *self.payload_len.insert(len)
+            }
+        };
+        if self.sealed.len() < len + TAG_LEN {
+            return Ok(false);
+        }
+        let payload = opener.open(&mut self.sealed[..len + TAG_LEN])?;
+        self.plain.clear();
+        self.plain.extend_from_slice(payload);
+        self.read = 0;
+        self.sealed.drain(..len + TAG_LEN);
+        self.payload_len = None;
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ObfsStream<S> {
+    /// Writes what's sealed to the inner stream.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.read);
+                buf.put_slice(&this.plain[this.read..this.read + n]);
+                this.read += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.open_chunk()? {
+                continue;
+            }
+            let len = this.sealed.len();
+            this.sealed.resize(len + READ_CHUNK, 0);
+            let mut read = ReadBuf::new(&mut this.sealed[len..]);
+            let polled = Pin::new(&mut this.inner).poll_read(cx, &mut read);
+            let n = read.filled().len();
+            this.sealed.truncate(len + n);
+            ready!(polled)?;
+            if n == 0 {
+                return match len == 0 && this.payload_len.is_none() {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                };
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.sealer.seal(&(n as u16).to_be_bytes(), &mut this.out);
+        this.sealer.seal(&buf[..n], &mut this.out);
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for ObfsStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ObfsStream").field(&self.inner).finish()
+    }
+}
+
+/// Accepts the clients of an obfuscated link on a TCP listener, to be added to a
+/// [`ServerBuilder`](crate::ServerBuilder) as any other listener. Clients without the key are
+/// closed without a byte in reply.
+#[derive(Debug)]
+pub struct ObfsListener {
+    inner: TcpListener,
+    key: ObfsKey,
+}
+
+impl ObfsListener {
+    pub fn new(listener: TcpListener, key: ObfsKey) -> Self {
+        ObfsListener {
+            inner: listener,
+            key,
+        }
+    }
+}
+
+impl Accept for ObfsListener {
+    type Stream = ObfsStream<TcpStream>;
+
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<ObfsStream<TcpStream>>>> {
+        Box::pin(async {
+            let (stream, peer) = self.inner.accept().await?;
+            Ok(Incoming {
+                peer: Some(peer),
+                ..Incoming::new(self.key.wrap(stream)?)
+            })
+        })
+    }
+
+    fn local_addr(&self) -> IOResult<ListenAddr> {
+        self.inner.local_addr().map(ListenAddr::Inet)
+    }
+}
+
+/// Like [`serve`](crate::serve), but every client is decrypted with the key before the SOCKS
+/// handshake starts. Clients without the key are closed without a byte in reply.
+pub async fn serve_obfs(server: TcpListener, key: ObfsKey, config: Config) -> IOResult<()> {
+    Server::builder()
+        .config(config)
+        .listener(ObfsListener::new(server, key))
+        .start()?
+        .join()
+        .await
+}
+
+/// Relays every client of the listener to a remote [`serve_obfs`] with the same key, so that
+/// local SOCKS clients reach the remote instance through the obfuscated link.
+pub async fn serve_obfs_local<A>(server: TcpListener, remote: A, key: ObfsKey) -> IOResult<()>
+where
+    A: ToSocketAddrs + Clone + Send + 'static,
+{
+    loop {
+        let mut client = match server.accept().await {
+            Ok((client, _)) => client,
+            Err(_) => {
+                back_off().await;
+                continue;
+            }
+        };
+        let (remote, key) = (remote.clone(), key.clone());
+        tokio::spawn(async move {
+            let mut upstream = key.wrap(TcpStream::connect(remote).await?)?;
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+        });
+    }
+}
+
+fn invalid_data(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        constant::{CONNECT, IPV4, NO_AUTH, OK, RSV, VER},
+        test::AsyncExactRead,
+    };
+
+    #[tokio::test]
+    async fn decrypt_what_is_encrypted() {
+        let key = ObfsKey::new("secret");
+        let (client, server) = duplex(1024);
+        let (mut client, mut server) = (key.wrap(client).unwrap(), key.wrap(server).unwrap());
+        let data = (0..100_000).map(|it| it as u8).collect::<Vec<_>>();
+
+        let send = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let mut received = vec![];
+        let (_, read) = tokio::join!(send, server.read_to_end(&mut received));
+
+        assert_eq!(read.unwrap(), data.len());
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn hide_the_plaintext() {
+        let (client, mut server) = duplex(1024);
+        let mut client = ObfsKey::new("secret").wrap(client).unwrap();
+
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        drop(client);
+        let mut sent = vec![];
+        server.read_to_end(&mut sent).await.unwrap();
+
+        assert_eq!(sent.len(), SALT_LEN + 2 + TAG_LEN + 3 + TAG_LEN);
+        assert!(!sent.windows(3).any(|it| it == [VER, 1, NO_AUTH]));
+    }
+
+    #[tokio::test]
+    async fn fails_with_another_key() {
+        let (client, server) = duplex(1024);
+        let mut client = ObfsKey::new("secret").wrap(client).unwrap();
+        let mut server = ObfsKey::new("guess").wrap(server).unwrap();
+
+        client.write_all(b"ping").await.unwrap();
+        let err = server.read(&mut [0; 4]).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn refuse_replayed_streams() {
+        let key = ObfsKey::new("secret");
+        let (client, mut recorder) = duplex(1024);
+        let mut client = key.wrap(client).unwrap();
+        client.write_all(b"ping").await.unwrap();
+        drop(client);
+        let mut recorded = vec![];
+        recorder.read_to_end(&mut recorded).await.unwrap();
+
+        for replayed in [false, true] {
+            let (mut attacker, server) = duplex(1024);
+            let mut server = key.wrap(server).unwrap();
+            attacker.write_all(&recorded).await.unwrap();
+            match replayed {
+                false => assert_eq!(server.read_exact_bytes().await.unwrap(), *b"ping"),
+                true => {
+                    let err = server.read(&mut [0; 4]).await.unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn forget_the_oldest_salts() {
+        let mut seen = SeenSalts::default();
+        let salt = |n: usize| {
+            let mut salt = [0; SALT_LEN];
+            salt[..8].copy_from_slice(&n.to_le_bytes());
+            salt
+        };
+        assert!(seen.insert(salt(0)));
+        assert!(!seen.insert(salt(0)));
+        for n in 1..=SEEN_SALTS {
+            assert!(seen.insert(salt(n)));
+        }
+
+        assert_eq!(seen.order.len(), SEEN_SALTS);
+        assert!(seen.insert(salt(0)));
+        assert!(!seen.insert(salt(SEEN_SALTS)));
+    }
+
+    #[tokio::test]
+    async fn tunnel_from_local_to_remote() {
+        let key = ObfsKey::new("secret");
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(target) = upstream.local_addr().unwrap() else {
+            unreachable!()
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        tokio::spawn(serve_obfs(remote, key.clone(), Config::default()));
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = local.local_addr().unwrap();
+        tokio::spawn(serve_obfs_local(local, remote_addr, key));
+
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        client.write_all(&[VER, CONNECT, RSV, IPV4]).await.unwrap();
+        client.write_all(&target.ip().octets()).await.unwrap();
+        client
+            .write_all(&target.port().to_be_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read_exact_bytes::<10>().await.unwrap()[..2],
+            [VER, OK]
+        );
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+
+    #[tokio::test]
+    async fn close_clients_without_the_key_silently() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = remote.local_addr().unwrap();
+        tokio::spawn(serve_obfs(
+            remote,
+            ObfsKey::new("secret"),
+            Config::default(),
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[0x5a; 64]).await.unwrap();
+
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::IOResult;
+
+/// Bytes a user may relay in both directions, with no limit when `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+/// The traffic of one user, with days and months counted in UTC.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes relayed from the user to upstreams, ever.
+    pub sent: u64,
+    /// Bytes relayed from upstreams to the user, ever.
+    pub received: u64,
+    pub today: u64,
+    pub this_month: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Record {
+    usage: Usage,
+    day: u64,
+    month: u64,
+}
+
+impl Record {
+    /// Starts counting over once the day or month has passed.
+    fn at(&mut self, day: u64) -> &mut Usage {
+        if self.day != day {
+            self.day = day;
+            self.usage.today = 0;
+        }
+        if self.month != month_of(day) {
+            self.month = month_of(day);
+            self.usage.this_month = 0;
+        }
+        &mut self.usage
+    }
+}
+
+/// Totals the traffic of authenticated users and enforces their quotas, optionally keeping the
+/// totals in a file across restarts.
+#[derive(Debug, Default)]
+pub struct Accounting {
+    quota: Quota,
+    user_quotas: HashMap<String, Quota>,
+    records: Mutex<HashMap<String, Record>>,
+    store: Option<PathBuf>,
+    /// Held while the file is written, so saves replace it one at a time.
+    saving: Mutex<()>,
+    /// Whether a save is waiting to run in the background.
+    queued: AtomicBool,
+}
+
+impl Accounting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the totals from the file if it exists, and saves them there afterwards.
+    pub fn open<P: AsRef<Path>>(path: P) -> IOResult<Self> {
+        let path = path.as_ref();
+        let records = match fs::read_to_string(path) {
+            Ok(content) => parse(&content)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Accounting {
+            records: Mutex::new(records),
+            store: Some(path.into()),
+            ..Self::default()
+        })
+    }
+
+    /// The quota of every user without one of their own.
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    pub fn user_quota<S: Into<String>>(mut self, name: S, quota: Quota) -> Self {
+        self.user_quotas.insert(name.into(), quota);
+        self
+    }
+
+    pub fn usage(&self, name: &str) -> Option<Usage> {
+        let mut records = self.records.lock().unwrap();
+        records.get_mut(name).map(|it| *it.at(today()))
+    }
+
+    /// Writes the totals to the file given to [`open`](Self::open), if any. This happens on its
+    /// own whenever a session of an authenticated user ends.
+    pub fn save(&self) -> IOResult<()> {
+        let Some(path) = &self.store else {
+            return Ok(());
+        };
+        let _saving = self.saving.lock().unwrap();
+        let content = format(&self.records.lock().unwrap());
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Saves on the blocking pool, unless a save is already waiting to, which will see the
+    /// latest totals. A store that can't be written is left for the next save.
+    pub(crate) fn save_in_background(self: &Arc<Self>) {
+        if self.store.is_none() || self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let accounting = self.clone();
+        tokio::task::spawn_blocking(move || {
+            accounting.queued.store(false, Ordering::Release);
+            let _ = accounting.save();
+        });
+    }
+
+    /// Whether the user has no traffic left today or this month.
+    pub(crate) fn exceeded(&self, name: &str) -> bool {
+        self.exceeded_at(name, today())
+    }
+
+    pub(crate) fn record(&self, name: &str, sent: u64, received: u64) {
+        self.record_at(name, sent, received, today())
+    }
+
+    fn exceeded_at(&self, name: &str, day: u64) -> bool {
+        let quota = self.user_quotas.get(name).unwrap_or(&self.quota);
+        let mut records = self.records.lock().unwrap();
+        let Some(usage) = records.get_mut(name).map(|it| *it.at(day)) else {
+            return quota.daily == Some(0) || quota.monthly == Some(0);
+        };
+        quota.daily.is_some_and(|it| usage.today >= it)
+            || quota.monthly.is_some_and(|it| usage.this_month >= it)
+    }
+
+    fn record_at(&self, name: &str, sent: u64, received: u64, day: u64) {
+        let mut records = self.records.lock().unwrap();
+        let usage = records.entry(name.into()).or_default().at(day);
+        usage.sent += sent;
+        usage.received += received;
+        usage.today += sent + received;
+        usage.this_month += sent + received;
+    }
+}
+
+/// Days since the Unix epoch in UTC.
+fn today() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / 86400
+}
+
+/// Months since year 0 of the day since the Unix epoch.
+fn month_of(day: u64) -> u64 {
+    let (year, month, _) = civil_from_days(day);
+    year * 12 + month - 1
+}
+
+/// The year, month and day of month of the day since the Unix epoch, after the civil calendar
+/// algorithms of Howard Hinnant.
+pub(crate) fn civil_from_days(day: u64) -> (u64, u64, u64) {
+    let z = day + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    match mp < 10 {
+        true => (yoe + era * 400, mp + 3, day),
+        false => (yoe + era * 400 + 1, mp - 9, day),
+    }
+}
+
+/// One line per user: the escaped name, the day and month of the last traffic, then the bytes
+/// sent, received, today and this month, separated by tabs.
+fn format(records: &HashMap<String, Record>) -> String {
+    let mut content = String::new();
+    for (name, record) in records {
+        let Usage {
+            sent,
+            received,
+            today,
+            this_month,
+        } = record.usage;
+        let (day, month) = (record.day, record.month);
+        let _ = writeln!(
+            content,
+            "{}\t{day}\t{month}\t{sent}\t{received}\t{today}\t{this_month}",
+            escape(name)
+        );
+    }
+    content
+}
+
+fn parse(content: &str) -> IOResult<HashMap<String, Record>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid accounting store");
+    let mut records = HashMap::new();
+    for line in content.lines().filter(|it| !it.is_empty()) {
+        let mut fields = line.split('\t');
+        let name = unescape(fields.next().ok_or_else(invalid)?).ok_or_else(invalid)?;
+        let mut numbers = [0; 6];
+        for number in &mut numbers {
+            *number = fields
+                .next()
+                .and_then(|it| it.parse().ok())
+                .ok_or_else(invalid)?;
+        }
+        let [day, month, sent, received, today, this_month] = numbers;
+        let usage = Usage {
+            sent,
+            received,
+            today,
+            this_month,
+        };
+        records.insert(name, Record { usage, day, month });
+    }
+    Ok(records)
+}
+
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' | '\t' | '\n' | '\r' => {
+                let _ = write!(escaped, "%{:02X}", c as u8);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some((head, tail)) = rest.split_once('%') {
+        unescaped.push_str(head);
+        let code = u8::from_str_radix(tail.get(..2)?, 16).ok()?;
+        unescaped.push(code as char);
+        rest = &tail[2..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-02-29 and the days around it.
+    const LEAP_DAY: u64 = 19782;
+
+    #[test]
+    fn months_of_days() {
+        assert_eq!(month_of(0), 1970 * 12);
+        assert_eq!(month_of(LEAP_DAY), 2024 * 12 + 1);
+        assert_eq!(month_of(LEAP_DAY + 1), 2024 * 12 + 2);
+        assert_eq!(month_of(LEAP_DAY - 29), 2024 * 12);
+        assert_eq!(month_of(LEAP_DAY - 60), 2023 * 12 + 11);
+        assert_eq!(civil_from_days(LEAP_DAY), (2024, 2, 29));
+        assert_eq!(civil_from_days(LEAP_DAY + 1), (2024, 3, 1));
+    }
+
+    #[test]
+    fn count_per_day_and_month() {
+        let accounting = Accounting::new();
+        accounting.record_at("alice", 10, 20, LEAP_DAY - 1);
+        accounting.record_at("alice", 1, 2, LEAP_DAY);
+        let usage = |day| {
+            *accounting
+                .records
+                .lock()
+                .unwrap()
+                .get_mut("alice")
+                .unwrap()
+                .at(day)
+        };
+
+        assert_eq!(
+            usage(LEAP_DAY),
+            Usage {
+                sent: 11,
+                received: 22,
+                today: 3,
+                this_month: 33
+            }
+        );
+        assert_eq!(usage(LEAP_DAY + 1).today, 0);
+        assert_eq!(usage(LEAP_DAY + 1).this_month, 0);
+        assert_eq!(usage(LEAP_DAY + 1).sent, 11);
+    }
+
+    #[test]
+    fn enforce_quotas() {
+        let accounting = Accounting::new()
+            .quota(Quota {
+                daily: Some(100),
+                monthly: None,
+            })
+            .user_quota(
+                "bob",
+                Quota {
+                    daily: None,
+                    monthly: Some(150),
+                },
+            );
+        accounting.record_at("alice", 50, 50, LEAP_DAY);
+        accounting.record_at("bob", 50, 50, LEAP_DAY - 1);
+        accounting.record_at("bob", 25, 25, LEAP_DAY);
+
+        assert!(accounting.exceeded_at("alice", LEAP_DAY));
+        assert!(!accounting.exceeded_at("alice", LEAP_DAY + 1));
+        assert!(accounting.exceeded_at("bob", LEAP_DAY));
+        assert!(!accounting.exceeded_at("bob", LEAP_DAY + 1));
+        assert!(!accounting.exceeded_at("carol", LEAP_DAY));
+    }
+
+    #[test]
+    fn keep_totals_in_a_file() {
+        let path = std::env::temp_dir().join(format!("socks5-{}.accounting", std::process::id()));
+        let accounting = Accounting::open(&path).unwrap();
+        accounting.record("alice", 1, 2);
+        accounting.record("50%\tbob", 3, 4);
+        accounting.save().unwrap();
+
+        let reopened = Accounting::open(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(reopened.usage("alice"), accounting.usage("alice"));
+        assert_eq!(reopened.usage("50%\tbob").unwrap().received, 4);
+        assert!(parse("alice\t1\t2").is_err());
+    }
+
+    #[test]
+    fn save_concurrently() {
+        let path = std::env::temp_dir().join(format!("socks5-{}.concurrent", std::process::id()));
+        let accounting = Accounting::open(&path).unwrap();
+        for n in 0..100 {
+            accounting.record(&format!("user{n}"), n, n);
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        accounting.save().unwrap();
+                    }
+                });
+            }
+        });
+
+        let reopened = Accounting::open(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(reopened.usage("user99").unwrap().sent, 99);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use tokio::sync::watch;
+
+/// A snapshot of a running session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    pub user: Option<String>,
+    pub target: Option<SocketAddr>,
+    pub started: SystemTime,
+    /// Bytes relayed from the client to the upstream so far.
+    pub sent: u64,
+    /// Bytes relayed from the upstream to the client so far.
+    pub received: u64,
+    /// The stage the session is in, such as `negotiation` or `forward`.
+    pub stage: &'static str,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    peer: Option<SocketAddr>,
+    user: Option<String>,
+    target: Option<SocketAddr>,
+    stage: &'static str,
+}
+
+/// The live state of one session, shared between the session and the registry.
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    pub(crate) id: u64,
+    pub(crate) started: SystemTime,
+    progress: Mutex<Progress>,
+    pub(crate) sent: AtomicU64,
+    pub(crate) received: AtomicU64,
+    terminate: watch::Sender<bool>,
+}
+
+impl Tracker {
+    pub(crate) fn new(id: u64) -> Self {
+        Tracker {
+            id,
+            started: SystemTime::now(),
+            progress: Mutex::default(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            terminate: watch::Sender::new(false),
+        }
+    }
+
+    pub(crate) fn update(
+        &self,
+        peer: Option<SocketAddr>,
+        user: Option<&str>,
+        target: Option<SocketAddr>,
+        stage: &'static str,
+    ) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.peer = peer;
+        if progress.user.as_deref() != user {
+            progress.user = user.map(Into::into);
+        }
+        progress.target = target;
+        progress.stage = stage;
+    }
+
+    /// Resolves once the session is asked to terminate.
+    pub(crate) async fn terminated(&self) {
+        let mut terminate = self.terminate.subscribe();
+        let _ = terminate.wait_for(|&it| it).await;
+    }
+
+    fn info(&self) -> SessionInfo {
+        let progress = self.progress.lock().unwrap();
+        SessionInfo {
+            id: self.id,
+            peer: progress.peer,
+            user: progress.user.clone(),
+            target: progress.target,
+            started: self.started,
+            sent: self.sent.load(Relaxed),
+            received: self.received.load(Relaxed),
+            stage: progress.stage,
+        }
+    }
+}
+
+/// The running sessions of one server.
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    last_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<Tracker>>>,
+}
+
+impl Registry {
+    pub(crate) fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Relaxed) + 1
+    }
+
+    pub(crate) fn insert(&self, tracker: Arc<Tracker>) {
+        self.sessions.lock().unwrap().insert(tracker.id, tracker);
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    /// The running sessions, oldest first.
+    pub(crate) fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|it| it.info())
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|it| it.id);
+        sessions
+    }
+
+    pub(crate) fn terminate(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(tracker) => {
+                tracker.terminate.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminates every session of the user, returning how many there were.
+    pub(crate) fn terminate_user(&self, name: &str) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|it| it.progress.lock().unwrap().user.as_deref() == Some(name))
+            .inspect(|it| {
+                it.terminate.send_replace(true);
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn list_running_sessions() {
+        let registry = Registry::default();
+        let (first, second) = (registry.next_id(), registry.next_id());
+        let tracker = Arc::new(Tracker::new(second));
+        registry.insert(tracker.clone());
+        registry.insert(Arc::new(Tracker::new(first)));
+        tracker.update(None, Some("alice"), None, "forward");
+        tracker.sent.fetch_add(3, Relaxed);
+
+        let sessions = registry.list();
+
+        assert_eq!(sessions.iter().map(|it| it.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(sessions[1].user.as_deref(), Some("alice"));
+        assert_eq!((sessions[1].stage, sessions[1].sent), ("forward", 3));
+
+        registry.remove(first);
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn terminate_sessions() {
+        let registry = Registry::default();
+        let trackers = ["alice", "alice", "bob"].map(|user| {
+            let tracker = Arc::new(Tracker::new(registry.next_id()));
+            tracker.update(None, Some(user), None, "forward");
+            registry.insert(tracker.clone());
+            tracker
+        });
+
+        assert_eq!(registry.terminate_user("alice"), 2);
+        trackers[0].terminated().await;
+        trackers[1].terminated().await;
+
+        assert!(registry.terminate(3));
+        trackers[2].terminated().await;
+        assert!(!registry.terminate(4));
+    }
+}
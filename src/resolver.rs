@@ -0,0 +1,42 @@
+use std::{fmt, net::SocketAddr};
+
+use trust_dns_resolver::{
+    error::ResolveError,
+    name_server::{GenericConnector, TokioRuntimeProvider},
+    AsyncResolver, TokioAsyncResolver,
+};
+
+use crate::{BoxFuture, Result};
+
+lazy_static::lazy_static! {
+    static ref DNS_RESOLVER: AsyncResolver<GenericConnector<TokioRuntimeProvider>> = TokioAsyncResolver::tokio_from_system_conf().unwrap();
+}
+
+/// Resolves the domain names clients ask to connect to.
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> BoxFuture<'a, Result<SocketAddr>>;
+}
+
+impl fmt::Debug for dyn Resolve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolve")
+    }
+}
+
+/// Looks up IPv4 addresses with the system DNS configuration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> BoxFuture<'a, Result<SocketAddr>> {
+        Box::pin(async move {
+            let record = DNS_RESOLVER
+                .ipv4_lookup(domain)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| ResolveError::from("No record found"))?;
+            Ok(SocketAddr::from((record.0, port)))
+        })
+    }
+}
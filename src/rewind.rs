@@ -0,0 +1,90 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::marker::Stream;
+
+/// A client stream that can push bytes it has already read back in front of the stream, so a
+/// stage can look ahead and leave the bytes for the next stage to decode.
+#[derive(Debug)]
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(inner: S) -> Self {
+        Rewind {
+            prefix: vec![],
+            inner,
+        }
+    }
+
+    pub fn rewind(&mut self, bytes: &[u8]) {
+        self.prefix.splice(0..0, bytes.iter().copied());
+    }
+
+    /// The inner stream, once every rewound byte has been read.
+    pub(crate) fn drained(&mut self) -> Option<&mut S> {
+        self.prefix.is_empty().then_some(&mut self.inner)
+    }
+}
+
+impl<S: Stream> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Stream> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::Rewind;
+    use crate::test::AsyncExactRead;
+
+    #[tokio::test]
+    async fn reads_rewound_bytes_first() {
+        let (mut client, server) = duplex(100);
+        let mut it = Rewind::new(server);
+        client.write_all(&[1, 2, 3]).await.unwrap();
+
+        let first = it.read_u8().await.unwrap();
+        it.rewind(&[first]);
+        it.rewind(&[0]);
+
+        assert_eq!(it.read_exact_bytes().await.unwrap(), [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,694 @@
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    sync::Mutex,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::{watch, Semaphore},
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    auth::User, config::Config, hooks::Hooks, marker::Stream, registry::SessionInfo,
+    resolver::Resolve, stats::Stats, BoxFuture, IOResult, Socks5, Upstream,
+};
+#[cfg(unix)]
+use crate::{
+    systemd::{self, Activated},
+    unix::UnixListener,
+    upgrade,
+};
+
+/// How long a listener pauses after a failed accept.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A source of client connections, such as a [`TcpListener`].
+pub trait Accept: Send + Sync + 'static {
+    type Stream: Stream + 'static;
+
+    /// Waits for the next client. A failure is counted in [`Stats::accept_failures`] and retried
+    /// after a pause, so it only fails the client it was accepting.
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<Self::Stream>>>;
+
+    fn local_addr(&self) -> IOResult<ListenAddr>;
+
+    /// The listening socket, which lets a new process take the listener over on upgrade.
+    #[cfg(unix)]
+    fn as_fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+}
+
+/// An accepted client connection and what the listener knows about the client.
+#[derive(Debug)]
+pub struct Incoming<S> {
+    pub stream: S,
+    /// Checked against the trusted networks.
+    pub peer: Option<SocketAddr>,
+    /// An identity the listener established, which lets the client skip authentication.
+    pub user: Option<User>,
+    /// The destination a transparently proxied client was headed to, which it's connected to
+    /// without a handshake.
+    pub destination: Option<SocketAddr>,
+}
+
+impl<S> Incoming<S> {
+    pub fn new(stream: S) -> Self {
+        Incoming {
+            stream,
+            peer: None,
+            user: None,
+            destination: None,
+        }
+    }
+}
+
+/// The address a listener accepts clients on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ListenAddr {
+    Inet(SocketAddr),
+    /// The path of a Unix socket, if it has one.
+    Unix(Option<PathBuf>),
+    /// Described by a custom [`Accept`] implementation.
+    Other(String),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Inet(addr) => addr.fmt(f),
+            ListenAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Unix(None) => f.write_str("unix:(unnamed)"),
+            ListenAddr::Other(addr) => f.write_str(addr),
+        }
+    }
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<TcpStream>>> {
+        Box::pin(async {
+            let (stream, peer) = TcpListener::accept(self).await?;
+            Ok(Incoming {
+                peer: Some(peer),
+                ..Incoming::new(stream)
+            })
+        })
+    }
+
+    fn local_addr(&self) -> IOResult<ListenAddr> {
+        TcpListener::local_addr(self).map(ListenAddr::Inet)
+    }
+
+    #[cfg(unix)]
+    fn as_fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(AsFd::as_fd(self))
+    }
+}
+
+struct Listener {
+    addr: IOResult<ListenAddr>,
+    #[cfg(unix)]
+    fd: IOResult<Option<OwnedFd>>,
+    serve: Serve,
+}
+
+/// The settings of new sessions, which [`Control::reload`] replaces.
+type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+type Serve = Box<
+    dyn FnOnce(
+            SharedConfig,
+            Option<Arc<Semaphore>>,
+            watch::Receiver<bool>,
+        ) -> BoxFuture<'static, IOResult<()>>
+        + Send,
+>;
+
+/// Entry point of [`ServerBuilder`], connecting upstream over TCP.
+#[derive(Debug)]
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+/// Composes the listeners and settings of a server, which connects upstream with `U`.
+pub struct ServerBuilder<U = TcpStream> {
+    listeners: Vec<Listener>,
+    /// The process that handed its listeners over, waiting to be told they are taken.
+    #[cfg(unix)]
+    upgrading: Option<std::os::unix::net::UnixStream>,
+    config: Config,
+    runtime: Option<Handle>,
+    max_connections: Option<usize>,
+    upstream: PhantomData<fn() -> U>,
+}
+
+impl<U> ServerBuilder<U>
+where
+    U: for<'a> Upstream<'a, Output = BoxFuture<'a, IOResult<U>>> + Stream + 'static,
+{
+    pub fn new() -> Self {
+        ServerBuilder {
+            listeners: vec![],
+            #[cfg(unix)]
+            upgrading: None,
+            config: Config::default(),
+            runtime: None,
+            max_connections: None,
+            upstream: PhantomData,
+        }
+    }
+
+    /// Accepts clients from the listener, next to any listener added before.
+    pub fn listener<A: Accept>(mut self, listener: A) -> Self {
+        let addr = listener.local_addr();
+        #[cfg(unix)]
+        let fd = listener
+            .as_fd()
+            .map(|it| it.try_clone_to_owned())
+            .transpose();
+        let serve: Serve = Box::new(move |config, limit, shutdown| {
+            Box::pin(accept_loop::<A, U>(listener, config, limit, shutdown))
+        });
+        self.listeners.push(Listener {
+            addr,
+            #[cfg(unix)]
+            fd,
+            serve,
+        });
+        self
+    }
+
+    /// Replaces every setting of the sessions, including the hooks, resolver and handshake
+    /// timeout set on this builder before.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn hooks<H: Hooks + 'static>(mut self, hooks: H) -> Self {
+        self.config = self.config.hooks(hooks);
+        self
+    }
+
+    pub fn resolver<R: Resolve + 'static>(mut self, resolver: R) -> Self {
+        self.config = self.config.resolver(resolver);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config = self.config.handshake_timeout(timeout);
+        self
+    }
+
+    /// Stops accepting clients while this many sessions are running.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Accepts clients on the sockets systemd passed through `LISTEN_FDS`, if any.
+    #[cfg(unix)]
+    pub fn systemd_listeners(self) -> IOResult<Self> {
+        self.inherit(systemd::listeners()?)
+    }
+
+    /// Takes the listeners over from the process that started this one with
+    /// [`ServerHandle::upgrade`], if any. That process is told to stop accepting once the server
+    /// starts.
+    #[cfg(unix)]
+    pub fn upgraded_listeners(mut self) -> IOResult<Self> {
+        match upgrade::inherited()? {
+            Some((parent, listeners)) => {
+                self.upgrading = Some(parent);
+                self.inherit(listeners)
+            }
+            None => Ok(self),
+        }
+    }
+
+    #[cfg(unix)]
+    fn inherit(mut self, listeners: Vec<Activated>) -> IOResult<Self> {
+        let runtime = match &self.runtime {
+            Some(runtime) => runtime.clone(),
+            None => Handle::try_current().map_err(io::Error::other)?,
+        };
+        let _runtime = runtime.enter();
+        for listener in listeners {
+            self = match listener {
+                Activated::Tcp(listener) => self.listener(TcpListener::from_std(listener)?),
+                Activated::Unix(listener) => self.listener(UnixListener::from_std(listener)?),
+            };
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn has_listeners(&self) -> bool {
+        !self.listeners.is_empty()
+    }
+
+    /// Runs the server on the runtime, the current one by default.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Starts accepting clients on every listener.
+    pub fn start(self) -> IOResult<ServerHandle> {
+        let runtime = match self.runtime {
+            Some(runtime) => runtime,
+            None => Handle::try_current().map_err(io::Error::other)?,
+        };
+        let stats = Arc::new(Stats::default());
+        let config = Arc::new(RwLock::new(Arc::new(Config {
+            stats: stats.clone(),
+            registry: Arc::default(),
+            ..self.config
+        })));
+        let limit = self.max_connections.map(|it| Arc::new(Semaphore::new(it)));
+        let (shutdown, stopped) = watch::channel(false);
+        let mut local_addrs = vec![];
+        #[cfg(unix)]
+        let mut fds = vec![];
+        let mut serves = vec![];
+        for listener in self.listeners {
+            local_addrs.push(listener.addr?);
+            #[cfg(unix)]
+            fds.extend(listener.fd?);
+            serves.push(listener.serve);
+        }
+        let listeners = serves
+            .into_iter()
+            .map(|serve| runtime.spawn(serve(config.clone(), limit.clone(), stopped.clone())))
+            .collect();
+        #[cfg(unix)]
+        if let Some(parent) = self.upgrading {
+            upgrade::taken_over(parent)?;
+        }
+        Ok(ServerHandle {
+            config,
+            local_addrs,
+            stats,
+            shutdown,
+            listeners,
+            #[cfg(unix)]
+            fds: Mutex::new(fds),
+        })
+    }
+}
+
+impl<U> Default for ServerBuilder<U>
+where
+    U: for<'a> Upstream<'a, Output = BoxFuture<'a, IOResult<U>>> + Stream + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn accept_loop<A, U>(
+    listener: A,
+    config: SharedConfig,
+    limit: Option<Arc<Semaphore>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> IOResult<()>
+where
+    A: Accept,
+    U: for<'a> Upstream<'a, Output = BoxFuture<'a, IOResult<U>>> + Stream + 'static,
+{
+    // a dropped handle leaves the server running.
+    let mut stopped = Box::pin(async move {
+        if shutdown.wait_for(|&it| it).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    });
+    loop {
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.map_err(io::Error::other)?),
+                _ = &mut stopped => return Ok(()),
+            },
+            None => None,
+        };
+        let incoming = tokio::select! {
+            incoming = listener.accept() => incoming,
+            _ = &mut stopped => return Ok(()),
+        };
+        let incoming = match incoming {
+            Ok(incoming) => incoming,
+            Err(_) => {
+                let stats = config.read().unwrap().stats.clone();
+                tokio::select! {
+                    _ = accept_failed(&stats) => continue,
+                    _ = &mut stopped => return Ok(()),
+                }
+            }
+        };
+        let config = config.read().unwrap().clone();
+        let mut socks5 = Socks5::<U>::with_config(config);
+        socks5.session.peer = incoming.peer;
+        socks5.session.user = incoming.user;
+        if let Some(destination) = incoming.destination {
+            socks5 = socks5.transparent(destination);
+        }
+        tokio::spawn(async move {
+            let _permit = permit;
+            socks5.start(incoming.stream).await
+        });
+    }
+}
+
+/// Counts a failed accept and pauses the listener, as the failure, such as running out of file
+/// descriptors, usually lasts until some sessions end.
+pub(crate) async fn accept_failed(stats: &Stats) {
+    stats.accept_failed();
+    back_off().await;
+}
+
+/// Pauses a listener that failed to accept, for those that pass the error on.
+pub(crate) async fn back_off() {
+    sleep(ACCEPT_BACKOFF).await;
+}
+
+/// A running server. Dropping the handle leaves the server running.
+#[derive(Debug)]
+pub struct ServerHandle {
+    config: SharedConfig,
+    local_addrs: Vec<ListenAddr>,
+    stats: Arc<Stats>,
+    shutdown: watch::Sender<bool>,
+    listeners: Vec<JoinHandle<IOResult<()>>>,
+    /// Copies of the listening sockets to hand over on upgrade, closed on shutdown.
+    #[cfg(unix)]
+    fds: Mutex<Vec<OwnedFd>>,
+}
+
+impl ServerHandle {
+    /// The addresses of the listeners, in the order they were added.
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.local_addrs
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Inspects and steers the running server from elsewhere, such as an admin API.
+    pub fn control(&self) -> Control {
+        Control(self.config.clone())
+    }
+
+    /// Stops accepting clients and closes the listeners. Running sessions are left to finish.
+    pub fn shutdown(&self) {
+        #[cfg(unix)]
+        self.fds.lock().unwrap().clear();
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits for running sessions to finish, returning whether they did in time.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.stats.active() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    /// Starts a new process of the current executable with the same arguments and hands the
+    /// listeners over to it, returning its id once it accepts clients. This server should then
+    /// [`shutdown`](Self::shutdown) and [`drain`](Self::drain) its sessions.
+    #[cfg(unix)]
+    pub async fn upgrade(&self) -> IOResult<u32> {
+        let fds = self
+            .fds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(OwnedFd::try_clone)
+            .collect::<IOResult<Vec<_>>>()?;
+        upgrade::spawn(fds).await
+    }
+
+    /// Waits until every listener has stopped after [`shutdown`](Self::shutdown). Failed
+    /// accepts are retried and counted in [`Stats::accept_failures`], so this only fails with
+    /// the panic of a listener task.
+    pub async fn join(&mut self) -> IOResult<()> {
+        let mut result = Ok(());
+        while let Some(listener) = self.listeners.first_mut() {
+            let stopped = listener.await.map_err(io::Error::other).and_then(|it| it);
+            self.listeners.remove(0);
+            result = result.and(stopped);
+        }
+        result
+    }
+}
+
+/// Lists and terminates the sessions of a running server, and reloads its settings.
+#[derive(Debug, Clone)]
+pub struct Control(SharedConfig);
+
+impl Control {
+    /// The running sessions, oldest first.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.config().registry.list()
+    }
+
+    /// Ends the session, returning whether it was running.
+    pub fn terminate(&self, id: u64) -> bool {
+        self.config().registry.terminate(id)
+    }
+
+    /// Ends every session of the user, returning how many there were.
+    pub fn terminate_user(&self, name: &str) -> usize {
+        self.config().registry.terminate_user(name)
+    }
+
+    /// Replaces the settings of new sessions, while running sessions keep theirs.
+    pub fn reload(&self, config: Config) {
+        let mut current = self.0.write().unwrap();
+        *current = Arc::new(Config {
+            stats: current.stats.clone(),
+            registry: current.registry.clone(),
+            ..config
+        });
+    }
+
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        constant::{CONNECT, IPV4, NO_AUTH, OK, RSV, VER},
+        test::AsyncExactRead,
+    };
+
+    /// Hands out the server side of duplex streams, as an embedding application might.
+    struct Channel(Mutex<mpsc::Receiver<DuplexStream>>);
+
+    impl Accept for Channel {
+        type Stream = DuplexStream;
+
+        fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<DuplexStream>>> {
+            Box::pin(async {
+                match self.0.lock().await.recv().await {
+                    Some(stream) => Ok(Incoming::new(stream)),
+                    None => Err(io::ErrorKind::BrokenPipe.into()),
+                }
+            })
+        }
+
+        fn local_addr(&self) -> IOResult<ListenAddr> {
+            Ok(ListenAddr::Other("channel".into()))
+        }
+    }
+
+    /// Fails every other accept, as a listener out of file descriptors does.
+    struct Flaky(AtomicBool, Channel);
+
+    impl Accept for Flaky {
+        type Stream = DuplexStream;
+
+        fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<DuplexStream>>> {
+            match self.0.fetch_xor(true, Ordering::Relaxed) {
+                false => Box::pin(async { Err(io::Error::other("too many open files")) }),
+                true => self.1.accept(),
+            }
+        }
+
+        fn local_addr(&self) -> IOResult<ListenAddr> {
+            self.1.local_addr()
+        }
+    }
+
+    async fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await
+                });
+            }
+        });
+        addr
+    }
+
+    async fn handshake<S: Stream>(client: &mut S, target: SocketAddr) {
+        let SocketAddr::V4(target) = target else {
+            unreachable!()
+        };
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        client.write_all(&[VER, CONNECT, RSV, IPV4]).await.unwrap();
+        client.write_all(&target.ip().octets()).await.unwrap();
+        client
+            .write_all(&target.port().to_be_bytes())
+            .await
+            .unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        assert_eq!(
+            client.read_exact_bytes::<10>().await.unwrap()[..2],
+            [VER, OK]
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_tcp_and_custom_listeners() {
+        let target = echo().await;
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let (streams, receiver) = mpsc::channel(1);
+        let server = Server::builder()
+            .listener(tcp)
+            .listener(Channel(Mutex::new(receiver)))
+            .start()
+            .unwrap();
+        assert_eq!(
+            server.local_addrs(),
+            [ListenAddr::Inet(addr), ListenAddr::Other("channel".into())]
+        );
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        handshake(&mut client, target).await;
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+
+        let (mut client, stream) = duplex(1024);
+        streams.send(stream).await.unwrap();
+        handshake(&mut client, target).await;
+        client.write_all(b"pong").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"pong");
+
+        assert_eq!(server.stats().accepted(), 2);
+        assert_eq!(server.stats().active(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_accepting_after_failures() {
+        let target = echo().await;
+        let (streams, receiver) = mpsc::channel(1);
+        let listener = Flaky(AtomicBool::new(false), Channel(Mutex::new(receiver)));
+        let server = Server::builder().listener(listener).start().unwrap();
+
+        for _ in 0..2 {
+            let (mut client, stream) = duplex(1024);
+            streams.send(stream).await.unwrap();
+            handshake(&mut client, target).await;
+        }
+        assert_eq!(server.stats().accepted(), 2);
+        assert!(server.stats().accept_failures() >= 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_listeners() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let mut server = Server::builder().listener(tcp).start().unwrap();
+
+        server.shutdown();
+        server.join().await.unwrap();
+
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_sessions() {
+        let target = echo().await;
+        let (streams, receiver) = mpsc::channel(2);
+        let server = Server::builder()
+            .listener(Channel(Mutex::new(receiver)))
+            .max_connections(1)
+            .start()
+            .unwrap();
+        let (mut first, stream) = duplex(1024);
+        streams.send(stream).await.unwrap();
+        handshake(&mut first, target).await;
+
+        let (mut second, stream) = duplex(1024);
+        streams.send(stream).await.unwrap();
+        second.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), second.read_u8()).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        assert_eq!(second.read_u8().await.unwrap(), VER);
+        assert_eq!(server.stats().accepted(), 2);
+    }
+
+    #[tokio::test]
+    async fn close_stalled_handshakes() {
+        let (streams, receiver) = mpsc::channel(1);
+        let server = Server::builder()
+            .listener(Channel(Mutex::new(receiver)))
+            .handshake_timeout(Duration::from_millis(50))
+            .start()
+            .unwrap();
+        let (mut client, stream) = duplex(1024);
+        streams.send(stream).await.unwrap();
+
+        client.write_all(&[VER, 1]).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+
+        assert!(buf.is_empty());
+        assert_eq!(server.stats().failed(), 1);
+    }
+}
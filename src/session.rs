@@ -0,0 +1,193 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    access::AccessRules, auth::User, codec::Addr, config::Config, error::Error, hooks::Hooks,
+    quota::Accounting, registry::Tracker, Result,
+};
+
+/// What is known about one client connection as it moves through the stages.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub(crate) config: Arc<Config>,
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) user: Option<User>,
+    /// The USERID field of a SOCKS4 request.
+    pub(crate) userid: Option<String>,
+    /// The command of the request, such as `CONNECT` or the method of a plain HTTP request.
+    pub(crate) command: Option<String>,
+    /// The target as the client asked for it, before hooks rewrite or resolve it.
+    pub(crate) requested: Option<Addr>,
+    pub(crate) target: Option<SocketAddr>,
+    pub(crate) sent: u64,
+    pub(crate) received: u64,
+    pub(crate) tracker: Arc<Tracker>,
+}
+
+impl Session {
+    pub(crate) fn new(config: Arc<Config>) -> Self {
+        Session {
+            tracker: Arc::new(Tracker::new(config.registry.next_id())),
+            config,
+            peer: None,
+            user: None,
+            userid: None,
+            command: None,
+            requested: None,
+            target: None,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Identifies the session among those of the server, counting from 1.
+    pub fn id(&self) -> u64 {
+        self.tracker.id
+    }
+
+    /// The address the client connects from, if the listener knows it.
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// The authenticated user, set by authentication or up front by the listener.
+    pub fn user(&self) -> Option<&User> {
+        self.user.as_ref()
+    }
+
+    /// The USERID a SOCKS4 client sent, if not empty. Nothing verifies it, so it doesn't pick
+    /// the access rules or quota of a user, though hooks may still reject sessions by it.
+    pub fn userid(&self) -> Option<&str> {
+        self.userid.as_deref()
+    }
+
+    /// The upstream address once connected.
+    pub fn target(&self) -> Option<SocketAddr> {
+        self.target
+    }
+
+    /// Bytes relayed from the client to the upstream.
+    pub fn bytes_sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Bytes relayed from the upstream to the client.
+    pub fn bytes_received(&self) -> u64 {
+        self.received
+    }
+
+    /// Shows the progress of the session to the registry.
+    pub(crate) fn track(&self, stage: &'static str) {
+        let user = self.user.as_ref().map(|it| it.name.as_str());
+        self.tracker.update(self.peer, user, self.target, stage);
+    }
+
+    pub(crate) fn hooks(&self) -> &dyn Hooks {
+        &*self.config.hooks
+    }
+
+    /// Whether the client may skip authentication, either because it already has an identity or
+    /// because it connects from a trusted network.
+    pub(crate) fn trusted(&self) -> bool {
+        self.user.is_some()
+            || self.peer.is_some_and(|peer| {
+                let ip = peer.ip();
+                self.config.trusted.iter().any(|it| it.contains(ip))
+            })
+    }
+
+    fn access(&self) -> &AccessRules {
+        let config = &self.config;
+        match &self.user {
+            Some(user) => config
+                .user_access
+                .get(&user.name)
+                .or(config.authenticated_access.as_ref())
+                .unwrap_or(&config.access),
+            None => &config.access,
+        }
+    }
+
+    /// The accounting of the user and their name, if the user is accounted for.
+    pub(crate) fn accounting(&self) -> Option<(&Accounting, &str)> {
+        let accounting = self.config.accounting.as_deref()?;
+        Some((accounting, &self.user.as_ref()?.name))
+    }
+
+    /// Fails if the client has no traffic left.
+    pub(crate) fn check_quota(&self) -> Result<()> {
+        match self.accounting() {
+            Some((accounting, name)) if accounting.exceeded(name) => Err(Error::QuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the access rules of the client deny any destination.
+    pub(crate) fn restricted(&self) -> bool {
+        !self.access().permits_all()
+    }
+
+    /// Checks the destination against the access rules and the quota of the client.
+    pub(crate) fn permit(&self, addr: SocketAddr) -> Result<SocketAddr> {
+        self.check_quota()?;
+        match self.access().permits(addr.ip()) {
+            true => Ok(addr),
+            false => Err(Error::NotAllowed(addr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Session;
+    use crate::{access::AccessRules, config::Config, error::Error};
+
+    #[test]
+    fn trusts_clients_from_trusted_networks() {
+        let config = Config::default().trust("10.8.0.0/16".parse().unwrap());
+        let mut session = Session::new(Arc::new(config));
+        assert!(!session.trusted());
+
+        session.peer = Some("10.8.1.1:5000".parse().unwrap());
+        assert!(session.trusted());
+
+        session.peer = Some("10.9.1.1:5000".parse().unwrap());
+        assert!(!session.trusted());
+
+        session.user = Some("root".into());
+        assert!(session.trusted());
+    }
+
+    #[test]
+    fn authenticated_clients_use_their_own_rules() {
+        let config = Config::default()
+            .access(AccessRules::default().deny("10.0.0.0/8".parse().unwrap()))
+            .authenticated_access(AccessRules::default());
+        let mut session = Session::new(Arc::new(config));
+        let addr = "10.0.0.1:80".parse().unwrap();
+
+        assert!(matches!(session.permit(addr), Err(Error::NotAllowed(it)) if it == addr));
+
+        session.user = Some("root".into());
+        assert!(matches!(session.permit(addr), Ok(it) if it == addr));
+    }
+
+    #[test]
+    fn users_have_their_own_rules() {
+        let config = Config::default()
+            .authenticated_access(AccessRules::default())
+            .user_access(
+                "1000",
+                AccessRules::default().deny("10.0.0.0/8".parse().unwrap()),
+            );
+        let mut session = Session::new(Arc::new(config));
+        let addr = "10.0.0.1:80".parse().unwrap();
+
+        session.user = Some("0".into());
+        assert!(session.permit(addr).is_ok());
+
+        session.user = Some("1000".into());
+        assert!(matches!(session.permit(addr), Err(Error::NotAllowed(_))));
+    }
+}
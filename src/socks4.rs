@@ -0,0 +1,164 @@
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+};
+
+use crate::{
+    codec::{Command, Socks4Reply, Socks4Request},
+    connect::connect_upstream,
+    constant::REQUEST_GRANTED,
+    credential::PasswordVerifier,
+    error::Error::{self, *},
+    forward::Forward,
+    marker::Stream,
+    read_message,
+    session::Session,
+    write_message, IOResult, Result, Stage, Upstream,
+};
+
+/// Serves a SOCKS4 or SOCKS4a request. Only `CONNECT` is supported, `BIND` being rejected as
+/// SOCKS5 `BIND` is, and since SOCKS4 has no way to carry a password every request is rejected
+/// when a password is required. The USERID is kept as [`Session::userid`].
+#[derive(Debug)]
+pub struct Socks4(pub Option<Arc<dyn PasswordVerifier>>);
+
+impl Socks4 {
+    pub async fn run<'a, S: Stream, U>(
+        &mut self,
+        mut client: S,
+        session: &mut Session,
+    ) -> Result<Stage<U>>
+    where
+        U: Upstream<'a>,
+        U::Output: Future<Output = IOResult<U>>,
+    {
+        let Socks4Request {
+            command,
+            addr,
+            userid,
+        } = read_message(&mut client).await?;
+        if !userid.is_empty() {
+            session.userid = Some(String::from_utf8_lossy(&userid).into_owned());
+        }
+        if self.0.is_some() {
+            return Err(Error::BadCredential);
+        }
+        if command != Command::Connect {
+            return Err(BadCommand(command.into()));
+        }
+        session.command = Some("CONNECT".into());
+        session.hooks().after_auth(session).await?;
+        let upstream = connect_upstream(session, addr).await?;
+        let reply = Socks4Reply {
+            reply: REQUEST_GRANTED,
+            addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+        write_message(&mut client, &reply).await?;
+        Ok(Stage::Forward(Forward(upstream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{duplex, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::Socks4;
+    use crate::{
+        constant::{BIND, CONNECT, REQUEST_GRANTED, SOCKS4_REPLY_VER, SOCKS4_VER},
+        credential::Credential,
+        error::Error::*,
+        forward::{Forward, Remote},
+        session::Session,
+        test::AsyncExactRead,
+        Stage,
+    };
+
+    #[tokio::test]
+    async fn connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut client, mut server) = duplex(usize::MAX);
+        let mut socks4 = Socks4(None);
+        let mut session = Session::new(Default::default());
+        client.write_all(&[SOCKS4_VER, CONNECT]).await.unwrap();
+        client.write_all(&addr.port().to_be_bytes()).await.unwrap();
+        client.write_all(&[127, 0, 0, 1]).await.unwrap();
+        client.write_all(b"root\0").await.unwrap();
+
+        let forward = socks4
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await
+            .unwrap();
+        assert!(matches!(forward,
+                Stage::Forward(Forward(Remote::Direct(upstream))) if upstream.peer_addr().unwrap() == addr));
+        assert_eq!(session.userid(), Some("root"));
+
+        let response = client.read_exact_bytes::<8>().await.unwrap();
+        assert_eq!(
+            response,
+            [SOCKS4_REPLY_VER, REQUEST_GRANTED, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_socks4a_domain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut client, mut server) = duplex(usize::MAX);
+        let mut socks4 = Socks4(None);
+        let mut session = Session::new(Default::default());
+        client.write_all(&[SOCKS4_VER, CONNECT]).await.unwrap();
+        client.write_all(&addr.port().to_be_bytes()).await.unwrap();
+        client.write_all(&[0, 0, 0, 1]).await.unwrap();
+        client.write_all(b"\0localhost\0").await.unwrap();
+
+        let forward = socks4
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await
+            .unwrap();
+        assert!(matches!(forward,
+                Stage::Forward(Forward(Remote::Direct(upstream))) if upstream.peer_addr().unwrap() == addr));
+        assert_eq!(session.userid(), None);
+    }
+
+    #[tokio::test]
+    async fn fails_with_bind() {
+        let (mut client, mut server) = duplex(usize::MAX);
+        let mut socks4 = Socks4(None);
+        let mut session = Session::new(Default::default());
+        client
+            .write_all(&[SOCKS4_VER, BIND, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+
+        let err = socks4
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BadCommand(BIND)));
+    }
+
+    #[tokio::test]
+    async fn fails_if_credential_was_provided() {
+        let (mut client, mut server) = duplex(usize::MAX);
+        let mut socks4 = Socks4(Some(Arc::new(Credential::new("root", "pass"))));
+        let mut session = Session::new(Default::default());
+        client
+            .write_all(&[SOCKS4_VER, CONNECT, 0, 80, 127, 0, 0, 1])
+            .await
+            .unwrap();
+        client.write_all(b"root\0").await.unwrap();
+
+        let err = socks4
+            .run::<_, TcpStream>(&mut server, &mut session)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BadCredential));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+use crate::IOResult;
+
+/// Moves the bytes of one direction with `splice(2)` through a pipe of about the size, so the
+/// bytes never leave the kernel, counting them as they are read. Once the reading side ends,
+/// shuts down writing to the other if asked to.
+pub(crate) async fn pump(
+    from: &TcpStream,
+    to: &TcpStream,
+    counter: &AtomicU64,
+    size: usize,
+    propagate: bool,
+) -> IOResult<()> {
+    let (pipe_read, pipe_write) = pipe(size)?;
+    let capacity = capacity(&pipe_write)?;
+    loop {
+        let n = loop {
+            from.readable().await?;
+            match from.try_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe_write.as_raw_fd(), capacity)
+            }) {
+                Ok(n) => break n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        if n == 0 {
+            // SAFETY: shutdown only changes the state of the socket the stream owns.
+            if propagate && unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        }
+        counter.fetch_add(n as u64, Relaxed);
+        let mut pending = n;
+        while pending > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe_read.as_raw_fd(), to.as_raw_fd(), pending)
+            }) {
+                Ok(n) => pending -= n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> IOResult<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: null offsets make splice use and advance the file offsets of both descriptors.
+    let n = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    match n {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+/// A non-blocking pipe, as its reading and writing ends, resized towards the size. The kernel
+/// rounds the size up to whole pages, and keeps its default over the limit of unprivileged
+/// processes.
+fn pipe(size: usize) -> IOResult<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe2 writes two descriptors into the array, which are owned from then on, and
+    // fcntl only resizes the pipe.
+    unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let size = size.min(i32::MAX as usize) as libc::c_int;
+        libc::fcntl(fds[1], libc::F_SETPIPE_SZ, size);
+        Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])))
+    }
+}
+
+fn capacity(pipe: &OwnedFd) -> IOResult<usize> {
+    // SAFETY: fcntl only reads the size of the pipe.
+    match unsafe { libc::fcntl(pipe.as_raw_fd(), libc::F_GETPIPE_SZ) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(client, listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn splice_both_ways_until_shut_down() {
+        let (mut client, proxy_client) = pair().await;
+        let (proxy_upstream, mut upstream) = pair().await;
+        let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+        let data = (0..200_000).map(|it| it as u8).collect::<Vec<_>>();
+
+        let relay = async {
+            tokio::try_join!(
+                pump(&proxy_client, &proxy_upstream, &sent, 4096, true),
+                pump(&proxy_upstream, &proxy_client, &received, 4096, true)
+            )
+        };
+        let peers = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut relayed = vec![];
+            upstream.read_to_end(&mut relayed).await.unwrap();
+            assert_eq!(relayed, data);
+
+            upstream.write_all(b"pong").await.unwrap();
+            upstream.shutdown().await.unwrap();
+            let mut relayed = vec![];
+            client.read_to_end(&mut relayed).await.unwrap();
+            assert_eq!(relayed, b"pong");
+        };
+        let (relayed, ()) = tokio::join!(relay, peers);
+
+        relayed.unwrap();
+        assert_eq!(sent.load(Relaxed), data.len() as u64);
+        assert_eq!(received.load(Relaxed), 4);
+    }
+}
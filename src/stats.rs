@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use crate::session::Session;
+
+/// Counters of the sessions served with one [`Config`](crate::Config).
+#[derive(Debug, Default)]
+pub struct Stats {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    failed: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    accept_failures: AtomicU64,
+}
+
+impl Stats {
+    /// Sessions started so far.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Relaxed)
+    }
+
+    /// Sessions still running.
+    pub fn active(&self) -> u64 {
+        self.active.load(Relaxed)
+    }
+
+    /// Sessions that ended with an error.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Relaxed)
+    }
+
+    /// Bytes relayed from clients to upstreams by finished sessions.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Relaxed)
+    }
+
+    /// Bytes relayed from upstreams to clients by finished sessions.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Relaxed)
+    }
+
+    /// Clients the listeners failed to accept, as when running out of file descriptors.
+    pub fn accept_failures(&self) -> u64 {
+        self.accept_failures.load(Relaxed)
+    }
+
+    pub(crate) fn accept_failed(&self) {
+        self.accept_failures.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn open(&self) {
+        self.accepted.fetch_add(1, Relaxed);
+        self.active.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn close(&self, session: &Session, failed: bool) {
+        self.active.fetch_sub(1, Relaxed);
+        self.failed.fetch_add(failed as u64, Relaxed);
+        self.bytes_sent.fetch_add(session.sent, Relaxed);
+        self.bytes_received.fetch_add(session.received, Relaxed);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    env, io,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::{UnixDatagram, UnixListener},
+    },
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{server::ServerHandle, IOResult};
+
+/// How long sessions may keep running once the server stops.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+const LISTEN_FDS_START: i32 = 3;
+
+/// A listening socket passed by systemd or the process upgraded from.
+#[derive(Debug)]
+pub(crate) enum Activated {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes the listening sockets systemd passed, which are only taken once. The environment is
+/// left as is, as it can't be changed safely once other threads run.
+pub(crate) fn listeners() -> IOResult<Vec<Activated>> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(vec![]);
+    }
+    let count = listen_fds(env::var("LISTEN_PID").ok(), env::var("LISTEN_FDS").ok())?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count as i32)
+        // SAFETY: systemd hands these descriptors over to this process.
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .map(|fd| close_on_exec(&fd).and_then(|_| activate(fd)))
+        .collect()
+}
+
+/// Keeps the descriptor from leaking into child processes, as systemd passes it inheritable.
+pub(crate) fn close_on_exec(fd: &OwnedFd) -> IOResult<()> {
+    // SAFETY: fcntl only reads and sets the flags of the descriptor.
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn listen_fds(pid: Option<String>, fds: Option<String>) -> IOResult<usize> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid LISTEN_FDS");
+    match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == process::id().to_string() => {
+            fds.parse().map_err(|_| invalid())
+        }
+        _ => Ok(0),
+    }
+}
+
+pub(crate) fn activate(fd: OwnedFd) -> IOResult<Activated> {
+    let listener = TcpListener::from(fd);
+    if listener.local_addr().is_ok() {
+        listener.set_nonblocking(true)?;
+        return Ok(Activated::Tcp(listener));
+    }
+    let listener = UnixListener::from(OwnedFd::from(listener));
+    match listener.local_addr() {
+        Ok(_) => {
+            listener.set_nonblocking(true)?;
+            Ok(Activated::Unix(listener))
+        }
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a TCP or Unix socket",
+        )),
+    }
+}
+
+/// Sends the state, such as `READY=1`, to the service manager. Returns whether the process runs
+/// under one that expects notifications.
+pub fn notify(state: &str) -> IOResult<bool> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(socket) => notify_to(&socket, state).map(|_| true),
+        Err(_) => Ok(false),
+    }
+}
+
+fn notify_to(socket: &str, state: &str) -> IOResult<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+/// How often to ping the watchdog, half the timeout the service manager enforces.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog(
+        env::var("WATCHDOG_PID").ok(),
+        env::var("WATCHDOG_USEC").ok(),
+    )
+}
+
+fn watchdog(pid: Option<String>, usec: Option<String>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid != process::id().to_string()) {
+        return None;
+    }
+    let usec = usec?.parse().ok().filter(|&it| it > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Reports the server ready and pings the watchdog until `SIGTERM`, which reports stopping and
+/// shuts the server down. On `SIGUSR2` the server upgrades to a new process of the executable,
+/// which becomes the main process, and drains its sessions. An upgrade that fails leaves the
+/// server running.
+pub async fn supervise(server: &mut ServerHandle) -> IOResult<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut upgrade = signal(SignalKind::user_defined2())?;
+    notify("READY=1")?;
+    let watchdog = watchdog_interval().map(|interval| {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let _ = notify("WATCHDOG=1");
+            }
+        })
+    });
+    let result = loop {
+        tokio::select! {
+            result = server.join() => break result,
+            _ = terminate.recv() => {
+                notify("STOPPING=1")?;
+                break Ok(());
+            }
+            _ = upgrade.recv() => {
+                if let Ok(pid) = server.upgrade().await {
+                    notify(&format!("MAINPID={pid}"))?;
+                    break Ok(());
+                }
+            }
+        }
+    };
+    if let Some(watchdog) = watchdog {
+        watchdog.abort();
+    }
+    result?;
+    server.shutdown();
+    server.join().await?;
+    server.drain(DRAIN_TIMEOUT).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid() -> Option<String> {
+        Some(process::id().to_string())
+    }
+
+    #[test]
+    fn count_listen_fds_of_this_process() {
+        assert_eq!(listen_fds(pid(), Some("2".into())).unwrap(), 2);
+        assert_eq!(listen_fds(Some("1".into()), Some("2".into())).unwrap(), 0);
+        assert_eq!(listen_fds(None, None).unwrap(), 0);
+        assert!(listen_fds(pid(), Some("two".into())).is_err());
+    }
+
+    #[test]
+    fn close_passed_sockets_on_exec() {
+        let tcp = OwnedFd::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        // SAFETY: dup returns a new descriptor, as systemd passes them, without FD_CLOEXEC.
+        let passed = unsafe { OwnedFd::from_raw_fd(libc::dup(tcp.as_raw_fd())) };
+        let flags = || unsafe { libc::fcntl(passed.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags() & libc::FD_CLOEXEC, 0);
+
+        close_on_exec(&passed).unwrap();
+        assert_eq!(flags() & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+
+    #[test]
+    fn activate_listening_sockets() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let Activated::Tcp(tcp) = activate(tcp.into()).unwrap() else {
+            panic!("expect a TCP listener")
+        };
+        assert_eq!(tcp.local_addr().unwrap(), addr);
+
+        let path = env::temp_dir().join(format!("socks5-activate-{}.sock", process::id()));
+        let unix = UnixListener::bind(&path).unwrap();
+        assert!(matches!(activate(unix.into()).unwrap(), Activated::Unix(_)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn notify_socket() {
+        let path = env::temp_dir().join(format!("socks5-notify-{}.sock", process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn watchdog_at_half_the_timeout() {
+        assert_eq!(
+            watchdog(None, Some("10000000".into())),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog(pid(), Some("2000".into())),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(watchdog(Some("1".into()), Some("2000".into())), None);
+        assert_eq!(watchdog(None, Some("0".into())), None);
+        assert_eq!(watchdog(None, None), None);
+    }
+}
@@ -7,7 +7,7 @@ use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, ReadBuf};
 
 pub trait AsyncExactRead {
-    fn read_exact_bytes<const N: usize>(&mut self) -> ReadExactBytes<N, Self>
+    fn read_exact_bytes<const N: usize>(&mut self) -> ReadExactBytes<'_, N, Self>
     where
         Self: Unpin,
     {
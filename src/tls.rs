@@ -0,0 +1,425 @@
+//! SOCKS over TLS, so RFC 1929 credentials never cross the network in plaintext.
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex, Semaphore},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_rustls::{
+    client,
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor, TlsConnector,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    config::Config,
+    marker::Stream,
+    server::{back_off, Accept, Incoming, ListenAddr, Server},
+    BoxFuture, IOResult, User,
+};
+
+/// How long a client has to complete the TLS handshake by default.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The handshakes that run at once by default.
+const MAX_HANDSHAKES: usize = 1024;
+/// The clients that completed the handshake and wait to be served.
+const BACKLOG: usize = 128;
+
+/// Certificate and key files for a TLS listener, both PEM encoded.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    handshake_timeout: Duration,
+    max_handshakes: usize,
+}
+
+impl TlsConfig {
+    pub fn new<C, K>(cert: C, key: K) -> Self
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        TlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            max_handshakes: MAX_HANDSHAKES,
+        }
+    }
+
+    /// How long a [`TlsListener`] waits for a client to complete the TLS handshake, 10 seconds
+    /// by default.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// How many handshakes a [`TlsListener`] runs at once, 1024 by default. Further clients wait
+    /// to be accepted until one completes or times out.
+    pub fn max_handshakes(mut self, handshakes: usize) -> Self {
+        self.max_handshakes = handshakes.max(1);
+        self
+    }
+
+    /// Verifies client certificates against the CA bundle. A client presenting a valid
+    /// certificate is authenticated as the certificate's common name and skips username/password
+    /// authentication, while a client without one still has to authenticate with the credential.
+    pub fn client_auth<P: Into<PathBuf>>(mut self, ca: P) -> Self {
+        self.client_ca = Some(ca.into());
+        self
+    }
+
+    pub fn acceptor(&self) -> IOResult<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(invalid_input)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(invalid_input)?;
+        let builder = ServerConfig::builder();
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(ca).map_err(invalid_input)? {
+                    roots
+                        .add(cert.map_err(invalid_input)?)
+                        .map_err(invalid_input)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(roots.into())
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(invalid_input)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(invalid_input)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Accepts TLS clients on a TCP listener, to be added to a
+/// [`ServerBuilder`](crate::ServerBuilder) as any other listener. Handshakes run concurrently, up
+/// to [`TlsConfig::max_handshakes`], and those that don't complete in time are dropped. A client presenting a verified certificate is
+/// identified by its common name.
+pub struct TlsListener {
+    addr: SocketAddr,
+    clients: Mutex<mpsc::Receiver<IOResult<Incoming<TlsStream<TcpStream>>>>>,
+    handshakes: JoinHandle<()>,
+}
+
+impl TlsListener {
+    /// Starts accepting clients, which must be done within a runtime.
+    pub fn new(listener: TcpListener, tls: &TlsConfig) -> IOResult<Self> {
+        let addr = listener.local_addr()?;
+        let acceptor = tls.acceptor()?;
+        let handshake_timeout = tls.handshake_timeout;
+        let running = Arc::new(Semaphore::new(tls.max_handshakes));
+        let (clients, receiver) = mpsc::channel(BACKLOG);
+        let handshakes = tokio::spawn(async move {
+            loop {
+                let Ok(permit) = running.clone().acquire_owned().await else {
+                    return;
+                };
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // passed on to be counted by the server.
+                        let _ = clients.send(Err(err)).await;
+                        back_off().await;
+                        continue;
+                    }
+                };
+                let (acceptor, clients) = (acceptor.clone(), clients.clone());
+                tokio::spawn(async move {
+                    let handshake = timeout(handshake_timeout, acceptor.accept(stream)).await;
+                    drop(permit);
+                    let Ok(Ok(stream)) = handshake else {
+                        return;
+                    };
+                    let user = match stream.get_ref().1.peer_certificates() {
+                        Some([cert, ..]) => match common_name(cert) {
+                            Some(name) => Some(User::new(name)),
+                            None => return,
+                        },
+                        _ => None,
+                    };
+                    let incoming = Incoming {
+                        peer: Some(peer),
+                        user,
+                        ..Incoming::new(stream)
+                    };
+                    let _ = clients.send(Ok(incoming)).await;
+                });
+            }
+        });
+        Ok(TlsListener {
+            addr,
+            clients: Mutex::new(receiver),
+            handshakes,
+        })
+    }
+}
+
+impl Accept for TlsListener {
+    type Stream = TlsStream<TcpStream>;
+
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<TlsStream<TcpStream>>>> {
+        Box::pin(async {
+            let mut clients = self.clients.lock().await;
+            clients
+                .recv()
+                .await
+                .unwrap_or_else(|| Err(io::ErrorKind::NotConnected.into()))
+        })
+    }
+
+    fn local_addr(&self) -> IOResult<ListenAddr> {
+        Ok(ListenAddr::Inet(self.addr))
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        self.handshakes.abort();
+    }
+}
+
+/// Like [`serve`](crate::serve), but every client has to complete a TLS handshake before the
+/// SOCKS handshake starts on the decrypted stream.
+pub async fn serve_tls(server: TcpListener, tls: TlsConfig, config: Config) -> IOResult<()> {
+    Server::builder()
+        .config(config)
+        .listener(TlsListener::new(server, &tls)?)
+        .start()?
+        .join()
+        .await
+}
+
+/// The common name of a verified client certificate, or the whole subject if it has none.
+/// `None` if the certificate can't be parsed, in which case the client is dropped.
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let subject = cert.subject();
+    let cn = subject.iter_common_name().next().map(|cn| cn.as_str());
+    match cn {
+        Some(Ok(cn)) => Some(cn.to_owned()),
+        _ => Some(subject.to_string()),
+    }
+}
+
+/// Connects to servers over TLS, verifying their certificate for the server name against a PEM
+/// encoded CA bundle.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    tls: TlsConnector,
+    name: ServerName<'static>,
+}
+
+impl Connector {
+    pub(crate) fn new<P: AsRef<Path>>(ca: P, server_name: &str) -> IOResult<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca).map_err(invalid_input)? {
+            roots
+                .add(cert.map_err(invalid_input)?)
+                .map_err(invalid_input)?;
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Connector {
+            tls: TlsConnector::from(Arc::new(config)),
+            name: ServerName::try_from(server_name.to_owned()).map_err(invalid_input)?,
+        })
+    }
+
+    /// Verifies the server of a `host:port` address for its host.
+    #[cfg(any(feature = "ldap", feature = "webhook"))]
+    pub(crate) fn for_addr<P: AsRef<Path>>(ca: P, addr: &str) -> IOResult<Self> {
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        Self::new(ca, host.trim_matches(['[', ']']))
+    }
+
+    pub(crate) async fn connect<S: Stream>(&self, stream: S) -> IOResult<client::TlsStream<S>> {
+        self.tls.connect(self.name.clone(), stream).await
+    }
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Connector").field(&self.name).finish()
+    }
+}
+
+pub(crate) fn invalid_input<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        client::TlsStream,
+        rustls::{
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    use super::{serve_tls, TlsConfig};
+    use crate::{
+        config::Config,
+        constant::{CREDENTIAL_AUTH, CREDENTIAL_VERSION, NO_ACCEPTABLE_METHODS, NO_AUTH, OK, VER},
+        credential::Credential,
+        test::AsyncExactRead,
+    };
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+
+    fn tls_config() -> TlsConfig {
+        TlsConfig::new(
+            format!("{FIXTURES}/server.pem"),
+            format!("{FIXTURES}/server.key"),
+        )
+    }
+
+    async fn start(tls: TlsConfig, config: Config) -> SocketAddr {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(serve_tls(server, tls, config));
+        addr
+    }
+
+    async fn connect(addr: SocketAddr, client_cert: bool) -> TlsStream<TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(format!("{FIXTURES}/ca.pem")).unwrap())
+            .unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match client_cert {
+            true => builder
+                .with_client_auth_cert(
+                    vec![CertificateDer::from_pem_file(format!("{FIXTURES}/client.pem")).unwrap()],
+                    PrivateKeyDer::from_pem_file(format!("{FIXTURES}/client.key")).unwrap(),
+                )
+                .unwrap(),
+            false => builder.with_no_client_auth(),
+        };
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn credential_auth_over_tls() {
+        let config = Config::new(Some(Credential::new("root", "pass")));
+        let addr = start(tls_config(), config).await;
+        let mut client = connect(addr, false).await;
+
+        client.write_all(&[VER, 1, CREDENTIAL_AUTH]).await.unwrap();
+        assert_eq!(
+            client.read_exact_bytes().await.unwrap(),
+            [VER, CREDENTIAL_AUTH]
+        );
+        client.write_all(b"\x01\x04root\x04pass").await.unwrap();
+        assert_eq!(
+            client.read_exact_bytes().await.unwrap(),
+            [CREDENTIAL_VERSION, OK]
+        );
+    }
+
+    #[tokio::test]
+    async fn client_certificate_skips_credential_auth() {
+        let config = Config::new(Some(Credential::new("root", "pass")));
+        let tls = tls_config().client_auth(format!("{FIXTURES}/ca.pem"));
+        let addr = start(tls, config).await;
+        let mut client = connect(addr, true).await;
+
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+    }
+
+    #[tokio::test]
+    async fn fails_without_client_certificate_or_credential() {
+        let config = Config::new(Some(Credential::new("root", "pass")));
+        let tls = tls_config().client_auth(format!("{FIXTURES}/ca.pem"));
+        let addr = start(tls, config).await;
+        let mut client = connect(addr, false).await;
+
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(
+            client.read_exact_bytes().await.unwrap(),
+            [VER, NO_ACCEPTABLE_METHODS]
+        );
+    }
+
+    #[test]
+    fn common_name_of_client_certificate() {
+        let cert = CertificateDer::from_pem_file(format!("{FIXTURES}/client.pem")).unwrap();
+
+        assert_eq!(super::common_name(&cert).unwrap(), "alice");
+        assert!(super::common_name(&CertificateDer::from(vec![0x30, 0])).is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_clients_that_stall_the_handshake() {
+        let tls = tls_config().handshake_timeout(Duration::from_millis(50));
+        let addr = start(tls, Config::default()).await;
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+
+        let mut client = connect(addr, false).await;
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        assert_eq!(stalled.read(&mut [0]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_handshakes() {
+        let tls = tls_config()
+            .handshake_timeout(Duration::from_millis(200))
+            .max_handshakes(1);
+        let addr = start(tls, Config::default()).await;
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+
+        let started = std::time::Instant::now();
+        let mut client = connect(addr, false).await;
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn fails_with_missing_certificate() {
+        let result = TlsConfig::new("missing.pem", "missing.key").acceptor();
+
+        assert!(matches!(result, Err(err) if err.kind() == std::io::ErrorKind::InvalidInput));
+    }
+}
@@ -0,0 +1,261 @@
+//! Clients redirected to the proxy by the firewall, which know nothing of it. They're connected
+//! to the destination they were headed to as if they'd requested it, with neither a handshake
+//! nor a reply.
+use std::{future::Future, net::SocketAddr};
+#[cfg(target_os = "linux")]
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsRawFd, RawFd},
+};
+
+#[cfg(target_os = "linux")]
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::{
+    codec::Addr, connect::connect_upstream, constant::NO_AUTH, error::Error, forward::Forward,
+    session::Session, IOResult, Result, Stage, Upstream,
+};
+#[cfg(target_os = "linux")]
+use crate::{
+    server::{Accept, Incoming, ListenAddr},
+    BoxFuture,
+};
+
+/// Connects the session to the destination of the client, running the hooks and checking the
+/// access rules of a CONNECT request to it. The client can't authenticate, so unless it's
+/// trusted it's only let through by servers that don't require a password.
+#[derive(Debug)]
+pub struct Transparent(pub SocketAddr);
+
+impl Transparent {
+    pub async fn run<'a, U>(&mut self, session: &mut Session) -> Result<Stage<U>>
+    where
+        U: Upstream<'a>,
+        U::Output: Future<Output = IOResult<U>>,
+    {
+        session.hooks().on_accept(session).await?;
+        if !session.trusted() && session.config.auth_methods.select(&[NO_AUTH]).is_none() {
+            return Err(Error::NotAllowed(self.0));
+        }
+        session.hooks().after_auth(session).await?;
+        session.command = Some("CONNECT".into());
+        let upstream = connect_upstream(session, Addr::Ip(self.0)).await?;
+        Ok(Stage::Forward(Forward(upstream)))
+    }
+}
+
+/// A listener of the connections an iptables rule diverts to it, which are served as clients
+/// of their original destination.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct TransparentListener {
+    inner: TcpListener,
+    tproxy: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl TransparentListener {
+    /// Accepts the connections of a `REDIRECT` rule, such as
+    /// `iptables -t nat -A PREROUTING -p tcp -j REDIRECT --to-ports 1081`, whose destination
+    /// is recovered from the connection tracking of the kernel with `SO_ORIGINAL_DST`.
+    /// Connections made to the listener itself are dropped.
+    pub fn redirect(listener: TcpListener) -> Self {
+        TransparentListener {
+            inner: listener,
+            tproxy: false,
+        }
+    }
+
+    /// Binds the address for the connections of a `TPROXY` rule, such as
+    /// `iptables -t mangle -A PREROUTING -p tcp -j TPROXY --on-port 1081`, which are accepted
+    /// on their destination address. Needs `CAP_NET_ADMIN`. Connections made to the port of
+    /// the listener are dropped.
+    pub fn tproxy(addr: SocketAddr) -> IOResult<Self> {
+        let (socket, level, name) = match addr {
+            SocketAddr::V4(_) => (TcpSocket::new_v4()?, libc::SOL_IP, libc::IP_TRANSPARENT),
+            SocketAddr::V6(_) => (TcpSocket::new_v6()?, libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        };
+        let enabled: libc::c_int = 1;
+        // SAFETY: setsockopt only reads the int it's given the size of.
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                (&enabled as *const libc::c_int).cast(),
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        Ok(TransparentListener {
+            inner: socket.listen(1024)?,
+            tproxy: true,
+        })
+    }
+
+    /// The destination of the connection, unless it wasn't diverted and has no other
+    /// destination than the listener, which it would be relayed to over and over.
+    fn diverted(&self, stream: &TcpStream) -> Option<SocketAddr> {
+        let local = stream.local_addr().ok()?;
+        if !self.tproxy {
+            return original_dst(stream.as_raw_fd(), local)
+                .ok()
+                .filter(|it| *it != local);
+        }
+        let listening = self.inner.local_addr().ok()?;
+        let direct = local.port() == listening.port()
+            && (listening.ip().is_unspecified() || local.ip() == listening.ip());
+        (!direct).then_some(local)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Accept for TransparentListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<TcpStream>>> {
+        Box::pin(async {
+            loop {
+                let (stream, peer) = self.inner.accept().await?;
+                if let Some(destination) = self.diverted(&stream) {
+                    return Ok(Incoming {
+                        peer: Some(peer),
+                        destination: Some(destination),
+                        ..Incoming::new(stream)
+                    });
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> IOResult<ListenAddr> {
+        self.inner.local_addr().map(ListenAddr::Inet)
+    }
+}
+
+/// The destination of a connection before the `REDIRECT` rule rewrote it.
+#[cfg(target_os = "linux")]
+fn original_dst(fd: RawFd, local: SocketAddr) -> IOResult<SocketAddr> {
+    // SAFETY: getsockopt writes at most `len` bytes into the zeroed address, which any bytes
+    // are valid for.
+    unsafe {
+        match local {
+            SocketAddr::V4(_) => {
+                let mut addr: libc::sockaddr_in = mem::zeroed();
+                getsockopt(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST, &mut addr)?;
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+            }
+            SocketAddr::V6(_) => {
+                let mut addr: libc::sockaddr_in6 = mem::zeroed();
+                getsockopt(fd, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST, &mut addr)?;
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                let port = u16::from_be(addr.sin6_port);
+                Ok(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into())
+            }
+        }
+    }
+}
+
+/// # Safety
+///
+/// The option must be one that the kernel answers with a `T`.
+#[cfg(target_os = "linux")]
+unsafe fn getsockopt<T>(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &mut T,
+) -> IOResult<()> {
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    match libc::getsockopt(fd, level, name, (value as *mut T).cast(), &mut len) {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        access::AccessRules, config::Config, credential::Credential, test::AsyncExactRead, Socks5,
+    };
+
+    async fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        addr
+    }
+
+    /// Starts a session of the client redirected from the destination.
+    fn redirected(socks5: Socks5<TcpStream>, destination: SocketAddr) -> DuplexStream {
+        let (client, server) = duplex(1024);
+        tokio::spawn(socks5.transparent(destination).start(server));
+        client
+    }
+
+    #[tokio::test]
+    async fn connect_without_a_handshake() {
+        let socks5 = Socks5::with_config(Arc::new(Config::default()));
+        let mut client = redirected(socks5, echo().await);
+
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+
+    #[tokio::test]
+    async fn close_denied_clients_without_a_reply() {
+        let rules = AccessRules::default().deny("127.0.0.0/8".parse().unwrap());
+        let socks5 = Socks5::with_config(Arc::new(Config::default().access(rules)));
+        let mut client = redirected(socks5, echo().await);
+
+        assert_eq!(client.read(&mut [0]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn require_trusted_clients_when_passwords_are_required() {
+        let config = Config::new(Some(Credential::new("root", "pass")))
+            .trust("10.8.0.0/16".parse().unwrap());
+        let config = Arc::new(config);
+        let mut client = redirected(Socks5::with_config(config.clone()), echo().await);
+        assert_eq!(client.read(&mut [0]).await.unwrap(), 0);
+
+        let socks5 = Socks5::with_config(config).peer("10.8.1.1:5000".parse().unwrap());
+        let mut client = redirected(socks5, echo().await);
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn drop_connections_that_were_not_redirected() {
+        use super::TransparentListener;
+        use crate::server::Server;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = Server::builder()
+            .listener(TransparentListener::redirect(listener))
+            .start()
+            .unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert!(matches!(client.read(&mut [0]).await, Ok(0) | Err(_)));
+    }
+}
@@ -0,0 +1,314 @@
+//! Sessions connected through a remote instance, which resolves and connects to their targets,
+//! so that the clients of a local instance reach the networks of the remote one.
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::{fmt, sync::Arc};
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+#[cfg(feature = "tls")]
+use crate::tls::Connector;
+use crate::{
+    codec::{Addr, AuthRequest, AuthResponse, Command, Greeting, MethodSelection, Request},
+    constant::{CREDENTIAL_AUTH, GENERAL_FAILURE, NO_AUTH, OK, VER},
+    credential::Credential,
+    error::Error,
+    marker::Stream,
+    mux::{Multiplex, Pool},
+    read_message, write_message, BoxFuture, IOResult, Result,
+};
+
+/// Opens the upstream streams of sessions in place of connecting to their targets.
+pub trait Dial: Send + Sync {
+    fn dial<'a>(&'a self, target: &'a Addr) -> BoxFuture<'a, Result<Box<dyn Stream>>>;
+}
+
+impl fmt::Debug for dyn Dial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Dial")
+    }
+}
+
+/// A remote instance that sessions are connected through with a SOCKS5 request of their own,
+/// authenticated with the credential if one is set and optionally over TLS. The remote instance
+/// resolves domains and checks targets against its own access rules.
+#[derive(Clone)]
+pub struct Tunnel {
+    remote: String,
+    credential: Option<Credential>,
+    #[cfg(feature = "tls")]
+    tls: Option<Connector>,
+    /// Shared by the clones of the tunnel.
+    links: Option<Arc<Pool>>,
+}
+
+impl Tunnel {
+    /// Connects through the instance listening on `host:port`.
+    pub fn new<R: Into<String>>(remote: R) -> Self {
+        Tunnel {
+            remote: remote.into(),
+            credential: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            links: None,
+        }
+    }
+
+    pub fn credential(mut self, credential: Credential) -> Self {
+        self.credential = Some(credential);
+        self
+    }
+
+    /// Connects over TLS, verifying the certificate of the remote instance for the server name
+    /// against the PEM encoded CA bundle.
+    #[cfg(feature = "tls")]
+    pub fn tls<P: AsRef<Path>>(mut self, ca: P, server_name: &str) -> IOResult<Self> {
+        self.tls = Some(Connector::new(ca, server_name)?);
+        Ok(self)
+    }
+
+    /// Multiplexes the sessions over a few persistent links to the remote instance, which
+    /// accepts them with a [`MuxListener`](crate::MuxListener), instead of connecting each one.
+    pub fn multiplex(mut self, settings: Multiplex) -> Self {
+        self.links = Some(Arc::new(Pool::new(settings)));
+        self
+    }
+
+    async fn connect(&self) -> IOResult<Box<dyn Stream>> {
+        let stream = TcpStream::connect(&self.remote).await?;
+        #[cfg(feature = "tls")]
+        if let Some(connector) = &self.tls {
+            return Ok(Box::new(connector.connect(stream).await?));
+        }
+        Ok(Box::new(stream))
+    }
+
+    /// Asks the remote instance to connect to the target, returning the stream relayed to it.
+    async fn request<S: Stream + 'static>(
+        &self,
+        mut stream: S,
+        target: &Addr,
+    ) -> Result<Box<dyn Stream>> {
+        let method = match self.credential {
+            Some(_) => CREDENTIAL_AUTH,
+            None => NO_AUTH,
+        };
+        let greeting = Greeting {
+            methods: vec![method],
+        };
+        write_message(&mut stream, &greeting).await?;
+        let selection: MethodSelection = read_message(&mut stream).await?;
+        if selection.method != method {
+            return Err(Error::Rejected(GENERAL_FAILURE));
+        }
+        if let Some(credential) = &self.credential {
+            let request = AuthRequest {
+                username: credential.username.as_bytes().to_vec(),
+                password: credential.password.as_bytes().to_vec(),
+            };
+            write_message(&mut stream, &request).await?;
+            let response: AuthResponse = read_message(&mut stream).await?;
+            if response.status != OK {
+                return Err(Error::Rejected(GENERAL_FAILURE));
+            }
+        }
+        let request = Request {
+            command: Command::Connect,
+            addr: target.clone(),
+        };
+        write_message(&mut stream, &request).await?;
+        // some servers answer failures with the version and the reply code alone.
+        let mut head = [0; 3];
+        stream.read_exact(&mut head[..2]).await?;
+        match head {
+            [VER, OK, _] => {
+                stream.read_exact(&mut head[2..]).await?;
+                let _: Addr = read_message(&mut stream).await?;
+                Ok(Box::new(stream))
+            }
+            [VER, reply, _] => Err(Error::Rejected(reply)),
+            [version, ..] => Err(Error::BadVersion(version)),
+        }
+    }
+}
+
+impl Dial for Tunnel {
+    fn dial<'a>(&'a self, target: &'a Addr) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            if let Some(links) = &self.links {
+                let stream = links.open(|| self.connect()).await?;
+                return self.request(stream, target).await;
+            }
+            self.request(self.connect().await?, target).await
+        })
+    }
+}
+
+impl fmt::Debug for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tunnel = f.debug_struct("Tunnel");
+        tunnel.field("remote", &self.remote);
+        #[cfg(feature = "tls")]
+        tunnel.field("tls", &self.tls);
+        tunnel.field("multiplexed", &self.links.is_some());
+        tunnel.finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::Tunnel;
+    use crate::{
+        access::AccessRules,
+        config::Config,
+        constant::{
+            CONNECT, CONNECTION_NOT_ALLOWED, DOMAIN_NAME, GENERAL_FAILURE, IPV4, NO_AUTH, OK, RSV,
+            VER,
+        },
+        credential::Credential,
+        mux::{Multiplex, MuxListener},
+        resolver::Resolve,
+        server::Server,
+        test::AsyncExactRead,
+        BoxFuture, Result,
+    };
+
+    /// Resolves every domain to loopback.
+    struct Loopback;
+
+    impl Resolve for Loopback {
+        fn resolve<'a>(&'a self, _: &'a str, port: u16) -> BoxFuture<'a, Result<SocketAddr>> {
+            Box::pin(async move { Ok(SocketAddr::from(([127, 0, 0, 1], port))) })
+        }
+    }
+
+    async fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        addr
+    }
+
+    async fn serve(config: Config) -> SocketAddr {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(crate::serve(server, config));
+        addr
+    }
+
+    /// Connects to the domain through the local instance, returning the reply code.
+    async fn connect(local: SocketAddr, domain: &str, port: u16) -> (TcpStream, u8) {
+        let mut client = TcpStream::connect(local).await.unwrap();
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        let mut request = vec![VER, CONNECT, RSV, DOMAIN_NAME, domain.len() as u8];
+        request.extend(domain.as_bytes());
+        request.extend(port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let [_, reply] = client.read_exact_bytes().await.unwrap();
+        (client, reply)
+    }
+
+    #[tokio::test]
+    async fn connect_through_the_remote_instance() {
+        let target = echo().await;
+        let credential = Credential::new("root", "pass");
+        let remote = serve(Config::new(Some(credential.clone())).resolver(Loopback)).await;
+        let tunnel = Tunnel::new(remote.to_string()).credential(credential);
+        let local = serve(Config::default().dial(tunnel)).await;
+
+        // only the remote instance resolves the domain.
+        let (mut client, reply) = connect(local, "office.test", target.port()).await;
+        assert_eq!(reply, OK);
+        assert_eq!(
+            client.read_exact_bytes::<8>().await.unwrap()[..2],
+            [RSV, IPV4]
+        );
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+
+    #[tokio::test]
+    async fn reply_what_the_remote_instance_replies() {
+        let rules = AccessRules::default().deny("127.0.0.0/8".parse().unwrap());
+        let remote = serve(Config::default().resolver(Loopback).access(rules)).await;
+        let local = serve(Config::default().dial(Tunnel::new(remote.to_string()))).await;
+
+        let (_, reply) = connect(local, "office.test", 80).await;
+        assert_eq!(reply, CONNECTION_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn fails_with_bad_credential() {
+        let remote = serve(Config::new(Some(Credential::new("root", "pass")))).await;
+        let tunnel = Tunnel::new(remote.to_string()).credential(Credential::new("root", "bad"));
+        let local = serve(Config::default().dial(tunnel)).await;
+
+        let (_, reply) = connect(local, "office.test", 80).await;
+        assert_eq!(reply, GENERAL_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn connect_over_multiplexed_links() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = listener.local_addr().unwrap();
+        let listener = MuxListener::new(listener, Multiplex::default()).unwrap();
+        let _server = Server::builder()
+            .config(Config::default().resolver(Loopback))
+            .listener(listener)
+            .start()
+            .unwrap();
+        let tunnel = Tunnel::new(remote.to_string()).multiplex(Multiplex {
+            links: 1,
+            ..Multiplex::default()
+        });
+        let local = serve(Config::default().dial(tunnel)).await;
+
+        let (mut first, reply) = connect(local, "office.test", echo().await.port()).await;
+        assert_eq!(reply, OK);
+        let (mut second, reply) = connect(local, "office.test", echo().await.port()).await;
+        assert_eq!(reply, OK);
+        for client in [&mut first, &mut second] {
+            client.read_exact_bytes::<8>().await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn connect_over_tls() {
+        use crate::tls::{serve_tls, TlsConfig};
+
+        const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+        let target = echo().await;
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = server.local_addr().unwrap();
+        let tls = TlsConfig::new(
+            format!("{FIXTURES}/server.pem"),
+            format!("{FIXTURES}/server.key"),
+        );
+        tokio::spawn(serve_tls(server, tls, Config::default().resolver(Loopback)));
+        let tunnel = Tunnel::new(remote.to_string())
+            .tls(format!("{FIXTURES}/ca.pem"), "localhost")
+            .unwrap();
+        let local = serve(Config::default().dial(tunnel)).await;
+
+        let (mut client, reply) = connect(local, "office.test", target.port()).await;
+        assert_eq!(reply, OK);
+        client.read_exact_bytes::<8>().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+}
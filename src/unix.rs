@@ -0,0 +1,268 @@
+use std::{
+    fs, io,
+    os::fd::{AsFd, BorrowedFd},
+    os::unix::{
+        fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::net::UnixStream;
+
+use crate::{
+    auth::User,
+    server::{Accept, Incoming, ListenAddr},
+    BoxFuture, IOResult,
+};
+
+/// A listener on a Unix domain socket, either a file or a name in the Linux abstract namespace.
+#[derive(Debug)]
+pub struct UnixListener {
+    inner: tokio::net::UnixListener,
+    /// The socket file, which the socket was moved to after being bound.
+    path: Option<PathBuf>,
+    peer_identity: bool,
+}
+
+impl UnixListener {
+    /// Binds the socket file, replacing a stale one no server listens on anymore. The file gets
+    /// the `0o600` mode, so only its owner may connect until
+    /// [`set_mode`](UnixListener::set_mode) lets others in.
+    pub fn bind<P: AsRef<Path>>(path: P) -> IOResult<Self> {
+        let path = path.as_ref();
+        remove_stale(path)?;
+        let listener = bind_private(path)?;
+        Ok(UnixListener {
+            path: Some(path.into()),
+            ..Self::from_std(listener)?
+        })
+    }
+
+    /// Binds the name in the abstract namespace, which has no file and no permissions.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_abstract<N: AsRef<[u8]>>(name: N) -> IOResult<Self> {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        Self::from_std(std::os::unix::net::UnixListener::bind_addr(&addr)?)
+    }
+
+    pub(crate) fn from_std(listener: std::os::unix::net::UnixListener) -> IOResult<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self::new(tokio::net::UnixListener::from_std(listener)?))
+    }
+
+    fn new(inner: tokio::net::UnixListener) -> Self {
+        UnixListener {
+            inner,
+            path: None,
+            peer_identity: false,
+        }
+    }
+
+    /// Sets the permission bits of the socket file, such as `0o660`, which decide who may connect.
+    pub fn set_mode(&self, mode: u32) -> IOResult<()> {
+        fs::set_permissions(self.path()?, fs::Permissions::from_mode(mode))
+    }
+
+    /// Changes the owner and group of the socket file, leaving either as is when `None`.
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> IOResult<()> {
+        chown(self.path()?, uid, gid)
+    }
+
+    /// Identifies every client by the credentials of its process, as a [`User`] named after the
+    /// uid with the `uid`, `gid` and `pid` attributes. Such clients skip authentication and get
+    /// the access rules of their uid.
+    pub fn peer_identity(mut self, enabled: bool) -> Self {
+        self.peer_identity = enabled;
+        self
+    }
+
+    fn path(&self) -> IOResult<PathBuf> {
+        self.pathname()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the socket has no file"))
+    }
+
+    fn pathname(&self) -> IOResult<Option<PathBuf>> {
+        if let Some(path) = &self.path {
+            return Ok(Some(path.clone()));
+        }
+        let addr = self.inner.local_addr()?;
+        Ok(addr.as_pathname().map(Into::into))
+    }
+}
+
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> BoxFuture<'_, IOResult<Incoming<UnixStream>>> {
+        Box::pin(async {
+            loop {
+                let (stream, _) = self.inner.accept().await?;
+                let user = match self.peer_identity {
+                    true => match peer_user(&stream) {
+                        Ok(user) => Some(user),
+                        // a client that can't be identified is dropped rather than served
+                        // anonymously.
+                        Err(_) => continue,
+                    },
+                    false => None,
+                };
+                return Ok(Incoming {
+                    user,
+                    ..Incoming::new(stream)
+                });
+            }
+        })
+    }
+
+    fn local_addr(&self) -> IOResult<ListenAddr> {
+        Ok(ListenAddr::Unix(self.pathname()?))
+    }
+
+    fn as_fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.inner.as_fd())
+    }
+}
+
+fn peer_user(stream: &UnixStream) -> IOResult<User> {
+    let credential = stream.peer_cred()?;
+    let mut user = User::new(credential.uid().to_string());
+    user.attributes
+        .insert("uid".into(), credential.uid().to_string());
+    user.attributes
+        .insert("gid".into(), credential.gid().to_string());
+    if let Some(pid) = credential.pid() {
+        user.attributes.insert("pid".into(), pid.to_string());
+    }
+    Ok(user)
+}
+
+/// Binds the socket in a directory only the process may enter, next to the path, and moves it
+/// there once its mode is `0o600`, so that no one else may connect in between.
+fn bind_private(path: &Path) -> IOResult<StdUnixListener> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path has no file name"))?;
+    let mut private = name.to_owned();
+    private.push(format!(
+        ".{}.{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let dir = path.with_file_name(private);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = (|| {
+        let socket = dir.join(name);
+        let listener = StdUnixListener::bind(&socket)?;
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&socket, path)?;
+        Ok(listener)
+    })();
+    if bound.is_err() {
+        let _ = fs::remove_file(dir.join(name));
+    }
+    fs::remove_dir(&dir)?;
+    bound
+}
+
+fn remove_stale(path: &Path) -> IOResult<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match StdUnixStream::connect(path) {
+            Ok(_) => Err(io::ErrorKind::AddrInUse.into()),
+            Err(_) => fs::remove_file(path),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::fs::MetadataExt,
+        sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    };
+
+    use super::*;
+
+    fn socket_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "socks5-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Relaxed)
+        ))
+    }
+
+    #[tokio::test]
+    async fn bind_socket_file() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        // the directory it was bound in is gone.
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let leftovers = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter(|it| {
+                let entry = it.as_ref().unwrap().file_name();
+                entry.to_str().unwrap().starts_with(&format!("{name}."))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        listener.set_mode(0o660).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        listener.set_owner(None, Some(metadata.gid())).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o660);
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            ListenAddr::Unix(Some(path.clone()))
+        );
+        assert_eq!(
+            UnixListener::bind(&path).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        drop(listener);
+        UnixListener::bind(&path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn identify_peers_by_credentials() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap().peer_identity(true);
+        let uid = fs::metadata(&path).unwrap().uid().to_string();
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let user = listener.accept().await.unwrap().user.unwrap();
+
+        assert_eq!(user.name, uid);
+        assert_eq!(user.attributes["uid"], uid);
+        assert_eq!(user.attributes["pid"], std::process::id().to_string());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn bind_abstract_name() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = socket_path().display().to_string();
+        let listener = UnixListener::bind_abstract(&name).unwrap();
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+
+        let _client = StdUnixStream::connect_addr(&addr).unwrap();
+        let incoming = listener.accept().await.unwrap();
+
+        assert!(incoming.user.is_none());
+        assert_eq!(listener.local_addr().unwrap(), ListenAddr::Unix(None));
+        assert!(listener.set_mode(0o600).is_err());
+    }
+}
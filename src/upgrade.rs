@@ -0,0 +1,238 @@
+use std::{
+    env,
+    io::{self, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process::Command,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, time::timeout};
+
+use crate::{
+    systemd::{activate, Activated},
+    IOResult,
+};
+
+/// Names the descriptor of the socket a new process receives the listeners over.
+const UPGRADE_FD: &str = "SOCKS5_UPGRADE_FD";
+/// The most listeners handed over at once.
+const MAX_FDS: usize = 64;
+/// How long the new process has to start accepting clients.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts the current executable again and hands the listeners over, returning the id of the
+/// new process once it accepts clients.
+pub(crate) async fn spawn(fds: Vec<OwnedFd>) -> IOResult<u32> {
+    let (parent, child) = UnixStream::pair()?;
+    let fd = child.as_raw_fd();
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(UPGRADE_FD, fd.to_string())
+        // the sockets systemd passed this process are handed over as upgraded ones.
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES");
+    // SAFETY: only calls fcntl, which is async-signal-safe.
+    unsafe { command.pre_exec(move || inheritable(fd)) };
+    let mut process = command.spawn()?;
+    drop(child);
+
+    send_fds(&parent, &fds)?;
+    drop(fds);
+    parent.set_nonblocking(true)?;
+    let mut parent = tokio::net::UnixStream::from_std(parent)?;
+    let started = match timeout(UPGRADE_TIMEOUT, parent.read_u8()).await {
+        Ok(started) => started.map(|_| ()),
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    };
+    match started {
+        Ok(_) => Ok(process.id()),
+        Err(err) => {
+            let _ = process.kill();
+            tokio::task::spawn_blocking(move || process.wait());
+            Err(err)
+        }
+    }
+}
+
+/// Receives the listeners from the process that started this one to upgrade, if any, along with
+/// the socket to tell it once they are taken. They're only received once.
+pub(crate) fn inherited() -> IOResult<Option<(UnixStream, Vec<Activated>)>> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    let Some(fd) = env::var_os(UPGRADE_FD) else {
+        return Ok(None);
+    };
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    let fd = fd
+        .to_str()
+        .and_then(|it| it.parse::<RawFd>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid SOCKS5_UPGRADE_FD"))?;
+    // SAFETY: the parent process hands the descriptor over to this process.
+    let parent = unsafe { UnixStream::from_raw_fd(fd) };
+    let listeners = recv_fds(&parent)?
+        .into_iter()
+        .map(activate)
+        .collect::<IOResult<_>>()?;
+    Ok(Some((parent, listeners)))
+}
+
+/// Tells the process upgraded from that this one accepts clients.
+pub(crate) fn taken_over(mut parent: UnixStream) -> IOResult<()> {
+    parent.write_all(&[1])
+}
+
+fn inheritable(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl only reads and sets the flags of the descriptor.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Sends the descriptors as `SCM_RIGHTS` along with their count.
+fn send_fds(socket: &UnixStream, fds: &[OwnedFd]) -> IOResult<()> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many listeners",
+        ));
+    }
+    let raw = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+    let count = (fds.len() as u32).to_be_bytes();
+    let mut iov = libc::iovec {
+        iov_base: count.as_ptr() as *mut _,
+        iov_len: count.len(),
+    };
+    let len = mem::size_of_val(raw.as_slice()) as u32;
+    // SAFETY: the control buffer is sized by CMSG_SPACE for exactly one header and the descriptors.
+    unsafe {
+        let mut control = vec![0u8; libc::CMSG_SPACE(len) as usize];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !raw.is_empty() {
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = control.len() as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+            ptr::copy_nonoverlapping(raw.as_ptr(), libc::CMSG_DATA(cmsg).cast(), raw.len());
+        }
+        if libc::sendmsg(socket.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receives the descriptors closed on exec, which macOS can only do once they're received.
+#[cfg(not(target_vendor = "apple"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(target_vendor = "apple")]
+const RECV_FLAGS: libc::c_int = 0;
+
+fn recv_fds(socket: &UnixStream) -> IOResult<Vec<OwnedFd>> {
+    let mut count = [0u8; 4];
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr().cast(),
+        iov_len: count.len(),
+    };
+    let mut fds = vec![];
+    // SAFETY: the kernel fills at most `msg_controllen` bytes of control messages, which are only
+    // read through the CMSG macros and hold descriptors now owned by this process.
+    unsafe {
+        let mut control =
+            vec![0u8; libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) as usize];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+        // the descriptors are only made inheritable again when handed over to the next process.
+        let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, RECV_FLAGS);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if n < count.len() as isize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many listeners",
+            ));
+        }
+    }
+    #[cfg(target_vendor = "apple")]
+    for fd in &fds {
+        crate::systemd::close_on_exec(fd)?;
+    }
+    match u32::from_be_bytes(count) as usize == fds.len() {
+        true => Ok(fds),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing listeners",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn hand_listeners_over() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (parent, child) = UnixStream::pair().unwrap();
+
+        send_fds(&parent, &[listener.into()]).unwrap();
+        let mut fds = recv_fds(&child).unwrap();
+
+        assert_eq!(fds.len(), 1);
+        let Activated::Tcp(listener) = activate(fds.remove(0)).unwrap() else {
+            panic!("expect a TCP listener")
+        };
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn hand_no_listeners_over() {
+        let (parent, child) = UnixStream::pair().unwrap();
+
+        send_fds(&parent, &[]).unwrap();
+
+        assert!(recv_fds(&child).unwrap().is_empty());
+        drop(parent);
+        assert_eq!(
+            recv_fds(&child).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
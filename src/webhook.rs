@@ -0,0 +1,301 @@
+//! Password verification delegated to an HTTP service.
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::{io, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::Connector;
+use crate::{
+    auth::User, credential::PasswordVerifier, marker::Stream, BoxFuture, IOResult, Result,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// POSTs `{"username": .., "password": ..}` to an `http://` or, with the `tls` feature, an
+/// `https://` URL. The service answers `200` with `{"allow": true, "attributes": {..}}` to accept
+/// the user, whose attributes are kept on the [`User`], or `{"allow": false}` to reject it. Any
+/// other status is a failure of the service.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    authority: String,
+    path: String,
+    timeout: Duration,
+    https: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<Connector>,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> IOResult<Self> {
+        let (rest, https) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+            (Some(rest), _) => (rest, false),
+            #[cfg(feature = "tls")]
+            (_, Some(rest)) => (rest, true),
+            #[cfg(not(feature = "tls"))]
+            (_, Some(_)) => return Err(invalid_input("https webhooks need the tls feature")),
+            _ => {
+                return Err(invalid_input(
+                    "webhook URL must start with http:// or https://",
+                ))
+            }
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(n) => rest.split_at(n),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid_input("webhook URL has no host"));
+        }
+        Ok(Webhook {
+            authority: authority.into(),
+            path: path.into(),
+            timeout: DEFAULT_TIMEOUT,
+            https,
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Verifies the certificate of an `https://` service against the PEM encoded CA bundle,
+    /// without which such a service can't be posted to.
+    #[cfg(feature = "tls")]
+    pub fn tls<P: AsRef<Path>>(mut self, ca: P) -> IOResult<Self> {
+        self.tls = Some(Connector::for_addr(ca, &self.authority)?);
+        Ok(self)
+    }
+
+    /// How long a request may take before it fails, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn connect(&self) -> IOResult<Box<dyn Stream>> {
+        let addr = match (self.authority.contains(':'), self.https) {
+            (true, _) => self.authority.clone(),
+            (false, false) => format!("{}:80", self.authority),
+            (false, true) => format!("{}:443", self.authority),
+        };
+        let stream = TcpStream::connect(addr).await?;
+        if !self.https {
+            return Ok(Box::new(stream));
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return Ok(Box::new(tls.connect(stream).await?));
+        }
+        Err(invalid_input("an https webhook needs a CA bundle"))
+    }
+
+    async fn post(&self, body: &[u8]) -> IOResult<Vec<u8>> {
+        let mut server = self.connect().await?;
+        // HTTP/1.0 rules out a chunked response, the body simply ends with the connection.
+        let head = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        );
+        server.write_all(head.as_bytes()).await?;
+        server.write_all(body).await?;
+        let mut response = vec![];
+        server
+            .take(MAX_RESPONSE_LEN)
+            .read_to_end(&mut response)
+            .await?;
+        Ok(response)
+    }
+}
+
+impl PasswordVerifier for Webhook {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move {
+            let body = json!({ "username": username, "password": password }).to_string();
+            let response = timeout(self.timeout, self.post(body.as_bytes()))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            Ok(parse_response(username, &response)?)
+        })
+    }
+}
+
+fn parse_response(username: &str, response: &[u8]) -> IOResult<Option<User>> {
+    let end = response
+        .windows(4)
+        .position(|it| it == b"\r\n\r\n")
+        .ok_or_else(|| invalid_data("incomplete response"))?;
+    let status = response[..end]
+        .split(|&it| it == b' ')
+        .nth(1)
+        .ok_or_else(|| invalid_data("malformed status line"))?;
+    if status != b"200" {
+        let status = String::from_utf8_lossy(status);
+        return Err(io::Error::other(format!("webhook answered {status}")));
+    }
+    let body: Value = serde_json::from_slice(&response[end + 4..])?;
+    if body["allow"] != Value::Bool(true) {
+        return Ok(None);
+    }
+    let mut user = User::new(username);
+    if let Some(attributes) = body["attributes"].as_object() {
+        for (name, value) in attributes {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            user.attributes.insert(name.clone(), value);
+        }
+    }
+    Ok(Some(user))
+}
+
+fn invalid_input(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("webhook: {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// An auth service accepting `alice` with password `secret`, answering `500` for `bob`.
+    async fn service() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                answer(client).await;
+            }
+        });
+        format!("http://{addr}/auth")
+    }
+
+    async fn answer<S: Stream>(mut client: S) {
+        let mut request = vec![0; 1024];
+        let n = client.read(&mut request).await.unwrap();
+        let request = String::from_utf8(request[..n].to_vec()).unwrap();
+        assert!(request.starts_with("POST /auth HTTP/1.0\r\n"), "{request}");
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        let response = match (body["username"].as_str(), body["password"].as_str()) {
+            (Some("alice"), Some("secret")) => {
+                "HTTP/1.1 200 OK\r\n\r\n\
+                 {\"allow\": true, \"attributes\": {\"group\": \"admin\", \"quota\": 10}}"
+            }
+            (Some("bob"), _) => "HTTP/1.1 500 Internal Server Error\r\n\r\n",
+            _ => "HTTP/1.1 200 OK\r\n\r\n{\"allow\": false}",
+        };
+        client.write_all(response.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn allow_with_attributes() {
+        let webhook = Webhook::new(&service().await).unwrap();
+
+        let user = webhook.verify("alice", "secret").await.unwrap().unwrap();
+
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.attributes["group"], "admin");
+        assert_eq!(user.attributes["quota"], "10");
+    }
+
+    #[tokio::test]
+    async fn deny() {
+        let webhook = Webhook::new(&service().await).unwrap();
+
+        assert_eq!(webhook.verify("alice", "bad").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn fails_with_error_status() {
+        let webhook = Webhook::new(&service().await).unwrap();
+
+        assert!(webhook.verify("bob", "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_with_unresponsive_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let webhook = Webhook::new(&url)
+            .unwrap()
+            .timeout(Duration::from_millis(50));
+
+        assert!(webhook.verify("alice", "secret").await.is_err());
+    }
+
+    #[test]
+    fn parse_urls() {
+        let webhook = Webhook::new("http://auth.local:8080/v1/check").unwrap();
+        assert_eq!(webhook.authority, "auth.local:8080");
+        assert_eq!(webhook.path, "/v1/check");
+
+        assert_eq!(Webhook::new("http://auth.local").unwrap().path, "/");
+        assert!(Webhook::new("ftp://auth.local/").is_err());
+        assert!(Webhook::new("http:///").is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn post_over_https() {
+        use crate::tls::TlsConfig;
+
+        const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+        let acceptor = TlsConfig::new(
+            format!("{FIXTURES}/server.pem"),
+            format!("{FIXTURES}/server.key"),
+        )
+        .acceptor()
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                if let Ok(client) = acceptor.accept(client).await {
+                    answer(client).await;
+                }
+            }
+        });
+        let url = format!("https://localhost:{port}/auth");
+        assert!(Webhook::new(&url)
+            .unwrap()
+            .verify("alice", "secret")
+            .await
+            .is_err());
+
+        let webhook = Webhook::new(&url)
+            .unwrap()
+            .tls(format!("{FIXTURES}/ca.pem"))
+            .unwrap();
+        let user = webhook.verify("alice", "secret").await.unwrap().unwrap();
+
+        assert_eq!(user.attributes["group"], "admin");
+    }
+}
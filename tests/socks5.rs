@@ -1,13 +1,11 @@
-use std::{
-    io::{self},
-    net::{SocketAddr, ToSocketAddrs},
-};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
-use socks5::Credential;
+use socks5::{AccessRules, BoxFuture, Config, Credential, ListenAddr, Network, Resolve};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
 };
+use trust_dns_resolver::error::ResolveError;
 
 #[path = "../src/test.rs"]
 mod test;
@@ -15,23 +13,132 @@ use test::*;
 
 include!("../src/constant.rs");
 
+/// Resolves the domains of the tests to loopback, and no other.
+struct Hosts;
+
+impl Resolve for Hosts {
+    fn resolve<'a>(
+        &'a self,
+        domain: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<SocketAddr, socks5::Error>> {
+        Box::pin(async move {
+            match domain {
+                "www.example.test" => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+                _ => Err(ResolveError::from("No record found").into()),
+            }
+        })
+    }
+}
+
+/// Serves the config on an ephemeral port, resolving domains with [`Hosts`].
+async fn proxy(config: Config) -> SocketAddr {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(socks5::serve(server, config.resolver(Hosts)));
+    addr
+}
+
+/// Starts the server of the binary on an ephemeral port.
+async fn listen(credential: Option<Credential>) -> SocketAddr {
+    let server = socks5::listen(0, credential).await.unwrap();
+    match server.local_addrs() {
+        [ListenAddr::Inet(addr)] => *addr,
+        addrs => panic!("{addrs:?}"),
+    }
+}
+
+/// Echoes every connection on an ephemeral port of the address.
+async fn echo(ip: IpAddr) -> SocketAddr {
+    let listener = TcpListener::bind((ip, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+    addr
+}
+
+/// Answers the first request of every connection with a page, then closes it.
+async fn http() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1];
+                    if stream.read(&mut buf).await? == 0 {
+                        return Ok(());
+                    }
+                    request.extend(buf);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\n\
+                          Content-Length: 5\r\n\
+                          Connection: close\r\n\r\n\
+                          hello",
+                    )
+                    .await?;
+                stream.shutdown().await
+            });
+        }
+    });
+    addr
+}
+
+/// Sends the target of a CONNECT request: the address type, the address and the port.
+fn target(addr: SocketAddr) -> Vec<u8> {
+    let mut target = match addr.ip() {
+        IpAddr::V4(ip) => [&[IPV4][..], &ip.octets()].concat(),
+        IpAddr::V6(ip) => [&[IPV6][..], &ip.octets()].concat(),
+    };
+    target.extend(addr.port().to_be_bytes());
+    target
+}
+
+fn domain(domain: &str, port: u16) -> Vec<u8> {
+    let mut target = vec![DOMAIN_NAME, domain.len() as u8];
+    target.extend(domain.as_bytes());
+    target.extend(port.to_be_bytes());
+    target
+}
+
+/// Requests the page through the connected client, returning the response once it's closed.
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(client: &mut S) -> String {
+    let data = b"\
+        GET / HTTP/1.1\r\n\
+        Host: www.example.test\r\n\
+        Accept: */*\r\n\r\n\
+    ";
+    client.write_all(data).await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    response
+}
+
 #[tokio::test]
 async fn no_auth() {
-    let port = 1081;
-    tokio::spawn(socks5::run(port, None));
-    _ = tokio::spawn(async {}).await;
-    let mut client = TcpStream::connect(format!("127.0.0.1:{port}"))
-        .await
-        .unwrap();
+    let proxy = proxy(Config::default()).await;
+    let upstream = http().await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
 
     // Negotiation
     client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
     assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
 
     // Connect
-    client.write_all(&[VER, CONNECT, RSV, IPV4]).await.unwrap();
+    client.write_all(&[VER, CONNECT, RSV]).await.unwrap();
     client
-        .write_all(resolve("www.baidu.com:80").unwrap().as_slice())
+        .write_all(&domain("www.example.test", upstream.port()))
         .await
         .unwrap();
     let response = client.read_exact_bytes::<10>().await.unwrap();
@@ -39,26 +146,87 @@ async fn no_auth() {
     assert_eq!(response[4..], UNSPECIFIED_SOCKET_ADDR);
 
     // Send
-    let data = b"\
-        GET / HTTP/1.1\r\n\
-        Host: www.baidu.com\r\n\
-        User-Agent: curl/7.68.0\r\n\
-        Accept: */*\r\n\r\n\r\n\
-    ";
-    client.write_all(data).await.unwrap();
-    let mut response = String::new();
-    client.read_to_string(&mut response).await.unwrap();
-    assert!(response.contains("百度一下"), "{}", response);
+    let response = get(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("hello"), "{}", response);
 }
 
 #[tokio::test]
-async fn shutdown_bad_request() {
-    let port = 1082;
-    tokio::spawn(socks5::run(port, None));
-    _ = tokio::spawn(async {}).await;
-    let mut client = TcpStream::connect(format!("127.0.0.1:{port}"))
+async fn connect_ipv4_and_ipv6_targets() {
+    let proxy = proxy(Config::default()).await;
+    for upstream in [
+        echo(IpAddr::from([127, 0, 0, 1])).await,
+        echo(IpAddr::from(Ipv6Addr::LOCALHOST)).await,
+    ] {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        client.write_all(&[VER, CONNECT, RSV]).await.unwrap();
+        client.write_all(&target(upstream)).await.unwrap();
+        let response = client.read_exact_bytes::<10>().await.unwrap();
+        assert_eq!(response[..2], [VER, OK], "{upstream}");
+
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+    }
+}
+
+#[tokio::test]
+async fn reply_errors_of_the_request() {
+    let proxy = proxy(
+        Config::default().access(AccessRules::default().deny("192.0.2.0/24".parse().unwrap())),
+    )
+    .await;
+    let closed = TcpListener::bind("127.0.0.1:0")
         .await
+        .unwrap()
+        .local_addr()
         .unwrap();
+    let requests = [
+        (
+            [&[VER, CONNECT, RSV][..], &domain("unknown.test", 80)].concat(),
+            TARGET_SERVER_UNREACHABLE,
+        ),
+        (
+            [
+                &[VER, CONNECT, RSV][..],
+                &target("192.0.2.1:80".parse().unwrap()),
+            ]
+            .concat(),
+            CONNECTION_NOT_ALLOWED,
+        ),
+        (
+            [&[VER, BIND, RSV][..], &target(closed)].concat(),
+            UNSUPPORTED_COMMAND,
+        ),
+        (vec![VER, CONNECT, RSV, 0x2], CONNECTION_REFUSED),
+    ];
+    for (request, reply) in requests {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+        assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+        client.write_all(&request).await.unwrap();
+        assert_eq!(
+            client.read_exact_bytes().await.unwrap(),
+            [VER, reply, RSV, IPV4, 0, 0, 0, 0, 0, 0],
+            "{request:?}"
+        );
+        assert_eq!(client.read(&mut [0]).await.unwrap(), 0, "Closed");
+    }
+
+    // an unreachable upstream closes the session without a reply.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+    client.write_all(&[VER, CONNECT, RSV]).await.unwrap();
+    client.write_all(&target(closed)).await.unwrap();
+    assert_eq!(client.read(&mut [0]).await.unwrap(), 0, "Closed");
+}
+
+#[tokio::test]
+async fn shutdown_bad_request() {
+    let proxy = listen(None).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
 
     // Negotiation
     client.write_all(&[VER, 0]).await.unwrap();
@@ -73,12 +241,9 @@ async fn shutdown_bad_request() {
 
 #[tokio::test]
 async fn user_credential_authentication() {
-    let port = 1083;
-    tokio::spawn(socks5::run(port, Some(Credential::new("root", "pass"))));
-    _ = tokio::spawn(async {}).await;
-    let mut client = TcpStream::connect(format!("127.0.0.1:{port}"))
-        .await
-        .unwrap();
+    let proxy = proxy(Config::new(Some(Credential::new("root", "pass")))).await;
+    let upstream = http().await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
 
     // Negotiation
     client
@@ -96,39 +261,28 @@ async fn user_credential_authentication() {
     client.write_all(b"root").await.unwrap();
     client.write_all(&[4]).await.unwrap();
     client.write_all(b"pass").await.unwrap();
-    assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, OK]);
+    assert_eq!(
+        client.read_exact_bytes().await.unwrap(),
+        [CREDENTIAL_VERSION, OK]
+    );
 
     // Connect
-    client.write_all(&[VER, CONNECT, RSV, IPV4]).await.unwrap();
-    client
-        .write_all(resolve("www.baidu.com:80").unwrap().as_slice())
-        .await
-        .unwrap();
+    client.write_all(&[VER, CONNECT, RSV]).await.unwrap();
+    client.write_all(&target(upstream)).await.unwrap();
     let response = client.read_exact_bytes::<10>().await.unwrap();
     assert_eq!(response[..4], [VER, OK, RSV, IPV4]);
     assert_eq!(response[4..], UNSPECIFIED_SOCKET_ADDR);
 
     // Send
-    let data = b"\
-        GET / HTTP/1.1\r\n\
-        Host: www.baidu.com\r\n\
-        User-Agent: curl/7.68.0\r\n\
-        Accept: */*\r\n\r\n\r\n\
-    ";
-    client.write_all(data).await.unwrap();
-    let mut response = String::new();
-    client.read_to_string(&mut response).await.unwrap();
-    assert!(response.contains("百度一下"), "{}", response);
+    let response = get(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("hello"), "{}", response);
 }
 
 #[tokio::test]
 async fn fails_with_bad_credential() {
-    let port = 1084;
-    tokio::spawn(socks5::run(port, Some(Credential::new("root", "pass"))));
-    _ = tokio::spawn(async {}).await;
-    let mut client = TcpStream::connect(format!("127.0.0.1:{port}"))
-        .await
-        .unwrap();
+    let proxy = listen(Some(Credential::new("root", "pass"))).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
 
     // Negotiation
     client
@@ -146,24 +300,119 @@ async fn fails_with_bad_credential() {
     client.write_all(b"root").await.unwrap();
     client.write_all(&[3]).await.unwrap();
     client.write_all(b"bad").await.unwrap();
-    assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, AUTH_ERROR]);
+    assert_eq!(
+        client.read_exact_bytes().await.unwrap(),
+        [CREDENTIAL_VERSION, AUTH_ERROR]
+    );
 
     let mut buf = [0; 1];
     assert_eq!(client.read(&mut buf).await.unwrap(), 0, "Closed");
 }
 
-fn resolve<T: ToSocketAddrs>(addr: T) -> io::Result<[u8; 6]> {
-    addr.to_socket_addrs()?
-        .find(SocketAddr::is_ipv4)
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Empty"))
-        .map(|addr| {
-            if let SocketAddr::V4(addr) = addr {
-                let mut out = [0; 6];
-                const BOUNDARY: usize = 4;
-                out[..BOUNDARY].copy_from_slice(&addr.ip().octets());
-                out[BOUNDARY..].copy_from_slice(&addr.port().to_be_bytes());
-                return out;
-            }
-            unreachable!()
-        })
+#[tokio::test]
+async fn socks4_connect() {
+    let proxy = listen(None).await;
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+
+    // Connect
+    client.write_all(&[SOCKS4_VER, CONNECT]).await.unwrap();
+    client
+        .write_all(&target.port().to_be_bytes())
+        .await
+        .unwrap();
+    client.write_all(&[127, 0, 0, 1]).await.unwrap();
+    client.write_all(b"root\0").await.unwrap();
+    let response = client.read_exact_bytes::<8>().await.unwrap();
+    assert_eq!(response[..2], [SOCKS4_REPLY_VER, REQUEST_GRANTED]);
+
+    // Send
+    client.write_all(b"ping").await.unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+}
+
+#[tokio::test]
+async fn http_connect() {
+    let proxy = listen(Some(Credential::new("root", "pass"))).await;
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+
+    // Connect
+    let request = format!(
+        "CONNECT {target} HTTP/1.1\r\n\
+         Host: {target}\r\n\
+         Proxy-Authorization: Basic cm9vdDpwYXNz\r\n\r\n"
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    assert_eq!(&client.read_exact_bytes::<39>().await.unwrap(), response);
+
+    // Send
+    client.write_all(b"ping").await.unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+}
+
+#[tokio::test]
+async fn trusted_network_skips_authentication() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let config = Config::new(Some(Credential::new("root", "pass")))
+        .trust("127.0.0.0/8".parse().unwrap())
+        .access(AccessRules::default().deny(Network::loopback()[0]))
+        .authenticated_access(AccessRules::default());
+    tokio::spawn(socks5::serve(server, config));
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let mut request = vec![VER, CONNECT, RSV, IPV4, 127, 0, 0, 1];
+    request.extend(target.port().to_be_bytes());
+
+    // Anonymous clients from a trusted network negotiate NO_AUTH but can't reach loopback.
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+    client.write_all(&request).await.unwrap();
+    assert_eq!(
+        client.read_exact_bytes().await.unwrap(),
+        [VER, CONNECTION_NOT_ALLOWED]
+    );
+
+    // Authenticated clients get their own access rules.
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(&[VER, 2, NO_AUTH, CREDENTIAL_AUTH])
+        .await
+        .unwrap();
+    assert_eq!(
+        client.read_exact_bytes().await.unwrap(),
+        [VER, CREDENTIAL_AUTH]
+    );
+    client.write_all(b"\x01\x04root\x04pass").await.unwrap();
+    assert_eq!(
+        client.read_exact_bytes().await.unwrap(),
+        [CREDENTIAL_VERSION, OK]
+    );
+    client.write_all(&request).await.unwrap();
+    assert_eq!(
+        client.read_exact_bytes::<10>().await.unwrap()[..2],
+        [VER, OK]
+    );
+    client.write_all(b"ping").await.unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
 }
@@ -0,0 +1,111 @@
+#![cfg(unix)]
+
+use std::{
+    net::SocketAddr,
+    os::unix::process::CommandExt,
+    process::{Child, Command},
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+
+#[path = "../src/test.rs"]
+mod test;
+use test::*;
+
+include!("../src/constant.rs");
+
+/// Kills the process and every process it upgraded to when dropped.
+struct ProcessGroup(Child);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        unsafe { libc::kill(-(self.0.id() as i32), libc::SIGKILL) };
+        let _ = self.0.wait();
+    }
+}
+
+async fn echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+    addr
+}
+
+async fn connect(proxy: SocketAddr, target: SocketAddr) -> TcpStream {
+    let mut client = loop {
+        match TcpStream::connect(proxy).await {
+            Ok(client) => break client,
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let SocketAddr::V4(target) = target else {
+        unreachable!()
+    };
+    client.write_all(&[VER, 1, NO_AUTH]).await.unwrap();
+    client.write_all(&[VER, CONNECT, RSV, IPV4]).await.unwrap();
+    client.write_all(&target.ip().octets()).await.unwrap();
+    client
+        .write_all(&target.port().to_be_bytes())
+        .await
+        .unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), [VER, NO_AUTH]);
+    assert_eq!(
+        client.read_exact_bytes::<10>().await.unwrap()[..2],
+        [VER, OK]
+    );
+    client
+}
+
+async fn ping(client: &mut TcpStream) {
+    client.write_all(b"ping").await.unwrap();
+    assert_eq!(client.read_exact_bytes().await.unwrap(), *b"ping");
+}
+
+#[tokio::test]
+async fn upgrade_without_refusing_clients() {
+    let target = echo().await;
+    let proxy = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut old = ProcessGroup(
+        Command::new(env!("CARGO_BIN_EXE_socks5"))
+            .args(["-", &proxy.port().to_string()])
+            .process_group(0)
+            .spawn()
+            .unwrap(),
+    );
+    let mut session = connect(proxy, target).await;
+    ping(&mut session).await;
+
+    unsafe { libc::kill(old.0.id() as i32, libc::SIGUSR2) };
+    for _ in 0..20 {
+        ping(&mut connect(proxy, target).await).await;
+        sleep(Duration::from_millis(10)).await;
+    }
+    ping(&mut session).await;
+    assert!(old.0.try_wait().unwrap().is_none());
+
+    drop(session);
+    let status = loop {
+        match old.0.try_wait().unwrap() {
+            Some(status) => break status,
+            None => sleep(Duration::from_millis(10)).await,
+        }
+    };
+    assert!(status.success());
+    ping(&mut connect(proxy, target).await).await;
+}